@@ -1,5 +1,5 @@
-use candle::Tensor;
-use models_hf::bert::BertInferenceModel;
+use candle::{DType, Device, Tensor};
+use models_hf::bert::{BertInferenceModel, DEFAULT_MAX_LENGTH};
 use rayon::prelude::*;
 
 fn main() {
@@ -28,6 +28,9 @@ fn main() {
         "refs/pr/21",
         "",
         "",
+        DEFAULT_MAX_LENGTH,
+        Device::Cpu,
+        DType::F32,
     )
     .unwrap();
     println!("Bert model loaded");
@@ -51,8 +54,8 @@ fn main() {
     println!("Embeddings generated");
 
     // Serialize the embeddings to a file
-    embeddings
-        .save_safetensors("my_embedding", "embeddings.bin")
+    bert_model
+        .save_embeddings(&embeddings, "embeddings.bin", "my_embedding")
         .unwrap();
     println!("embeddings.bin saved");
 }
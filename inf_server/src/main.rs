@@ -1,7 +1,8 @@
 use std::{fs::File, sync::Arc};
 
 use axum::{extract::State, routing::post, Json, Router};
-use models_hf::bert::BertInferenceModel;
+use candle::{DType, Device};
+use models_hf::bert::{BertInferenceModel, DEFAULT_MAX_LENGTH};
 use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
 
@@ -21,12 +22,16 @@ async fn main() -> anyhow::Result<()> {
     // Load the bert model
     let filename = "../data/embeddings.bin";
     let embeding_key = "my_embedding";
-    let bert_model = BertInferenceModel::load(
+    let bert_model = BertInferenceModel::load_async(
         "sentence-transformers/all-MiniLM-L6-v2",
         "refs/pr/21",
         filename,
         embeding_key,
-    )?;
+        DEFAULT_MAX_LENGTH,
+        Device::Cpu,
+        DType::F32,
+    )
+    .await?;
 
     // Load the text_map
     let mut text_map_file = File::open("../data/text_map.bin")?;
@@ -51,21 +56,19 @@ async fn find_similar(
 ) -> Json<ResPayload> {
     let (model, text_map) = &*model_ctx;
 
-    // infer
-    let query_vector = model
-        .infer_sentence_embedding(&payload.text)
-        .expect("error inferring sentence embedding");
-
-    // search for the K similar embeddings
+    // embed the query and search for the K similar embeddings
     let results = model
-        .score_vector_similarity(query_vector, payload.num_results as usize)
-        .unwrap();
+        .search_text(&payload.text, payload.num_results as usize)
+        .expect("error searching for similar sentences");
 
     let results: Vec<String> = results
         .into_iter()
         .map(|r| {
-            let top_item_text = text_map.get(r.0).unwrap();
-            format!("Item: {} (index: {} score: {:?})", top_item_text, r.0, r.1)
+            let top_item_text = text_map.get(r.index).unwrap();
+            format!(
+                "Item: {} (index: {} score: {:?})",
+                top_item_text, r.index, r.score
+            )
         })
         .collect();
 
@@ -0,0 +1,27 @@
+use std::sync::Arc;
+
+use models_hf::bert::BertInferenceModel;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args: Vec<String> = std::env::args().collect();
+    anyhow::ensure!(
+        (3..=4).contains(&args.len()),
+        "Usage: search_server <embeddings_file> <embeddings_key> [address]"
+    );
+    let address = args.get(3).map_or("0.0.0.0:3000", String::as_str);
+
+    let model = BertInferenceModel::builder()
+        .embeddings(&args[1], &args[2])
+        .build_async()
+        .await?;
+    // Pay one-time costs before the first request
+    model.warmup()?;
+
+    let listener = TcpListener::bind(address).await?;
+    log::info!("Listening on {}", listener.local_addr()?);
+    models_hf::server::serve(listener, Arc::new(model)).await
+}
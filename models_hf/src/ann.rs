@@ -0,0 +1,90 @@
+use candle::{DType, Tensor};
+use hnsw_rs::prelude::{DistCosine, Hnsw};
+
+/// Tunables of an [`HnswIndex`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HnswParams {
+    /// Neighbours kept per node and layer (`M` in the HNSW paper). Higher values improve recall
+    /// at the cost of memory and build time. Must be below 256.
+    pub max_connections: usize,
+    /// Candidates explored while inserting. Higher values build a better graph, more slowly.
+    pub ef_construction: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            max_connections: 16,
+            ef_construction: 200,
+        }
+    }
+}
+
+/// Upper bound on the layers of the graph; hnsw_rs caps it at 16 anyway.
+const MAX_LAYERS: usize = 16;
+
+/// Approximate nearest-neighbour index over embeddings, by cosine similarity.
+pub struct HnswIndex {
+    hnsw: Hnsw<'static, f32, DistCosine>,
+    params: HnswParams,
+}
+
+impl HnswIndex {
+    /// Builds an index over the rows of `embeddings` (`[n, hidden]`), keyed by row index.
+    pub fn build(embeddings: &Tensor, params: HnswParams) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            (1..256).contains(&params.max_connections),
+            "max_connections must be between 1 and 255, got {}",
+            params.max_connections
+        );
+        let rows = embeddings.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+
+        let hnsw = Hnsw::new(
+            params.max_connections,
+            rows.len(),
+            MAX_LAYERS,
+            params.ef_construction,
+            DistCosine,
+        );
+        let rows: Vec<_> = rows.iter().zip(0..).collect();
+        hnsw.parallel_insert(&rows);
+
+        Ok(Self { hnsw, params })
+    }
+
+    /// Returns up to `top_k` `(row index, cosine similarity)` pairs, most similar first.
+    /// `ef` is the size of the candidate list; it is raised to `top_k` when smaller.
+    pub fn search(
+        &self,
+        query: &Tensor,
+        top_k: usize,
+        ef: usize,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        let query = query
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+        if top_k == 0 || self.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let neighbours = self.hnsw.search(&query, top_k, ef.max(top_k));
+
+        Ok(neighbours
+            .into_iter()
+            .map(|neighbour| (neighbour.d_id, 1. - neighbour.distance))
+            .collect())
+    }
+
+    pub fn params(&self) -> HnswParams {
+        self.params
+    }
+
+    pub fn len(&self) -> usize {
+        self.hnsw.get_nb_point()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -1,166 +1,6897 @@
-use candle::{safetensors, Device, Tensor};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+    num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use candle::{safetensors, DType, Device, Tensor, D};
 use candle_nn::VarBuilder;
-use candle_transformers::models::bert::{BertModel, Config, DTYPE};
-use hf_hub::{api::sync::Api, Repo, RepoType};
-use tokenizers::Tokenizer;
+use candle_transformers::models::bert::{BertModel, Config};
+use hf_hub::{api::sync::ApiBuilder, Cache, Repo, RepoType};
+use log::{debug, info, warn};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use tokenizers::{
+    EncodeInput, Encoding, PaddingParams, Tokenizer, TruncationParams, TruncationStrategy,
+};
+
+#[cfg(feature = "hnsw")]
+use crate::ann::{HnswIndex, HnswParams};
+use crate::bm25::Bm25;
+use crate::mmap::MmapedEmbeddings;
+use crate::quantization::{self, ProductQuantizer, QuantizedEmbeddings};
+#[cfg(feature = "gguf")]
+use crate::quantized_bert::QuantizedBertModel;
+use crate::standardize::Standardizer;
+
+/// How the per-token hidden states are reduced to a single sentence vector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolingStrategy {
+    #[default]
+    Max,
+    /// Max over the real tokens only, ignoring padding.
+    MaxMasked,
+    Mean,
+    /// Mean over the real tokens only, ignoring padding.
+    MeanMasked,
+    Cls,
+}
+
+impl PoolingStrategy {
+    /// Name of the strategy as recorded in saved embeddings files.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PoolingStrategy::Max => "max",
+            PoolingStrategy::MaxMasked => "max_masked",
+            PoolingStrategy::Mean => "mean",
+            PoolingStrategy::MeanMasked => "mean_masked",
+            PoolingStrategy::Cls => "cls",
+        }
+    }
+}
+
+impl std::str::FromStr for PoolingStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "max" => Ok(PoolingStrategy::Max),
+            "max_masked" => Ok(PoolingStrategy::MaxMasked),
+            "mean" => Ok(PoolingStrategy::Mean),
+            "mean_masked" => Ok(PoolingStrategy::MeanMasked),
+            "cls" => Ok(PoolingStrategy::Cls),
+            _ => anyhow::bail!("Unknown pooling strategy {name:?}"),
+        }
+    }
+}
+
+/// Metadata key under which [`BertInferenceModel::save_embeddings`] records the pooling used.
+const POOLING_METADATA_KEY: &str = "pooling";
+
+/// Files of an index written by [`BertInferenceModel::save_index`].
+const INDEX_EMBEDDINGS_FILE: &str = "embeddings.safetensors";
+const INDEX_EMBEDDINGS_KEY: &str = "embeddings";
+const INDEX_CONFIG_FILE: &str = "index.json";
+
+/// Max number of tokens (special tokens included) fed to the model per sentence.
+pub const DEFAULT_MAX_LENGTH: usize = 128;
+
+/// A stored embedding matched by a search.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchResult {
+    /// Row of the embedding in [`BertInferenceModel::embeddings`].
+    pub index: usize,
+    pub score: f32,
+    /// Document ID of the row, when set with [`BertInferenceModel::set_doc_ids`].
+    pub id: Option<String>,
+}
+
+impl From<SearchResult> for (usize, f32) {
+    fn from(result: SearchResult) -> Self {
+        (result.index, result.score)
+    }
+}
+
+/// Text prepended to sentences before tokenization. Models such as E5 or GTE are trained with
+/// `"query: "` and `"passage: "` prefixes and embed noticeably worse without them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Prefixes {
+    /// Prepended by [`BertInferenceModel::infer_sentence_embedding`].
+    pub query: String,
+    /// Prepended by [`BertInferenceModel::create_embeddings`].
+    pub passage: String,
+}
+
+/// Task instructions prepended to sentences before tokenization, for instruction-tuned models
+/// such as INSTRUCTOR, e.g. `"Represent the Science title:"`. An instruction is separated from
+/// the sentence (and its [`Prefixes`], if any) by a space.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Instructions {
+    /// Prepended by [`BertInferenceModel::infer_sentence_embedding`].
+    pub query: Option<String>,
+    /// Prepended by [`BertInferenceModel::create_embeddings`].
+    pub passage: Option<String>,
+}
+
+/// Where the weights of a model were loaded from, so an index can be reopened with the same
+/// model, see [`BertInferenceModel::save_index`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelSource {
+    pub model_name: String,
+    pub revision: String,
+    /// Local directory the model files were read from instead of the HF Hub.
+    pub model_dir: Option<PathBuf>,
+}
+
+/// The JSON sidecar of an index: everything besides the embeddings needed to search them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexConfig {
+    pub source: Option<ModelSource>,
+    pub pooling: PoolingStrategy,
+    pub normalize: bool,
+    pub add_special_tokens: bool,
+    pub max_length: usize,
+    pub prefixes: Prefixes,
+    pub instructions: Instructions,
+    /// Statistics the stored embeddings were standardized with.
+    pub standardizer: Option<Standardizer>,
+    pub doc_ids: Option<Vec<String>>,
+}
+
+/// Counters of the query embedding cache, see [`BertInferenceModel::set_query_cache_size`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// Queries currently cached.
+    pub len: usize,
+}
+
+/// Normalized query embeddings keyed by query text, least recently used evicted first.
+struct QueryCache {
+    entries: LruCache<String, Tensor>,
+    hits: usize,
+    misses: usize,
+}
+
+/// Call count and wall time of one inference stage, see [`Metrics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StageMetrics {
+    pub count: u64,
+    pub total: Duration,
+    /// Slowest single call.
+    pub max: Duration,
+}
+
+impl StageMetrics {
+    /// Average duration of a call, `None` before the first one.
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count).ok().filter(|&count| count > 0)?;
+        Some(self.total / count)
+    }
+
+    fn record(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+}
+
+/// Timings accumulated since metrics were enabled, see
+/// [`BertInferenceModel::set_metrics_enabled`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Tokenizer calls, one per sentence, sentence pair or batch.
+    pub tokenization: StageMetrics,
+    /// `BertModel` forward passes, one per sentence, sentence pair or batch.
+    pub forward: StageMetrics,
+    /// Scoring of queries against stored embeddings, one per search or batch of queries.
+    pub scoring: StageMetrics,
+}
+
+/// What the tokenizer did to a sentence before inference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InferenceStats {
+    /// Tokens fed to the model, special tokens included.
+    pub token_count: usize,
+    /// Whether tokens past `max_length` were dropped.
+    pub truncated: bool,
+}
+
+/// Summary of the stored embeddings, see [`BertInferenceModel::embedding_stats`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmbeddingStats {
+    pub count: usize,
+    pub hidden_size: usize,
+    /// L2 norms of the vectors, all 0 without any.
+    pub mean_norm: f32,
+    pub min_norm: f32,
+    pub max_norm: f32,
+    /// Fraction of the vectors whose norm is below [`NEAR_ZERO_NORM`].
+    pub near_zero_fraction: f32,
+}
+
+/// Norm under which [`EmbeddingStats`] counts a vector as zero.
+pub const NEAR_ZERO_NORM: f32 = 1e-6;
+
+/// Sentences embedded per forward pass by [`BertInferenceModel::index_jsonl`].
+const INDEX_BATCH_SIZE: usize = 64;
+
+/// Rows compared at once by [`BertInferenceModel::dedup_embeddings`].
+const DEDUP_CHUNK_SIZE: usize = 1024;
+
+/// Largest safetensors header [`BertInferenceModel::read_embeddings_pooling`] reads, the limit
+/// safetensors itself enforces.
+const MAX_HEADER_SIZE: usize = 100_000_000;
+
+/// Rows read from disk at once by [`BertInferenceModel::score_vector_similarity_mmap`].
+const MMAP_CHUNK_ROWS: usize = 4096;
+
+/// k-means rounds run per subspace by [`BertInferenceModel::train_pq`].
+const PQ_KMEANS_ITERATIONS: usize = 25;
+
+/// Candidates retrieved per requested result before MMR re-ranking.
+const MMR_POOL_FACTOR: usize = 4;
+
+/// Pad tokens looked up in the vocabulary when the tokenizer has no padding configured: BERT's,
+/// then RoBERTa's.
+const PAD_TOKENS: [&str; 2] = ["[PAD]", "<pad>"];
+
+/// How a query vector is compared against the stored embeddings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SimilarityMetric {
+    /// Cosine similarity. Works on any vectors; for L2-normalized ones, such as those produced by
+    /// `create_embeddings`, it equals the dot product.
+    #[default]
+    Cosine,
+    /// Raw inner product, so vector magnitudes affect the ranking. Only meaningful for models
+    /// trained with a dot-product objective.
+    Dot,
+}
+
+/// The network turning token ids into per-token hidden states.
+enum Encoder {
+    Bert(BertModel),
+    /// Weights quantized in a GGUF file, see [`BertInferenceModel::load_quantized`].
+    #[cfg(feature = "gguf")]
+    Quantized(QuantizedBertModel),
+}
+
+impl Encoder {
+    fn forward(&self, token_ids: &Tensor, token_type_ids: &Tensor) -> candle::Result<Tensor> {
+        match self {
+            Encoder::Bert(model) => model.forward(token_ids, token_type_ids),
+            #[cfg(feature = "gguf")]
+            Encoder::Quantized(model) => model.forward(token_ids, token_type_ids),
+        }
+    }
+}
 
-// NOTE: max length: 128
-// Hidden vector size: 384
 pub struct BertInferenceModel {
-    model: BertModel,
+    model: Encoder,
+    /// Size of the vectors the model produces, from its `config.json`.
+    hidden_size: usize,
+    /// Unknown for models assembled from parts or loaded from quantized weights.
+    source: Option<ModelSource>,
     tokenizer: Tokenizer,
     device: Device,
+    dtype: DType,
     embeddings: Tensor,
+    pooling: PoolingStrategy,
+    /// Pooling the stored embeddings were created with, when their file recorded it.
+    embeddings_pooling: Option<PoolingStrategy>,
+    /// Whether scoring fails, rather than just warning, when the two poolings differ.
+    strict_pooling: bool,
+    normalize: bool,
+    /// Whether `[CLS]`/`[SEP]` (or the model's equivalents) are added when tokenizing.
+    add_special_tokens: bool,
+    /// Whether cosine scores are mapped from [-1, 1] to [0, 1].
+    unit_interval_scores: bool,
+    /// Largest per-dimension difference under which two stored embeddings count as duplicates
+    /// in search results, `None` to return duplicates.
+    duplicate_tolerance: Option<f32>,
+    /// Dtype of the embeddings returned by `create_embeddings`, the model dtype when unset.
+    output_dtype: Option<DType>,
+    max_length: usize,
+    prefixes: Prefixes,
+    instructions: Instructions,
+    query_cache: Option<Mutex<QueryCache>>,
+    /// Only recorded while enabled, to keep the hot path free of locking.
+    metrics: Option<Mutex<Metrics>>,
+    /// Applied to the stored embeddings, and to queries before scoring them.
+    standardizer: Option<Standardizer>,
+    doc_ids: Option<Vec<String>>,
+    /// Lexical index of the texts of `embeddings`, for hybrid search.
+    bm25: Option<Bm25>,
+    /// Separate corpora searched by name, independently of `embeddings`.
+    embedding_sets: HashMap<String, Tensor>,
+    quantized: Option<QuantizedEmbeddings>,
+    /// Product quantizer and the `[n, n_subspaces]` codes of `embeddings`.
+    pq: Option<(ProductQuantizer, Tensor)>,
+    /// Embeddings searched straight from disk, independently of `embeddings`.
+    mmaped: Option<MmapedEmbeddings>,
+    /// Packed sign bits of `embeddings`, built on the first binary search.
+    binary: OnceLock<Tensor>,
+    #[cfg(feature = "hnsw")]
+    index: Option<HnswIndex>,
+    /// Runs the forward passes, when their threads are limited.
+    #[cfg(feature = "rayon")]
+    thread_pool: Option<rayon::ThreadPool>,
 }
 
 impl BertInferenceModel {
+    /// Starts configuring a model; see [`BertInferenceModelBuilder`].
+    pub fn builder() -> BertInferenceModelBuilder {
+        BertInferenceModelBuilder::default()
+    }
+
+    /// Loads `model_name` at `revision` from the HF Hub, with weights in `dtype` (e.g.
+    /// `DType::F16` to halve memory), and the embeddings stored under `embeddings_key` in
+    /// `embeddings_filename`, if given.
     pub fn load(
         model_name: &str,
         revision: &str,
         embeddings_filename: &str,
         embeddings_key: &str,
+        max_length: usize,
+        device: Device,
+        dtype: DType,
     ) -> anyhow::Result<Self> {
-        let device = Device::Cpu;
+        Self::builder()
+            .model_name(model_name)
+            .revision(revision)
+            .embeddings(embeddings_filename, embeddings_key)
+            .max_length(max_length)
+            .device(device)
+            .dtype(dtype)
+            .build()
+    }
+
+    /// Like [`Self::load`], but downloads the model files asynchronously.
+    #[cfg(feature = "tokio")]
+    pub async fn load_async(
+        model_name: &str,
+        revision: &str,
+        embeddings_filename: &str,
+        embeddings_key: &str,
+        max_length: usize,
+        device: Device,
+        dtype: DType,
+    ) -> anyhow::Result<Self> {
+        Self::builder()
+            .model_name(model_name)
+            .revision(revision)
+            .embeddings(embeddings_filename, embeddings_key)
+            .max_length(max_length)
+            .device(device)
+            .dtype(dtype)
+            .build_async()
+            .await
+    }
+
+    /// Like [`Self::load`], but reads `config.json`, `tokenizer.json` and `model.safetensors`
+    /// from a local directory instead of the HF Hub, so no network access is needed.
+    pub fn load_from_path(
+        model_dir: &Path,
+        embeddings_filename: &str,
+        embeddings_key: &str,
+        max_length: usize,
+        device: Device,
+        dtype: DType,
+    ) -> anyhow::Result<Self> {
+        Self::builder()
+            .model_dir(model_dir)
+            .embeddings(embeddings_filename, embeddings_key)
+            .max_length(max_length)
+            .device(device)
+            .dtype(dtype)
+            .build()
+    }
+
+    fn load_from_files(
+        files: &ModelFiles,
+        config: Option<serde_json::Value>,
+        tokenizer: Option<Tokenizer>,
+        embeddings: Tensor,
+        max_length: usize,
+        device: Device,
+        dtype: DType,
+    ) -> anyhow::Result<Self> {
+        // load the model config, unless the caller brought their own
+        let raw_config = match config {
+            Some(config) => config,
+            None => {
+                let config = std::fs::read_to_string(&files.config).with_context(|| {
+                    format!("Error reading model config {}", files.config.display())
+                })?;
+                serde_json::from_str(&config)?
+            }
+        };
+        // `Config` keeps its fields private, so read the hidden size from the raw JSON
+        let config: Config = serde_json::from_value(raw_config.clone())?;
+        let hidden_size = raw_config["hidden_size"]
+            .as_u64()
+            .context("The model config has no hidden_size")? as usize;
+
+        // load the tokenizer, unless the caller brought their own
+        let tokenizer = match tokenizer {
+            Some(tokenizer) => tokenizer,
+            None => Tokenizer::from_file(&files.tokenizer).map_err(anyhow::Error::msg)?,
+        };
+
+        // load the model
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&files.weights, dtype, &device)? };
+        let model = BertModel::load(vb, &config)?;
+
+        Self::from_parts(
+            Encoder::Bert(model),
+            hidden_size,
+            tokenizer,
+            device,
+            dtype,
+            embeddings,
+            max_length,
+        )
+    }
+
+    /// Loads a BERT model whose weights were quantized to a GGUF file, e.g. with candle's
+    /// `tensor-tools quantize` on its `model.safetensors`, for a much smaller and cheaper model.
+    /// `config.json` and `tokenizer.json` are read from `model_dir`, and the embeddings as in
+    /// [`Self::load`].
+    ///
+    /// Quantized models compute in f32, so [`Self::dtype`] is always `DType::F32`. Their
+    /// embeddings stay close to those of the full-precision model, with the same interface.
+    #[cfg(feature = "gguf")]
+    pub fn load_quantized(
+        gguf_path: &Path,
+        model_dir: &Path,
+        embeddings_filename: &str,
+        embeddings_key: &str,
+        max_length: usize,
+        device: Device,
+    ) -> anyhow::Result<Self> {
+        let config = std::fs::read_to_string(model_dir.join("config.json"))?;
+        let config: serde_json::Value = serde_json::from_str(&config)?;
+        let tokenizer =
+            Tokenizer::from_file(model_dir.join("tokenizer.json")).map_err(anyhow::Error::msg)?;
+        let model = QuantizedBertModel::load(gguf_path, &config, &device)
+            .with_context(|| format!("Error loading GGUF weights {}", gguf_path.display()))?;
 
+        let embeddings =
+            Self::load_embeddings_or_empty(embeddings_filename, &[embeddings_key], &device)?;
+        let hidden_size = model.hidden_size();
+        Self::from_parts(
+            Encoder::Quantized(model),
+            hidden_size,
+            tokenizer,
+            device,
+            DType::F32,
+            embeddings,
+            max_length,
+        )
+    }
+
+    fn load_embeddings_or_empty(
+        embeddings_filename: &str,
+        embeddings_keys: &[impl AsRef<str>],
+        device: &Device,
+    ) -> anyhow::Result<Tensor> {
         // Load the embeddings from a file
         let embeddings = match embeddings_filename.is_empty() {
             true => {
-                println!("No file name provided. Embeddings return empty tensor.");
-                Tensor::new(&[0.0], &device)?
+                info!("No file name provided. Embeddings return empty tensor.");
+                Tensor::new(&[0.0], device)?
             }
-            false => {
-                let tensor_file = safetensors::load(embeddings_filename, &device)
-                    .expect("Error loading embeddings file");
-                tensor_file
-                    .get(embeddings_key)
-                    .expect("Error getting embeddings key")
-                    .clone()
+            false => Self::load_embeddings_concat(embeddings_filename, embeddings_keys, device)?,
+        };
+        info!("Loaded embedding shape: {:?}", embeddings.shape());
+
+        Ok(embeddings)
+    }
+
+    /// Reads the tensor stored under `key` in the safetensors file `filename`.
+    fn load_embeddings(filename: &str, key: &str, device: &Device) -> anyhow::Result<Tensor> {
+        Self::load_embeddings_concat(filename, &[key], device)
+    }
+
+    /// Reads the tensors stored under `keys` in the safetensors file `filename`, e.g. one per
+    /// shard, and concatenates their rows in the order of `keys`.
+    fn load_embeddings_concat(
+        filename: &str,
+        keys: &[impl AsRef<str>],
+        device: &Device,
+    ) -> anyhow::Result<Tensor> {
+        anyhow::ensure!(!keys.is_empty(), "No embeddings key given for {filename}");
+        let mut tensor_file = safetensors::load(filename, device)
+            .with_context(|| format!("Error loading embeddings file {filename}"))?;
+        let mut available: Vec<_> = tensor_file.keys().cloned().collect();
+        available.sort();
+
+        let mut parts = Vec::with_capacity(keys.len());
+        // Key and hidden size of the first tensor, which the others must match
+        let mut first: Option<(&str, usize)> = None;
+        for key in keys {
+            let key = key.as_ref();
+            let Some(embeddings) = tensor_file.remove(key) else {
+                anyhow::bail!(
+                    "Embeddings key {key:?} not found in {filename}, available keys: {available:?}"
+                )
+            };
+            check_embeddings_layout(&embeddings, &format!("{key:?} in {filename}"))?;
+            let hidden_size = embeddings.dim(1)?;
+            match first {
+                Some((first_key, expected)) => anyhow::ensure!(
+                    hidden_size == expected,
+                    "Embeddings {key:?} in {filename} have hidden size {hidden_size} but \
+                     {first_key:?} have hidden size {expected}"
+                ),
+                None => first = Some((key, hidden_size)),
+            }
+            parts.push(embeddings);
+        }
+
+        Ok(Tensor::cat(&parts, 0)?)
+    }
+
+    /// Replaces the stored embeddings with the `[n, hidden]` float array in the `.npy` file at
+    /// `path`, e.g. as written by `numpy.save`.
+    pub fn load_embeddings_npy(&mut self, path: &str) -> anyhow::Result<()> {
+        let embeddings = Tensor::read_npy(path)
+            .with_context(|| format!("Error loading embeddings file {path}"))?;
+        anyhow::ensure!(
+            embeddings.dtype().is_float(),
+            "Embeddings in {path} must be floats, got {:?}",
+            embeddings.dtype()
+        );
+        check_embeddings_layout(&embeddings, &format!("in {path}"))?;
+
+        self.replace_embeddings(embeddings)
+    }
+
+    /// Builds a fresh index from a JSONL file with one `{"<id_field>": ..., "<text_field>": ...}`
+    /// object per line: embeds every text and keeps the IDs, so [`Self::search`] returns them.
+    /// String and number IDs are accepted.
+    ///
+    /// Replaces the stored embeddings and document IDs. Malformed lines are skipped with a
+    /// warning.
+    pub fn index_jsonl(
+        &mut self,
+        path: &str,
+        text_field: &str,
+        id_field: &str,
+    ) -> anyhow::Result<()> {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading JSONL file {path}"))?;
+
+        let mut texts = Vec::new();
+        let mut doc_ids = Vec::new();
+        for (line_number, line) in file.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
             }
+            let record = match serde_json::from_str::<serde_json::Value>(line) {
+                Ok(record) => record,
+                Err(err) => {
+                    warn!("Skipping line {} of {path}: {err}", line_number + 1);
+                    continue;
+                }
+            };
+            let id = match &record[id_field] {
+                serde_json::Value::String(id) => id.clone(),
+                serde_json::Value::Number(id) => id.to_string(),
+                _ => {
+                    warn!(
+                        "Skipping line {} of {path}: no {id_field:?} field",
+                        line_number + 1
+                    );
+                    continue;
+                }
+            };
+            let Some(text) = record[text_field].as_str() else {
+                warn!(
+                    "Skipping line {} of {path}: no {text_field:?} field",
+                    line_number + 1
+                );
+                continue;
+            };
+
+            texts.push(text.to_string());
+            doc_ids.push(id);
+        }
+        anyhow::ensure!(!texts.is_empty(), "No valid records in {path}");
+
+        let embeddings = self.create_embeddings_chunked(texts, INDEX_BATCH_SIZE)?;
+        self.replace_embeddings(embeddings)?;
+        self.set_doc_ids(doc_ids)
+    }
+
+    /// Embeds `texts` and adds them to the stored embeddings under `ids`, one per text, so
+    /// [`Self::search_text`] finds them: the write side of streaming ingestion. Texts whose ID is
+    /// already stored replace its previous embedding, which moves to the end.
+    ///
+    /// The stored embeddings, if any, must have document IDs. Nothing changes when embedding
+    /// fails.
+    pub fn index_texts(&mut self, texts: Vec<String>, ids: Vec<String>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            texts.len() == ids.len(),
+            "Got {} IDs for {} texts",
+            ids.len(),
+            texts.len()
+        );
+        let new_ids: HashSet<&str> = ids.iter().map(String::as_str).collect();
+        anyhow::ensure!(new_ids.len() == ids.len(), "Got duplicate IDs to index");
+        let n_embeddings = match self.embeddings.rank() {
+            2 => self.embeddings.dim(0)?,
+            _ => 0,
+        };
+        let stored_ids = match &self.doc_ids {
+            Some(doc_ids) => doc_ids.as_slice(),
+            None if n_embeddings == 0 => &[],
+            None => anyhow::bail!(
+                "The {n_embeddings} stored embeddings have no document IDs, use set_doc_ids first"
+            ),
         };
-        println!("Loaded embedding shape: {:?}", embeddings.shape());
+        let kept: Vec<usize> = stored_ids
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| !new_ids.contains(id.as_str()))
+            .map(|(index, _)| index)
+            .collect();
 
-        // Start loading the model from the hub
-        let repo = Repo::with_revision(model_name.parse()?, RepoType::Model, revision.parse()?);
-        let api = Api::new()?;
-        let api = api.repo(repo);
-        let config_filename = api.get("config.json")?;
-        let tokenizer_filename = api.get("tokenizer.json")?;
-        let weights_filename = api.get("model.safetensors")?;
+        let embeddings = self.create_embeddings_chunked(texts, INDEX_BATCH_SIZE)?;
+        if kept.len() < n_embeddings {
+            self.retain_rows(&kept)?;
+        }
+        self.add_embeddings(embeddings)?;
+        let mut doc_ids = self.doc_ids.take().unwrap_or_default();
+        doc_ids.extend(ids);
+        self.doc_ids = Some(doc_ids);
 
-        // load the model config
-        let config = std::fs::read_to_string(config_filename)?;
-        let config: Config = serde_json::from_str(&config)?;
+        Ok(())
+    }
 
-        // load the tokenizer
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(anyhow::Error::msg)?;
+    /// Swaps in a new set of `[n, hidden]` embeddings, dropping everything derived from the
+    /// previous ones.
+    fn replace_embeddings(&mut self, embeddings: Tensor) -> anyhow::Result<()> {
+        self.check_hidden_size(&embeddings)?;
+        let embeddings = embeddings.to_device(&self.device)?;
+        self.embeddings = match &self.standardizer {
+            Some(standardizer) => standardizer.transform(&embeddings)?,
+            None => embeddings,
+        };
+        self.embeddings_pooling = None;
+        self.doc_ids = None;
+        self.bm25 = None;
+        self.quantized = None;
+        self.pq = None;
+        self.reset_derived_indexes();
 
-        // load the model
-        let vb =
-            unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], DTYPE, &device)? };
-        let model = BertModel::load(vb, &config)?;
+        Ok(())
+    }
 
-        Ok(Self {
+    /// Reads the pooling recorded by [`Self::save_embeddings`] from the header of a safetensors
+    /// file, without loading its tensors.
+    fn read_embeddings_pooling(filename: &str) -> anyhow::Result<Option<PoolingStrategy>> {
+        use std::io::Read;
+
+        let mut file = std::fs::File::open(filename)
+            .with_context(|| format!("Error loading embeddings file {filename}"))?;
+        // The header is a JSON object preceded by its length as a little-endian u64
+        let mut header_len = [0u8; 8];
+        file.read_exact(&mut header_len)?;
+        let header_len = u64::from_le_bytes(header_len);
+        anyhow::ensure!(
+            header_len <= MAX_HEADER_SIZE as u64,
+            "Embeddings file {filename} has a {header_len} byte header, is it a safetensors file?"
+        );
+        let mut header = vec![0u8; header_len as usize];
+        file.read_exact(&mut header)?;
+        let header: serde_json::Value = serde_json::from_slice(&header)?;
+
+        header["__metadata__"][POOLING_METADATA_KEY]
+            .as_str()
+            .map(str::parse)
+            .transpose()
+    }
+
+    /// Writes `embeddings` to the safetensors file `path` under `key`, so that a later
+    /// [`Self::load`] with the same file and key can reuse them instead of re-embedding.
+    ///
+    /// The current pooling strategy is recorded in the file metadata, so loading the embeddings
+    /// into a model that pools differently can be detected.
+    pub fn save_embeddings(
+        &self,
+        embeddings: &Tensor,
+        path: &str,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        let metadata = HashMap::from([(
+            POOLING_METADATA_KEY.to_string(),
+            self.pooling.as_str().to_string(),
+        )]);
+        ::safetensors::serialize_to_file([(key, embeddings)], &Some(metadata), Path::new(path))
+            .with_context(|| format!("Error saving embeddings file {path}"))?;
+
+        Ok(())
+    }
+
+    /// Saves a portable, search-ready index to `dir`: the stored embeddings, and a JSON sidecar
+    /// holding their document IDs, the pooling, normalization, prefixes and standardizer used,
+    /// and the model they were created with. [`Self::load_index`] reopens it.
+    pub fn save_index(&self, dir: &Path) -> anyhow::Result<()> {
+        anyhow::ensure!(self.embeddings.rank() == 2, "No embeddings to save");
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Error creating index directory {}", dir.display()))?;
+
+        let embeddings_path = dir.join(INDEX_EMBEDDINGS_FILE);
+        let embeddings_path = embeddings_path
+            .to_str()
+            .context("Index directory is not valid UTF-8")?;
+        self.save_embeddings(&self.embeddings, embeddings_path, INDEX_EMBEDDINGS_KEY)?;
+
+        let config = IndexConfig {
+            source: self.source.clone(),
+            pooling: self.pooling,
+            normalize: self.normalize,
+            add_special_tokens: self.add_special_tokens,
+            max_length: self.max_length,
+            prefixes: self.prefixes.clone(),
+            instructions: self.instructions.clone(),
+            standardizer: self.standardizer.clone(),
+            doc_ids: self.doc_ids.clone(),
+        };
+        let config_path = dir.join(INDEX_CONFIG_FILE);
+        std::fs::write(&config_path, serde_json::to_string_pretty(&config)?)
+            .with_context(|| format!("Error saving index config {}", config_path.display()))?;
+
+        Ok(())
+    }
+
+    /// Reopens an index written by [`Self::save_index`]: loads the model it was created with onto
+    /// `device`, configured the same way, with its embeddings and document IDs.
+    pub fn load_index(dir: &Path, device: Device) -> anyhow::Result<Self> {
+        let config_path = dir.join(INDEX_CONFIG_FILE);
+        let config = std::fs::read_to_string(&config_path)
+            .with_context(|| format!("Error reading index config {}", config_path.display()))?;
+        let config: IndexConfig = serde_json::from_str(&config)?;
+        let source = config.source.with_context(|| {
+            format!(
+                "The index in {} doesn't record the model it was created with",
+                dir.display()
+            )
+        })?;
+
+        let embeddings_path = dir.join(INDEX_EMBEDDINGS_FILE);
+        let embeddings_path = embeddings_path
+            .to_str()
+            .context("Index directory is not valid UTF-8")?;
+        let mut builder = Self::builder()
+            .model_name(source.model_name)
+            .revision(source.revision)
+            .embeddings(embeddings_path, INDEX_EMBEDDINGS_KEY)
+            .device(device)
+            .pooling(config.pooling)
+            .normalize(config.normalize)
+            .add_special_tokens(config.add_special_tokens)
+            .max_length(config.max_length)
+            .prefixes(config.prefixes)
+            .instructions(config.instructions);
+        if let Some(model_dir) = source.model_dir {
+            builder = builder.model_dir(model_dir);
+        }
+        let mut model = builder.build()?;
+        // The saved embeddings are already standardized
+        model.standardizer = config.standardizer;
+        if let Some(doc_ids) = config.doc_ids {
+            model.set_doc_ids(doc_ids)?;
+        }
+
+        Ok(model)
+    }
+
+    /// Model the embeddings are computed with, when it was loaded by name or from a directory.
+    pub fn source(&self) -> Option<&ModelSource> {
+        self.source.as_ref()
+    }
+
+    /// Like [`Self::load`], but runs on the fastest device available: CUDA, then Metal, then CPU.
+    pub fn load_auto(
+        model_name: &str,
+        revision: &str,
+        embeddings_filename: &str,
+        embeddings_key: &str,
+        max_length: usize,
+        dtype: DType,
+    ) -> anyhow::Result<Self> {
+        let device = Self::auto_device();
+        info!("Selected device: {:?}", device);
+
+        Self::load(
+            model_name,
+            revision,
+            embeddings_filename,
+            embeddings_key,
+            max_length,
+            device,
+            dtype,
+        )
+    }
+
+    /// Returns the first device that candle was compiled for and that can actually be opened.
+    pub fn auto_device() -> Device {
+        if candle::utils::cuda_is_available() {
+            match Device::new_cuda(0) {
+                Ok(device) => return device,
+                Err(err) => warn!("CUDA unavailable, falling back: {err}"),
+            }
+        }
+        if candle::utils::metal_is_available() {
+            match Device::new_metal(0) {
+                Ok(device) => return device,
+                Err(err) => warn!("Metal unavailable, falling back: {err}"),
+            }
+        }
+
+        Device::Cpu
+    }
+
+    /// Runs the forward passes of this model on a dedicated pool of `num_threads` threads, e.g.
+    /// to leave cores to other processes on a shared machine. With `None`, they share rayon's
+    /// global pool, as candle's CPU matmuls do by default. Needs the `rayon` feature to limit
+    /// the threads.
+    ///
+    /// Scoring, including the parallel scoring of the `rayon` feature, and the batches of
+    /// [`Self::create_embeddings_parallel`] still run on the global pool. candle also still
+    /// splits its matmuls into as many tasks as `RAYON_NUM_THREADS` or the number of CPUs, which
+    /// only setting `RAYON_NUM_THREADS` before starting the process changes.
+    pub fn set_num_threads(&mut self, num_threads: Option<usize>) -> anyhow::Result<()> {
+        #[cfg(feature = "rayon")]
+        {
+            self.thread_pool = match num_threads {
+                Some(num_threads) => {
+                    anyhow::ensure!(num_threads > 0, "The number of threads must be at least 1");
+                    Some(
+                        rayon::ThreadPoolBuilder::new()
+                            .num_threads(num_threads)
+                            .build()?,
+                    )
+                }
+                None => None,
+            };
+        }
+        #[cfg(not(feature = "rayon"))]
+        anyhow::ensure!(
+            num_threads.is_none(),
+            "Limiting the threads of a model needs the rayon feature, or set RAYON_NUM_THREADS \
+             before starting the process"
+        );
+
+        Ok(())
+    }
+
+    /// Number of threads the forward passes run on, see [`Self::set_num_threads`].
+    pub fn num_threads(&self) -> usize {
+        #[cfg(feature = "rayon")]
+        if let Some(thread_pool) = &self.thread_pool {
+            return thread_pool.current_num_threads();
+        }
+        candle::utils::get_num_threads()
+    }
+
+    /// Runs the model, on its own thread pool when [`Self::set_num_threads`] set one.
+    fn encode(&self, token_ids: &Tensor, token_type_ids: &Tensor) -> candle::Result<Tensor> {
+        #[cfg(feature = "rayon")]
+        if let Some(thread_pool) = &self.thread_pool {
+            return thread_pool.install(|| self.model.forward(token_ids, token_type_ids));
+        }
+        self.model.forward(token_ids, token_type_ids)
+    }
+
+    fn from_parts(
+        model: Encoder,
+        hidden_size: usize,
+        tokenizer: Tokenizer,
+        device: Device,
+        dtype: DType,
+        embeddings: Tensor,
+        max_length: usize,
+    ) -> anyhow::Result<Self> {
+        // Without an embeddings file the model starts with a rank-1 placeholder
+        if embeddings.rank() == 2 {
+            let stored = embeddings.dim(1)?;
+            anyhow::ensure!(
+                stored == hidden_size,
+                "The stored embeddings have hidden size {stored} but the model has hidden size \
+                 {hidden_size}, were they created with another model?"
+            );
+        }
+        let mut model = Self {
             model,
+            hidden_size,
+            source: None,
             tokenizer,
             device,
+            dtype,
             embeddings,
-        })
-    }
-
-    pub fn infer_sentence_embedding(&self, sentence: &str) -> anyhow::Result<Tensor> {
-        let tokens = self
-            .tokenizer
-            .encode(sentence, true)
-            .map_err(anyhow::Error::msg)?;
+            pooling: PoolingStrategy::default(),
+            embeddings_pooling: None,
+            strict_pooling: false,
+            normalize: true,
+            add_special_tokens: true,
+            unit_interval_scores: false,
+            duplicate_tolerance: None,
+            output_dtype: None,
+            max_length,
+            prefixes: Prefixes::default(),
+            instructions: Instructions::default(),
+            query_cache: None,
+            metrics: None,
+            standardizer: None,
+            doc_ids: None,
+            bm25: None,
+            embedding_sets: HashMap::new(),
+            quantized: None,
+            pq: None,
+            mmaped: None,
+            binary: OnceLock::new(),
+            #[cfg(feature = "hnsw")]
+            index: None,
+            #[cfg(feature = "rayon")]
+            thread_pool: None,
+        };
+        model.set_max_length(max_length)?;
 
-        let token_ids = Tensor::new(tokens.get_ids(), &self.device)?.unsqueeze(0)?;
-        // WARN: Are they attention masks? If so, we need to create a tensor of 1s and 0s
-        let token_type_ids = token_ids.zeros_like()?;
+        Ok(model)
+    }
 
-        let start = std::time::Instant::now();
-        let embeddings = self.model.forward(&token_ids, &token_type_ids)?;
-        println!("Time taken for inference: {:?}", start.elapsed());
-        println!("Embeddings: {:?}", embeddings);
+    pub fn embeddings(&self) -> &Tensor {
+        &self.embeddings
+    }
 
-        let embeddings = Self::apply_max_pooling(&embeddings)?;
-        println!("Embeddings after max pooling: {:?}", embeddings);
+    /// Count, norms and share of all-zero vectors of the stored embeddings, to sanity-check an
+    /// index: normalized embeddings have norms of 1, and zero vectors usually come from a bug
+    /// upstream, such as empty texts or a failed export.
+    pub fn embedding_stats(&self) -> anyhow::Result<EmbeddingStats> {
+        // Without an embeddings file the model holds a rank-1 placeholder
+        if self.embeddings.rank() != 2 || self.embeddings.dim(0)? == 0 {
+            return Ok(EmbeddingStats {
+                count: 0,
+                hidden_size: self.hidden_size,
+                mean_norm: 0.,
+                min_norm: 0.,
+                max_norm: 0.,
+                near_zero_fraction: 0.,
+            });
+        }
 
-        let embeddings = Self::l2_normalize(&embeddings)?;
+        let (count, hidden_size) = self.embeddings.dims2()?;
+        let norms = self
+            .embeddings
+            .to_dtype(DType::F32)?
+            .sqr()?
+            .sum(1)?
+            .sqrt()?
+            .to_vec1::<f32>()?;
+        let near_zero = norms.iter().filter(|&&norm| norm < NEAR_ZERO_NORM).count();
 
-        Ok(embeddings)
+        Ok(EmbeddingStats {
+            count,
+            hidden_size,
+            mean_norm: norms.iter().sum::<f32>() / count as f32,
+            min_norm: norms.iter().copied().fold(f32::INFINITY, f32::min),
+            max_norm: norms.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            near_zero_fraction: near_zero as f32 / count as f32,
+        })
     }
 
-    pub fn create_embeddings(&self, sentences: Vec<String>) -> anyhow::Result<Tensor> {
-        println!("create_embeddings: sentences.len(): {}", sentences.len());
+    /// Appends `new` (`[m, hidden]`) to the stored embeddings so later searches cover them too.
+    pub fn add_embeddings(&mut self, new: Tensor) -> anyhow::Result<()> {
+        let new = new.to_device(&self.device)?;
+        self.check_hidden_size(&new)?;
+        let new = match &self.standardizer {
+            Some(standardizer) => standardizer.transform(&new)?,
+            None => new,
+        };
 
-        let tokens = self
-            .tokenizer
-            .encode_batch(sentences, true)
-            .map_err(anyhow::Error::msg)?;
+        self.reset_derived_indexes();
+        // Without an embeddings file the model starts with a rank-1 placeholder
+        if self.embeddings.rank() != 2 {
+            self.embeddings = new;
+            return Ok(());
+        }
 
-        let token_ids = tokens
-            .iter()
-            .map(|tokens| {
-                let tokens = tokens.get_ids().to_vec();
-                Ok(Tensor::new(tokens.as_slice(), &self.device)?)
-            })
-            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.embeddings = Tensor::cat(&[&self.embeddings, &new], 0)?;
 
-        let token_ids = Tensor::stack(&token_ids, 0)?;
-        // WARN: Are they attention masks? If so, we need to create a tensor of 1s and 0s
-        let token_type_ids = token_ids.zeros_like()?;
+        Ok(())
+    }
 
-        println!("token_ids(input) shape: {:?}", token_ids.shape());
+    /// Fails when `embeddings` (`[n, hidden]`) don't have the hidden size of the model, so they
+    /// can't be compared with its queries.
+    fn check_hidden_size(&self, embeddings: &Tensor) -> anyhow::Result<()> {
+        let (_n, hidden_size) = embeddings.dims2()?;
+        anyhow::ensure!(
+            hidden_size == self.hidden_size,
+            "The embeddings have hidden size {hidden_size} but the model has hidden size {}, were \
+             they created with another model?",
+            self.hidden_size
+        );
 
-        let embeddings = self.model.forward(&token_ids, &token_type_ids)?;
-        let embeddings = Self::apply_max_pooling(&embeddings)?;
-        let embeddings = Self::l2_normalize(&embeddings)?;
+        Ok(())
+    }
 
-        println!(
-            "create_embeddings completed - shape: {:?}",
-            embeddings.shape()
+    /// Fits a [`Standardizer`] on the stored embeddings and applies it to them, so every
+    /// dimension has zero mean and unit variance. From then on queries are standardized the
+    /// same way before scoring, and [`Self::add_embeddings`] standardizes the new rows.
+    pub fn fit_standardizer(&mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.standardizer.is_none(),
+            "The embeddings are already standardized"
         );
+        let standardizer = Standardizer::fit(&self.embeddings)?;
+        self.set_standardizer(standardizer)
+    }
 
-        Ok(embeddings)
+    /// Applies previously fitted statistics, e.g. deserialized from disk, to the stored
+    /// embeddings, which must not have been standardized yet.
+    pub fn set_standardizer(&mut self, standardizer: Standardizer) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.standardizer.is_none(),
+            "The embeddings are already standardized"
+        );
+        self.embeddings = standardizer.transform(&self.embeddings)?;
+        self.standardizer = Some(standardizer);
+        self.reset_derived_indexes();
+
+        Ok(())
     }
 
-    pub fn score_vector_similarity(
-        &self,
-        vector: Tensor,
-        top_k: usize,
-    ) -> anyhow::Result<Vec<(usize, f32)>> {
-        let vec_len = self.embeddings.dim(0)?;
-        let mut scores = vec![(0, 0.0); vec_len];
+    pub fn standardizer(&self) -> Option<&Standardizer> {
+        self.standardizer.as_ref()
+    }
+
+    /// Returns the `[n, n]` matrix of cosine similarities between every pair of stored
+    /// embeddings, in f32, e.g. for clustering or evaluation.
+    ///
+    /// NOTE: the matrix takes O(n²) memory; for large corpora prefer
+    /// [`Self::score_batch`] over chunks of rows.
+    pub fn similarity_matrix(&self) -> anyhow::Result<Tensor> {
+        let normalized = Self::l2_normalize(&self.embeddings.to_dtype(DType::F32)?)?;
+        Ok(normalized.matmul(&normalized.t()?)?)
+    }
 
-        for (embedding_index, score_tuple) in scores.iter_mut().enumerate() {
-            let cur_vec = self.embeddings.get(embedding_index)?.unsqueeze(0)?;
-            // NOTE: cur_vec and (query) vector are already normalized
-            let cosine_similarity = (&cur_vec * &vector)?.sum_all()?.to_scalar::<f32>()?;
-            *score_tuple = (embedding_index, cosine_similarity);
+    /// Removes every stored embedding whose cosine similarity to an earlier, kept one exceeds
+    /// `threshold`, along with its document ID. Returns the removed row indices, in order, as
+    /// they were before the removal.
+    ///
+    /// Compares every pair of rows, so it costs O(n²) dot products.
+    pub fn dedup_embeddings(&mut self, threshold: f32) -> anyhow::Result<Vec<usize>> {
+        if self.embeddings.rank() != 2 {
+            return Ok(Vec::new());
         }
+        let normalized = Self::l2_normalize(&self.embeddings.to_dtype(DType::F32)?)?;
+        let n_embeddings = normalized.dim(0)?;
 
-        scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-        scores.truncate(top_k);
+        let mut kept = Vec::with_capacity(n_embeddings);
+        let mut removed = Vec::new();
+        for start in (0..n_embeddings).step_by(DEDUP_CHUNK_SIZE) {
+            let len = DEDUP_CHUNK_SIZE.min(n_embeddings - start);
+            // [chunk, hidden] x [hidden, n] -> [chunk, n]
+            let similarities = normalized
+                .narrow(0, start, len)?
+                .matmul(&normalized.t()?)?
+                .to_vec2::<f32>()?;
 
-        Ok(scores)
+            for (offset, similarities) in similarities.iter().enumerate() {
+                let index = start + offset;
+                match kept
+                    .iter()
+                    .any(|&other: &usize| similarities[other] > threshold)
+                {
+                    true => removed.push(index),
+                    false => kept.push(index),
+                }
+            }
+        }
+        if !removed.is_empty() {
+            self.retain_rows(&kept)?;
+        }
+
+        Ok(removed)
     }
 
-    pub fn apply_max_pooling(embeddings: &Tensor) -> anyhow::Result<Tensor> {
-        Ok(embeddings.max(1)?)
+    /// Deletes the stored embeddings at `indices`, along with their document IDs, and returns
+    /// how many are left. Duplicate indices are removed once, and indices out of range are
+    /// ignored. The rows after a removed one move up, so their indices change.
+    pub fn remove_embeddings(&mut self, indices: &[usize]) -> anyhow::Result<usize> {
+        if self.embeddings.rank() != 2 {
+            return Ok(0);
+        }
+        let n_embeddings = self.embeddings.dim(0)?;
+        let removed: HashSet<usize> = indices.iter().copied().collect();
+        let kept: Vec<usize> = (0..n_embeddings)
+            .filter(|index| !removed.contains(index))
+            .collect();
+        if kept.len() < n_embeddings {
+            self.retain_rows(&kept)?;
+        }
+
+        Ok(kept.len())
     }
 
-    pub fn apply_mean_pooling(embeddings: &Tensor) -> anyhow::Result<Tensor> {
-        let (_n_sentence, n_tokens, _hidden_size) = embeddings.dims3()?;
-        // TODO: Check if this is correct
-        // The number of tokens is different sentence by sentence
-        // Wondering if all the hidden vectors are valid
-        // If there are zero-padding tokens, their hidden vectors should be ignored.
-        let embeddings = (embeddings.sum(1)? / (n_tokens as f64))?;
+    /// Keeps only the stored embeddings (and document IDs) at the sorted `kept` indices.
+    fn retain_rows(&mut self, kept: &[usize]) -> anyhow::Result<()> {
+        let kept_indices: Vec<u32> = kept.iter().map(|&index| index as u32).collect();
+        let kept_indices = Tensor::new(kept_indices.as_slice(), self.embeddings.device())?;
+        self.embeddings = self.embeddings.index_select(&kept_indices, 0)?;
+        if let Some(doc_ids) = &mut self.doc_ids {
+            *doc_ids = kept.iter().map(|&index| doc_ids[index].clone()).collect();
+        }
+        // Row indices have shifted
+        self.bm25 = None;
+        self.quantized = None;
+        self.pq = None;
+        self.reset_derived_indexes();
 
-        Ok(embeddings)
+        Ok(())
     }
 
-    pub fn l2_normalize(embeddings: &Tensor) -> anyhow::Result<Tensor> {
-        Ok(embeddings.broadcast_div(&embeddings.sqr()?.sum_keepdim(1)?.sqrt()?)?)
+    /// Stores `embeddings` (`[n, hidden]`) as the set `name`, replacing any set of that name.
+    /// Named sets are searched with [`Self::score_set_similarity`] and are independent of the
+    /// main embeddings: they are not standardized, quantized or indexed.
+    pub fn add_embedding_set(
+        &mut self,
+        name: impl Into<String>,
+        embeddings: Tensor,
+    ) -> anyhow::Result<()> {
+        let name = name.into();
+        anyhow::ensure!(
+            embeddings.rank() == 2,
+            "Embedding set {name:?} must be a [n, hidden] tensor, got shape {:?}",
+            embeddings.shape()
+        );
+        let embeddings = embeddings.to_device(&self.device)?;
+        self.embedding_sets.insert(name, embeddings);
+
+        Ok(())
+    }
+
+    /// Loads the tensor under `key` in the safetensors file `filename` as the set `name`.
+    pub fn load_embedding_set(
+        &mut self,
+        name: impl Into<String>,
+        filename: &str,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        let embeddings = Self::load_embeddings(filename, key, &self.device)?;
+        self.add_embedding_set(name, embeddings)
+    }
+
+    pub fn embedding_set(&self, name: &str) -> Option<&Tensor> {
+        self.embedding_sets.get(name)
+    }
+
+    /// Names of the stored embedding sets, sorted.
+    pub fn embedding_set_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.embedding_sets.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn remove_embedding_set(&mut self, name: &str) -> Option<Tensor> {
+        self.embedding_sets.remove(name)
+    }
+
+    /// Drops the search structures built from the stored embeddings after they change.
+    fn reset_derived_indexes(&mut self) {
+        self.binary = OnceLock::new();
+        #[cfg(feature = "hnsw")]
+        {
+            self.index = None;
+        }
+    }
+
+    pub fn doc_ids(&self) -> Option<&[String]> {
+        self.doc_ids.as_deref()
+    }
+
+    /// Lexical index of the document texts, when set with [`Self::set_doc_texts`].
+    pub fn bm25(&self) -> Option<&Bm25> {
+        self.bm25.as_ref()
+    }
+
+    /// Indexes the text of every stored embedding row with BM25, for [`Self::search_hybrid`].
+    /// Only the term counts are kept, not the texts.
+    pub fn set_doc_texts(&mut self, texts: &[String]) -> anyhow::Result<()> {
+        let n_embeddings = self.embeddings.dim(0)?;
+        anyhow::ensure!(
+            texts.len() == n_embeddings,
+            "Got {} document texts for {n_embeddings} embeddings",
+            texts.len()
+        );
+        self.bm25 = Some(Bm25::new(texts));
+
+        Ok(())
+    }
+
+    /// Sets one document ID per stored embedding row, returned by [`Self::search`].
+    pub fn set_doc_ids(&mut self, doc_ids: Vec<String>) -> anyhow::Result<()> {
+        let n_embeddings = self.embeddings.dim(0)?;
+        anyhow::ensure!(
+            doc_ids.len() == n_embeddings,
+            "Got {} document IDs for {n_embeddings} embeddings",
+            doc_ids.len()
+        );
+        self.doc_ids = Some(doc_ids);
+
+        Ok(())
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Size of the embeddings the model produces, e.g. 384 for all-MiniLM-L6-v2 or 768 for
+    /// bert-base models.
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    /// The dtype of the model weights, and therefore of the embeddings it produces.
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    /// Truncates every tokenized sentence to at most `max_length` tokens.
+    pub fn set_max_length(&mut self, max_length: usize) -> anyhow::Result<()> {
+        let truncation = self.tokenizer.get_truncation().cloned().unwrap_or_default();
+        self.set_truncation(TruncationParams {
+            max_length,
+            ..truncation
+        })
+    }
+
+    /// Replaces the tokenizer truncation settings; `max_length` follows `truncation.max_length`.
+    pub fn set_truncation(&mut self, truncation: TruncationParams) -> anyhow::Result<()> {
+        let max_length = truncation.max_length;
+        self.tokenizer
+            .with_truncation(Some(truncation))
+            .map_err(anyhow::Error::msg)?;
+        self.max_length = max_length;
+        self.clear_query_cache();
+
+        Ok(())
+    }
+
+    /// How sentence pairs longer than `max_length` are cut down, see
+    /// [`Self::set_truncation_strategy`].
+    pub fn truncation_strategy(&self) -> TruncationStrategy {
+        self.tokenizer
+            .get_truncation()
+            .map(|truncation| truncation.strategy)
+            .unwrap_or_default()
+    }
+
+    /// Sets which side of a sentence pair loses tokens to fit `max_length`: the longest one, one
+    /// token at a time (the default, as BERT was trained), or only the first or second one, e.g.
+    /// to keep a short query whole next to a long passage. Single sentences are cut at the end
+    /// either way; [`Self::set_truncation`] also sets the direction.
+    pub fn set_truncation_strategy(&mut self, strategy: TruncationStrategy) -> anyhow::Result<()> {
+        let truncation = self.tokenizer.get_truncation().cloned().unwrap_or_default();
+        self.set_truncation(TruncationParams {
+            strategy,
+            ..truncation
+        })
+    }
+
+    /// Sets how batches are padded: to the longest sentence or to a fixed length, on the left or
+    /// the right. With `None`, batches are right-padded to their longest sentence.
+    pub fn set_padding(&mut self, padding: Option<PaddingParams>) {
+        self.tokenizer.with_padding(padding);
+        self.clear_query_cache();
+    }
+
+    /// The `(id, token)` batches are padded with: the tokenizer's padding token when padding is
+    /// configured, otherwise the first of `[PAD]` and `<pad>` found in its vocabulary. Fails when
+    /// there is neither, rather than padding with an id that means something else to the model.
+    pub fn pad_token(&self) -> anyhow::Result<(u32, String)> {
+        if let Some(padding) = self.tokenizer.get_padding() {
+            return Ok((padding.pad_id, padding.pad_token.clone()));
+        }
+
+        PAD_TOKENS
+            .iter()
+            .find_map(|token| {
+                self.tokenizer
+                    .token_to_id(token)
+                    .map(|id| (id, token.to_string()))
+            })
+            .with_context(|| {
+                format!(
+                    "The tokenizer has no padding configured and none of {PAD_TOKENS:?} in its \
+                     vocabulary, set a padding token with set_padding"
+                )
+            })
+    }
+
+    pub fn pooling(&self) -> PoolingStrategy {
+        self.pooling
+    }
+
+    /// Sets the pooling used by both `infer_sentence_embedding` and `create_embeddings`.
+    /// It should match the pooling the model was trained with.
+    pub fn set_pooling(&mut self, pooling: PoolingStrategy) {
+        self.pooling = pooling;
+        self.clear_query_cache();
+        if let Some(mismatch) = self.pooling_mismatch() {
+            warn!("{mismatch}");
+        }
+    }
+
+    /// Pooling the stored embeddings were created with, if known.
+    pub fn embeddings_pooling(&self) -> Option<PoolingStrategy> {
+        self.embeddings_pooling
+    }
+
+    /// With `strict`, scoring fails when queries are pooled differently from the stored
+    /// embeddings, whose scores would be meaningless. Otherwise that is only logged as a warning.
+    pub fn set_strict_pooling(&mut self, strict: bool) {
+        self.strict_pooling = strict;
+    }
+
+    fn pooling_mismatch(&self) -> Option<String> {
+        let embeddings_pooling = self.embeddings_pooling?;
+        (embeddings_pooling != self.pooling).then(|| {
+            format!(
+                "Stored embeddings were created with {} pooling but queries use {} pooling",
+                embeddings_pooling.as_str(),
+                self.pooling.as_str()
+            )
+        })
+    }
+
+    /// Warns about, or fails on with strict pooling, a query/corpus pooling mismatch.
+    fn check_pooling(&self) -> anyhow::Result<()> {
+        match self.pooling_mismatch() {
+            Some(mismatch) if self.strict_pooling => anyhow::bail!(mismatch),
+            Some(mismatch) => {
+                warn!("{mismatch}");
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Whether embeddings are L2-normalized after pooling.
+    pub fn normalize(&self) -> bool {
+        self.normalize
+    }
+
+    /// Set to `false` to get the raw pooled vectors, e.g. to feed a classifier. On by default,
+    /// which makes dot-product and cosine scores agree.
+    pub fn set_normalize(&mut self, normalize: bool) {
+        self.normalize = normalize;
+        self.clear_query_cache();
+    }
+
+    /// Whether cosine scores are rescaled to [0, 1], see [`Self::set_unit_interval_scores`].
+    pub fn unit_interval_scores(&self) -> bool {
+        self.unit_interval_scores
+    }
+
+    /// Set to `true` to map cosine scores from [-1, 1] to [0, 1] with `(cos + 1) / 2`, e.g. to
+    /// show them as percentages or pick thresholds more intuitively. The mapping is monotonic,
+    /// so rankings don't change; thresholds given to
+    /// [`Self::score_vector_similarity_threshold`] apply to the rescaled scores. Dot product,
+    /// L2 and approximate scores are left as they are.
+    pub fn set_unit_interval_scores(&mut self, unit_interval_scores: bool) {
+        self.unit_interval_scores = unit_interval_scores;
+    }
+
+    /// Tolerance under which stored embeddings are collapsed in search results, see
+    /// [`Self::set_collapse_duplicates`].
+    pub fn collapse_duplicates(&self) -> Option<f32> {
+        self.duplicate_tolerance
+    }
+
+    /// Set to `Some(tolerance)` to return a single representative, the best ranked, of stored
+    /// embeddings whose dimensions all differ by at most `tolerance` (`0.` for bit-identical
+    /// vectors), so that a corpus with exact duplicates doesn't fill the top-k with copies of
+    /// the same document. Searches keep going until `top_k` distinct results are found. Off by
+    /// default; applies to [`Self::score_vector_similarity`] and its in-memory and mmap variants.
+    pub fn set_collapse_duplicates(&mut self, tolerance: Option<f32>) {
+        self.duplicate_tolerance = tolerance;
+    }
+
+    /// Maps a cosine score to [0, 1] when [`Self::set_unit_interval_scores`] is on.
+    fn rescale_cosine(&self, mut scores: Vec<f32>) -> Vec<f32> {
+        if self.unit_interval_scores {
+            for score in &mut scores {
+                // Clamped, as rounding can push cosines slightly past ±1
+                *score = ((*score + 1.) / 2.).clamp(0., 1.);
+            }
+        }
+        scores
+    }
+
+    /// Whether special tokens such as `[CLS]` and `[SEP]` are added when tokenizing.
+    pub fn add_special_tokens(&self) -> bool {
+        self.add_special_tokens
+    }
+
+    /// Set to `false` to embed sentences without the special tokens, in both
+    /// [`Self::infer_sentence_embedding`] and [`Self::create_embeddings`]. On by default, as BERT
+    /// models are trained with them.
+    ///
+    /// Without them, CLS pooling takes the first word of the sentence instead of `[CLS]`, and an
+    /// empty sentence has no tokens left to embed.
+    pub fn set_add_special_tokens(&mut self, add_special_tokens: bool) {
+        self.add_special_tokens = add_special_tokens;
+        self.clear_query_cache();
+    }
+
+    pub fn output_dtype(&self) -> Option<DType> {
+        self.output_dtype
+    }
+
+    /// Makes [`Self::create_embeddings`] return its embeddings in `dtype`, e.g. `DType::F16` to
+    /// halve their size on disk. `None` keeps the model dtype.
+    ///
+    /// Queries are cast to the dtype of the stored embeddings before scoring, so searching half
+    /// precision embeddings with an f32 query works as usual, at half precision.
+    pub fn set_output_dtype(&mut self, dtype: Option<DType>) {
+        self.output_dtype = dtype;
+    }
+
+    pub fn prefixes(&self) -> &Prefixes {
+        &self.prefixes
+    }
+
+    pub fn set_prefixes(&mut self, prefixes: Prefixes) {
+        self.prefixes = prefixes;
+        self.clear_query_cache();
+    }
+
+    pub fn instructions(&self) -> &Instructions {
+        &self.instructions
+    }
+
+    pub fn set_instructions(&mut self, instructions: Instructions) {
+        self.instructions = instructions;
+        self.clear_query_cache();
+    }
+
+    /// `sentence` as fed to the model when embedded as a query.
+    fn query_text(&self, sentence: &str) -> String {
+        with_instruction(
+            self.instructions.query.as_deref(),
+            format!("{}{sentence}", self.prefixes.query),
+        )
+    }
+
+    /// `sentence` as fed to the model when embedded as a passage.
+    fn passage_text(&self, sentence: &str) -> String {
+        with_instruction(
+            self.instructions.passage.as_deref(),
+            format!("{}{sentence}", self.prefixes.passage),
+        )
+    }
+
+    /// Keeps the embeddings of the `size` most recently seen queries, so
+    /// [`Self::infer_sentence_embedding`] skips the model for repeated ones. 0, the default,
+    /// disables the cache. Resizing drops whatever was cached.
+    pub fn set_query_cache_size(&mut self, size: usize) {
+        self.query_cache = NonZeroUsize::new(size).map(|size| {
+            Mutex::new(QueryCache {
+                entries: LruCache::new(size),
+                hits: 0,
+                misses: 0,
+            })
+        });
+    }
+
+    pub fn query_cache_stats(&self) -> Option<QueryCacheStats> {
+        let cache = self.query_cache.as_ref()?;
+        let cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+
+        Some(QueryCacheStats {
+            hits: cache.hits,
+            misses: cache.misses,
+            len: cache.entries.len(),
+        })
+    }
+
+    /// Starts recording [`Metrics`] from zero, or stops recording them.
+    pub fn set_metrics_enabled(&mut self, enabled: bool) {
+        self.metrics = enabled.then(|| Mutex::new(Metrics::default()));
+    }
+
+    /// Returns the timings recorded so far, or `None` when metrics are disabled.
+    pub fn metrics(&self) -> Option<Metrics> {
+        let metrics = self.metrics.as_ref()?;
+        Some(*metrics.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Adds the time elapsed since `start` to the stage picked by `stage`, if enabled.
+    fn record_metric(&self, stage: fn(&mut Metrics) -> &mut StageMetrics, start: Instant) {
+        if let Some(metrics) = &self.metrics {
+            let mut metrics = metrics.lock().unwrap_or_else(PoisonError::into_inner);
+            stage(&mut metrics).record(start.elapsed());
+        }
+    }
+
+    /// Forgets cached queries after a change that affects their embeddings.
+    fn clear_query_cache(&mut self) {
+        if let Some(cache) = &mut self.query_cache {
+            let cache = cache.get_mut().unwrap_or_else(PoisonError::into_inner);
+            cache.entries.clear();
+        }
+    }
+
+    /// Embeds a single query sentence into a `[1, hidden]` tensor.
+    ///
+    /// An empty or whitespace-only sentence tokenizes to the special tokens alone, whose
+    /// embedding is returned: it is valid, but carries no meaning.
+    pub fn infer_sentence_embedding(&self, sentence: &str) -> anyhow::Result<Tensor> {
+        let Some(cache) = &self.query_cache else {
+            let (embeddings, _stats) = self.infer_with_stats(sentence)?;
+            return Ok(embeddings);
+        };
+
+        {
+            let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(embeddings) = cache.entries.get(sentence).cloned() {
+                cache.hits += 1;
+                return Ok(embeddings);
+            }
+        }
+
+        // Don't hold the lock during inference
+        let (embeddings, _stats) = self.infer_with_stats(sentence)?;
+        let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+        cache.misses += 1;
+        cache.entries.put(sentence.to_string(), embeddings.clone());
+
+        Ok(embeddings)
+    }
+
+    /// Runs a dummy query through the model, and scores it against the stored embeddings if
+    /// any, so one-time costs (kernel compilation on GPU, allocator growth) are paid before the
+    /// first real query. Nothing is cached, but the passes count in [`Self::metrics`].
+    pub fn warmup(&self) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let (query, _stats) = self.infer_with_stats("warmup")?;
+        if self.embeddings.rank() == 2 {
+            self.similarity_scores(query, SimilarityMetric::Cosine)?;
+        }
+        info!("Warmed up in {:?}", start.elapsed());
+
+        Ok(())
+    }
+
+    /// Like [`Self::infer_sentence_embedding`], but also reports how many tokens were fed to the
+    /// model and whether the sentence had to be truncated to `max_length` to fit.
+    pub fn infer_with_stats(&self, sentence: &str) -> anyhow::Result<(Tensor, InferenceStats)> {
+        let (embeddings, _norms, stats) = self.infer_query(sentence)?;
+        Ok((embeddings, stats))
+    }
+
+    /// Like [`Self::infer_sentence_embedding`], but also returns the `[1]` f32 L2 norm of the
+    /// pooled embedding before normalization, e.g. as a confidence signal: very short or
+    /// degenerate inputs tend to have distinctive norms.
+    pub fn infer_with_norm(&self, sentence: &str) -> anyhow::Result<(Tensor, Tensor)> {
+        let (embeddings, norms, _stats) = self.infer_query(sentence)?;
+        Ok((embeddings, norms))
+    }
+
+    /// Embeds a query, returning its embedding, pre-normalization norm and tokenization stats.
+    fn infer_query(&self, sentence: &str) -> anyhow::Result<(Tensor, Tensor, InferenceStats)> {
+        let sentence = self.query_text(sentence);
+        let (embeddings, tokens) = self.forward_sentence(&sentence)?;
+        let stats = InferenceStats {
+            // Padding, when configured, isn't fed to the model as tokens
+            token_count: tokens.get_attention_mask().iter().sum::<u32>() as usize,
+            // The tokenizer moves whatever it cut off into the overflowing encodings
+            truncated: !tokens.get_overflowing().is_empty(),
+        };
+
+        let attention_mask = self.attention_mask(&tokens)?;
+        let embeddings = self.pool(&embeddings, &attention_mask)?;
+        debug!("Embeddings shape after pooling: {:?}", embeddings.shape());
+
+        let (embeddings, norms) = self.normalize_with_norms(embeddings)?;
+
+        Ok((embeddings, norms, stats))
+    }
+
+    /// Embeds a query sentence with both max and mean pooling from a single forward pass, e.g. to
+    /// ensemble the two. Returns the `([1, hidden] max, [1, hidden] mean)` pair, whatever the
+    /// configured pooling: both ignore padding and are normalized as configured.
+    pub fn infer_dual_pooling(&self, sentence: &str) -> anyhow::Result<(Tensor, Tensor)> {
+        let sentence = self.query_text(sentence);
+        let (embeddings, tokens) = self.forward_sentence(&sentence)?;
+        let attention_mask = self.attention_mask(&tokens)?;
+
+        let max = Self::apply_max_pooling_masked(&embeddings, &attention_mask)?;
+        let mean = Self::apply_mean_pooling_masked(&embeddings, &attention_mask)?;
+
+        Ok((
+            self.normalize_if_enabled(max)?,
+            self.normalize_if_enabled(mean)?,
+        ))
+    }
+
+    /// Returns the `[1, seq_len, hidden]` hidden states of every token of `sentence`, before
+    /// pooling, along with the token strings (special tokens included) position by position.
+    ///
+    /// Unlike [`Self::infer_sentence_embedding`], no query prefix is added.
+    pub fn infer_token_embeddings(&self, sentence: &str) -> anyhow::Result<(Tensor, Vec<String>)> {
+        let (embeddings, tokens) = self.forward_sentence(sentence)?;
+        Ok((embeddings, tokens.get_tokens().to_vec()))
+    }
+
+    /// Runs the underlying `BertModel` on `[batch, seq]` token ids and token type ids, and
+    /// returns its unpooled `[batch, seq, hidden]` hidden states, for custom pooling or
+    /// analysis. No prefix, pooling or normalization is applied.
+    pub fn forward_raw(
+        &self,
+        token_ids: &Tensor,
+        token_type_ids: &Tensor,
+    ) -> anyhow::Result<Tensor> {
+        Ok(self.encode(token_ids, token_type_ids)?)
+    }
+
+    /// Embeds a sentence pair, e.g. a premise and hypothesis for NLI, read together as two
+    /// segments: the tokens of `second` get token type id 1. No prefix is added.
+    pub fn infer_pair_embedding(&self, first: &str, second: &str) -> anyhow::Result<Tensor> {
+        let (embeddings, tokens) = self.forward_input((first, second))?;
+
+        let attention_mask = self.attention_mask(&tokens)?;
+        let embeddings = self.pool(&embeddings, &attention_mask)?;
+
+        self.normalize_if_enabled(embeddings)
+    }
+
+    /// The `[1, seq_len]` attention mask of a single encoding, 0 at the padding the tokenizer
+    /// adds when padding is configured.
+    fn attention_mask(&self, tokens: &Encoding) -> anyhow::Result<Tensor> {
+        Ok(Tensor::new(tokens.get_attention_mask(), &self.device)?.unsqueeze(0)?)
+    }
+
+    /// Tokenizes a single sentence and runs the model over it.
+    fn forward_sentence(&self, sentence: &str) -> anyhow::Result<(Tensor, Encoding)> {
+        self.forward_input(sentence)
+    }
+
+    /// Tokenizes a sentence or sentence pair and runs the model over it.
+    fn forward_input<'s>(
+        &self,
+        input: impl Into<EncodeInput<'s>>,
+    ) -> anyhow::Result<(Tensor, Encoding)> {
+        let (tokens, token_ids, token_type_ids) = self.encode_input(input)?;
+
+        let start = Instant::now();
+        let embeddings = self.encode(&token_ids, &token_type_ids)?;
+        self.record_metric(|metrics| &mut metrics.forward, start);
+        debug!("Time taken for inference: {:?}", start.elapsed());
+        debug!("Embeddings shape: {:?}", embeddings.shape());
+
+        Ok((embeddings, tokens))
+    }
+
+    /// Tokenizes a sentence or sentence pair into `[1, seq_len]` token ids and token type ids,
+    /// the segment of each token: 0 for the first sentence, 1 for the second.
+    fn encode_input<'s>(
+        &self,
+        input: impl Into<EncodeInput<'s>>,
+    ) -> anyhow::Result<(Encoding, Tensor, Tensor)> {
+        let start = Instant::now();
+        let tokens = self
+            .tokenizer
+            .encode(input, self.add_special_tokens)
+            .map_err(anyhow::Error::msg)?;
+        self.record_metric(|metrics| &mut metrics.tokenization, start);
+
+        let token_ids = Tensor::new(tokens.get_ids(), &self.device)?.unsqueeze(0)?;
+        let token_type_ids = Tensor::new(tokens.get_type_ids(), &self.device)?.unsqueeze(0)?;
+
+        Ok((tokens, token_ids, token_type_ids))
+    }
+
+    /// Embeds every sentence of a batch (as passages, see [`Prefixes`]), returning a
+    /// `[n_sentences, hidden]` tensor. Fails on an empty batch.
+    ///
+    /// Like [`Self::infer_sentence_embedding`], an empty or whitespace-only sentence is embedded
+    /// from the special tokens alone, so all of them get the same, valid vector.
+    pub fn create_embeddings(&self, sentences: Vec<String>) -> anyhow::Result<Tensor> {
+        let (embeddings, _norms) = self.create_embeddings_with_norms(sentences)?;
+        Ok(embeddings)
+    }
+
+    /// Like [`Self::create_embeddings`], but also returns the `[n_sentences]` f32 L2 norms of
+    /// the pooled embeddings before normalization, see [`Self::infer_with_norm`].
+    pub fn create_embeddings_with_norms(
+        &self,
+        sentences: Vec<String>,
+    ) -> anyhow::Result<(Tensor, Tensor)> {
+        debug!("create_embeddings: sentences.len(): {}", sentences.len());
+        anyhow::ensure!(
+            !sentences.is_empty(),
+            "Cannot embed an empty batch of sentences"
+        );
+
+        let sentences =
+            match self.prefixes.passage.is_empty() && self.instructions.passage.is_none() {
+                true => sentences,
+                false => sentences
+                    .iter()
+                    .map(|sentence| self.passage_text(sentence))
+                    .collect(),
+            };
+        let (token_ids, attention_mask) = self.encode_batch_padded(sentences)?;
+        // Every sentence is a single segment
+        let token_type_ids = token_ids.zeros_like()?;
+
+        debug!("token_ids(input) shape: {:?}", token_ids.shape());
+
+        let start = Instant::now();
+        let embeddings = self.encode(&token_ids, &token_type_ids)?;
+        self.record_metric(|metrics| &mut metrics.forward, start);
+        let embeddings = self.pool(&embeddings, &attention_mask)?;
+        let (embeddings, norms) = self.normalize_with_norms(embeddings)?;
+        let embeddings = match self.output_dtype {
+            Some(dtype) => embeddings.to_dtype(dtype)?,
+            None => embeddings,
+        };
+
+        debug!(
+            "create_embeddings completed - shape: {:?}",
+            embeddings.shape()
+        );
+
+        Ok((embeddings, norms))
+    }
+
+    /// Like [`Self::create_embeddings`], but returns one plain `Vec<f32>` per sentence, e.g. to
+    /// hand over to a vector database client. Embeddings are converted to f32 whatever the output
+    /// dtype.
+    pub fn create_embeddings_vec(&self, sentences: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+        let embeddings = self.create_embeddings(sentences)?;
+        Ok(embeddings.to_dtype(DType::F32)?.to_vec2::<f32>()?)
+    }
+
+    /// Like [`Self::create_embeddings`], but runs the model over `batch_size` sentences at a time
+    /// so memory stays bounded on large corpora.
+    ///
+    /// NOTE: the underlying `BertModel` doesn't take an attention mask, so padding can slightly
+    /// change the output; results match a single pass exactly when the padded lengths agree.
+    pub fn create_embeddings_chunked(
+        &self,
+        sentences: Vec<String>,
+        batch_size: usize,
+    ) -> anyhow::Result<Tensor> {
+        self.create_embeddings_with_progress(sentences, batch_size, |_, _| {})
+    }
+
+    /// Like [`Self::create_embeddings_chunked`], calling `progress(processed, total)` with the
+    /// number of sentences embedded so far after every batch.
+    pub fn create_embeddings_with_progress(
+        &self,
+        sentences: Vec<String>,
+        batch_size: usize,
+        mut progress: impl FnMut(usize, usize),
+    ) -> anyhow::Result<Tensor> {
+        anyhow::ensure!(batch_size > 0, "batch_size must be greater than 0");
+        anyhow::ensure!(
+            !sentences.is_empty(),
+            "Cannot embed an empty batch of sentences"
+        );
+
+        let total = sentences.len();
+        let mut processed = 0;
+        let embeddings = sentences
+            .chunks(batch_size)
+            .map(|chunk| {
+                let embeddings = self.create_embeddings(chunk.to_vec())?;
+                processed += chunk.len();
+                progress(processed, total);
+                Ok(embeddings)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Tensor::cat(&embeddings, 0)?)
+    }
+
+    /// Like [`Self::create_embeddings_chunked`], but sizes every batch from the token counts of
+    /// its sentences, so that batches × longest sentence, the number of token positions the model
+    /// runs over once padded, stays within `max_tokens_per_batch`: short sentences go through in
+    /// large batches and long ones in small batches, keeping memory bounded without wasting
+    /// throughput on corpora of very uneven lengths. Sentences keep their order.
+    ///
+    /// Fails when a single sentence, once tokenized and truncated to `max_length`, has more
+    /// tokens than the budget.
+    pub fn create_embeddings_adaptive(
+        &self,
+        sentences: Vec<String>,
+        max_tokens_per_batch: usize,
+    ) -> anyhow::Result<Tensor> {
+        anyhow::ensure!(
+            !sentences.is_empty(),
+            "Cannot embed an empty batch of sentences"
+        );
+
+        let embeddings = self
+            .adaptive_batches(&sentences, max_tokens_per_batch)?
+            .into_iter()
+            .map(|batch| self.create_embeddings(sentences[batch].to_vec()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Tensor::cat(&embeddings, 0)?)
+    }
+
+    /// Splits `sentences` into consecutive batches of at most `max_tokens` padded token
+    /// positions, each as large as the budget allows.
+    fn adaptive_batches(
+        &self,
+        sentences: &[String],
+        max_tokens: usize,
+    ) -> anyhow::Result<Vec<Range<usize>>> {
+        // Tokenized the way create_embeddings will
+        let texts: Vec<_> = sentences
+            .iter()
+            .map(|sentence| self.passage_text(sentence))
+            .collect();
+        let lengths: Vec<_> = self
+            .tokenizer
+            .encode_batch(texts, self.add_special_tokens)
+            .map_err(anyhow::Error::msg)?
+            .iter()
+            .map(|encoding| encoding.get_ids().len())
+            .collect();
+
+        let mut batches = Vec::new();
+        let mut start = 0;
+        let mut longest = 0;
+        for (index, &length) in lengths.iter().enumerate() {
+            anyhow::ensure!(
+                length <= max_tokens,
+                "Sentence {index} has {length} tokens, more than max_tokens_per_batch \
+                 ({max_tokens}): raise it to at least max_length ({})",
+                self.max_length
+            );
+            let batch_longest = longest.max(length);
+            if (index + 1 - start) * batch_longest > max_tokens {
+                batches.push(start..index);
+                start = index;
+                longest = length;
+            } else {
+                longest = batch_longest;
+            }
+        }
+        batches.push(start..lengths.len());
+
+        Ok(batches)
+    }
+
+    /// Like [`Self::create_embeddings_chunked`], but embeds the batches concurrently on the
+    /// rayon thread pool, sharing the model weights, so a CPU-only machine can use all of its
+    /// cores. The embeddings come back in input order, equal to the chunked ones.
+    #[cfg(feature = "rayon")]
+    pub fn create_embeddings_parallel(
+        &self,
+        sentences: Vec<String>,
+        batch_size: usize,
+    ) -> anyhow::Result<Tensor> {
+        use rayon::prelude::*;
+
+        anyhow::ensure!(batch_size > 0, "batch_size must be greater than 0");
+        anyhow::ensure!(
+            !sentences.is_empty(),
+            "Cannot embed an empty batch of sentences"
+        );
+
+        // `collect` keeps the order of the batches, whichever thread finishes first
+        let embeddings = sentences
+            .par_chunks(batch_size)
+            .map(|chunk| self.create_embeddings(chunk.to_vec()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Tensor::cat(&embeddings, 0)?)
+    }
+
+    /// Lazily embeds `sentences` `batch_size` at a time, yielding one `[batch, hidden]` tensor per
+    /// batch, so only a single batch of sentences is held in memory at once.
+    ///
+    /// Yields a single error when `batch_size` is 0.
+    pub fn embed_stream<'a>(
+        &'a self,
+        sentences: impl Iterator<Item = String> + 'a,
+        batch_size: usize,
+    ) -> impl Iterator<Item = anyhow::Result<Tensor>> + 'a {
+        let mut sentences = sentences.fuse();
+        let mut done = false;
+
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            if batch_size == 0 {
+                done = true;
+                return Some(Err(anyhow::anyhow!("batch_size must be greater than 0")));
+            }
+
+            let batch: Vec<String> = sentences.by_ref().take(batch_size).collect();
+            if batch.is_empty() {
+                return None;
+            }
+            Some(self.create_embeddings(batch))
+        })
+    }
+
+    /// Embeds a document longer than `max_length` without dropping its end: its tokens are split
+    /// into windows of `window` tokens, each starting `stride` tokens after the previous one, and
+    /// every window is embedded on its own with the special tokens around it. No prefix is added.
+    ///
+    /// Returns the `[n_windows, hidden]` window embeddings, in document order; average them
+    /// (e.g. with `mean_keepdim(0)`) for a single document vector. A document of `len > window`
+    /// tokens gets `ceil((len - window) / stride) + 1` windows, a shorter one a single window.
+    pub fn embed_long_document(
+        &self,
+        text: &str,
+        window: usize,
+        stride: usize,
+    ) -> anyhow::Result<Tensor> {
+        use tokenizers::PostProcessor;
+
+        anyhow::ensure!(
+            (1..=window).contains(&stride),
+            "stride must be between 1 and window ({window}), got {stride}"
+        );
+        let n_special_tokens = match self.add_special_tokens {
+            true => self
+                .tokenizer
+                .get_post_processor()
+                .map_or(0, |processor| processor.added_tokens(false)),
+            false => 0,
+        };
+        anyhow::ensure!(
+            window + n_special_tokens <= self.max_length,
+            "A window of {window} tokens plus {n_special_tokens} special tokens exceeds max_length \
+             {}",
+            self.max_length
+        );
+
+        // The tokenizer cuts the overflow into windows overlapping by `window - stride` tokens
+        let mut tokenizer = self.tokenizer.clone();
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: window + n_special_tokens,
+                stride: window - stride,
+                ..Default::default()
+            }))
+            .map_err(anyhow::Error::msg)?;
+        let start = Instant::now();
+        let mut first = tokenizer
+            .encode(text, self.add_special_tokens)
+            .map_err(anyhow::Error::msg)?;
+        self.record_metric(|metrics| &mut metrics.tokenization, start);
+
+        let overflowing = first.take_overflowing();
+        let windows = std::iter::once(&first)
+            .chain(&overflowing)
+            .map(|window| self.embed_encoding(window))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let embeddings = Tensor::cat(&windows, 0)?;
+
+        Ok(match self.output_dtype {
+            Some(dtype) => embeddings.to_dtype(dtype)?,
+            None => embeddings,
+        })
+    }
+
+    /// Runs the model over an already tokenized sentence and pools it into a `[1, hidden]`
+    /// embedding.
+    fn embed_encoding(&self, encoding: &Encoding) -> anyhow::Result<Tensor> {
+        let token_ids = Tensor::new(encoding.get_ids(), &self.device)?.unsqueeze(0)?;
+        let token_type_ids = Tensor::new(encoding.get_type_ids(), &self.device)?.unsqueeze(0)?;
+        let attention_mask =
+            Tensor::new(encoding.get_attention_mask(), &self.device)?.unsqueeze(0)?;
+
+        let start = Instant::now();
+        let embeddings = self.encode(&token_ids, &token_type_ids)?;
+        self.record_metric(|metrics| &mut metrics.forward, start);
+        let embeddings = self.pool(&embeddings, &attention_mask)?;
+
+        self.normalize_if_enabled(embeddings)
+    }
+
+    /// Tokenizes a batch and right-pads every sequence to the longest one in it, unless padding
+    /// was configured with [`Self::set_padding`].
+    ///
+    /// Returns `(token_ids, attention_mask)`, both `[n_sentences, seq_len]`, where the mask is 1
+    /// for real tokens and 0 for padding.
+    fn encode_batch_padded(&self, sentences: Vec<String>) -> anyhow::Result<(Tensor, Tensor)> {
+        let start = Instant::now();
+        let tokens = self
+            .tokenizer
+            .encode_batch(sentences, self.add_special_tokens)
+            .map_err(anyhow::Error::msg)?;
+        self.record_metric(|metrics| &mut metrics.tokenization, start);
+
+        let seq_len = tokens.iter().map(|t| t.get_ids().len()).max().unwrap_or(0);
+        // Only looked up when needed, so batches of equal lengths don't need a pad token
+        let pad_id = match tokens.iter().any(|t| t.get_ids().len() < seq_len) {
+            true => self.pad_token()?.0,
+            false => 0,
+        };
+
+        let mut token_ids = Vec::with_capacity(tokens.len());
+        let mut attention_mask = Vec::with_capacity(tokens.len());
+        for tokens in tokens.iter() {
+            let mut ids = tokens.get_ids().to_vec();
+            // Already padded when the tokenizer has padding configured
+            let mut mask = tokens.get_attention_mask().to_vec();
+            ids.resize(seq_len, pad_id);
+            mask.resize(seq_len, 0);
+
+            token_ids.push(Tensor::new(ids.as_slice(), &self.device)?);
+            attention_mask.push(Tensor::new(mask.as_slice(), &self.device)?);
+        }
+
+        let token_ids = Tensor::stack(&token_ids, 0)?;
+        let attention_mask = Tensor::stack(&attention_mask, 0)?;
+
+        Ok((token_ids, attention_mask))
+    }
+
+    /// Returns the `top_k` stored embeddings most similar to `vector` by cosine similarity.
+    pub fn score_vector_similarity(
+        &self,
+        vector: Tensor,
+        top_k: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        self.score_vector_similarity_with(vector, top_k, SimilarityMetric::Cosine)
+    }
+
+    /// Embeds `query` with [`Self::infer_sentence_embedding`] and returns the `top_k` most
+    /// similar stored embeddings by cosine similarity.
+    pub fn search_text(&self, query: &str, top_k: usize) -> anyhow::Result<Vec<SearchResult>> {
+        let query = self.infer_sentence_embedding(query)?;
+        self.score_vector_similarity(query, top_k)
+    }
+
+    /// Cosine similarity between the embeddings of two sentences, both embedded as queries with
+    /// [`Self::infer_sentence_embedding`], e.g. for semantic textual similarity. No stored
+    /// embeddings are involved.
+    pub fn sentence_similarity(&self, a: &str, b: &str) -> anyhow::Result<f32> {
+        let a = self.infer_sentence_embedding(a)?.to_dtype(DType::F32)?;
+        let b = self.infer_sentence_embedding(b)?.to_dtype(DType::F32)?;
+        let scores = cosine_scores(&a, &b)?;
+
+        Ok(scores[0])
+    }
+
+    /// Like [`Self::score_vector_similarity`], but returns document IDs instead of row indices.
+    pub fn search(&self, vector: Tensor, top_k: usize) -> anyhow::Result<Vec<(String, f32)>> {
+        let doc_ids = self
+            .doc_ids
+            .as_ref()
+            .context("No document IDs set, use set_doc_ids first")?;
+        let n_embeddings = self.embeddings.dim(0)?;
+        anyhow::ensure!(
+            doc_ids.len() == n_embeddings,
+            "Got {} document IDs for {n_embeddings} embeddings",
+            doc_ids.len()
+        );
+
+        let scores = self.similarity_scores(vector, SimilarityMetric::Cosine)?;
+
+        Ok(self
+            .top_k_results(scores.into_iter().enumerate(), top_k)?
+            .into_iter()
+            .map(|result| (doc_ids[result.index].clone(), result.score))
+            .collect())
+    }
+
+    /// Like [`Self::score_vector_similarity`], but ranks by the given `metric`.
+    pub fn score_vector_similarity_with(
+        &self,
+        vector: Tensor,
+        top_k: usize,
+        metric: SimilarityMetric,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let scores = self.similarity_scores(vector, metric)?;
+
+        self.top_k_results(scores.into_iter().enumerate(), top_k)
+    }
+
+    /// Like [`Self::score_vector_similarity`], but scores every stored embedding with
+    /// `score(query, row)`, highest first, for metrics beyond cosine and dot product such as
+    /// Manhattan distance (negated) or a weighted cosine. Both are `[hidden]` vectors, the query
+    /// standardized like the stored embeddings.
+    ///
+    /// NOTE: this calls `score` once per row, each with its own small tensor ops, instead of a
+    /// single matmul over all of them, so it is orders of magnitude slower on large corpora.
+    /// Prefer it for re-scoring modest corpora, or use [`Self::score_against`] on a shortlist.
+    pub fn score_with<F>(
+        &self,
+        query: Tensor,
+        top_k: usize,
+        score: F,
+    ) -> anyhow::Result<Vec<SearchResult>>
+    where
+        F: Fn(&Tensor, &Tensor) -> anyhow::Result<f32>,
+    {
+        anyhow::ensure!(self.embeddings.rank() == 2, "No stored embeddings to score");
+        let start = Instant::now();
+        let query = self.prepare_query(query)?.flatten_all()?;
+        let scores = (0..self.embeddings.dim(0)?)
+            .map(|index| Ok((index, score(&query, &self.embeddings.get(index)?)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.record_metric(|metrics| &mut metrics.scoring, start);
+
+        self.top_k_results(scores, top_k)
+    }
+
+    /// Like [`Self::score_vector_similarity`], but runs the scan on `device`, e.g. on the CPU
+    /// for a model running on a GPU with little memory to spare, or the other way around. The
+    /// stored embeddings are copied to `device` on every call unless they already live there.
+    pub fn score_vector_similarity_on(
+        &self,
+        vector: Tensor,
+        top_k: usize,
+        device: &Device,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let scores = self.similarity_scores_on(vector, SimilarityMetric::Cosine, device)?;
+
+        self.top_k_results(scores.into_iter().enumerate(), top_k)
+    }
+
+    /// Like [`Self::score_vector_similarity`], but never returns the stored embeddings at
+    /// `exclude`, e.g. documents already shown to a user: the `top_k` results are the best of
+    /// the others, so no over-fetching is needed.
+    pub fn score_vector_similarity_excluding(
+        &self,
+        vector: Tensor,
+        top_k: usize,
+        exclude: &HashSet<usize>,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let scores = self.similarity_scores(vector, SimilarityMetric::Cosine)?;
+        let candidates = scores
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| !exclude.contains(index));
+
+        self.top_k_results(candidates, top_k)
+    }
+
+    /// Selects the `top_k` highest scored `(index, score)` pairs of the stored embeddings, with
+    /// their IDs.
+    fn top_k_results(
+        &self,
+        scores: impl IntoIterator<Item = (usize, f32)>,
+        top_k: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        self.top_k_results_from(scores, top_k, |index| {
+            Ok(self
+                .embeddings
+                .get(index)?
+                .to_dtype(DType::F32)?
+                .to_vec1()?)
+        })
+    }
+
+    /// Like [`Self::top_k_results`], with `row` reading the embedding at an index, to compare
+    /// candidates when duplicates are collapsed.
+    fn top_k_results_from(
+        &self,
+        scores: impl IntoIterator<Item = (usize, f32)>,
+        top_k: usize,
+        row: impl Fn(usize) -> anyhow::Result<Vec<f32>>,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let top = match self.duplicate_tolerance {
+            Some(tolerance) => select_top_k_distinct(scores, top_k, tolerance, row)?,
+            None => select_top_k(scores, top_k),
+        };
+
+        Ok(top
+            .into_iter()
+            .map(|(index, score)| SearchResult {
+                index,
+                score,
+                id: self
+                    .doc_ids
+                    .as_ref()
+                    .and_then(|doc_ids| doc_ids.get(index).cloned()),
+            })
+            .collect())
+    }
+
+    /// Like [`Self::score_vector_similarity`], but searches the embedding set `set_name`. The
+    /// results carry no document IDs.
+    pub fn score_set_similarity(
+        &self,
+        set_name: &str,
+        vector: Tensor,
+        top_k: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let embeddings = self.embedding_sets.get(set_name).with_context(|| {
+            format!(
+                "No embedding set named {set_name:?}, available sets: {:?}",
+                self.embedding_set_names()
+            )
+        })?;
+        check_query_hidden_size(embeddings, &vector)?;
+        let start = Instant::now();
+        let vector = vector
+            .to_device(embeddings.device())?
+            .to_dtype(embeddings.dtype())?;
+        let scores = cosine_scores(embeddings, &vector)?;
+        self.record_metric(|metrics| &mut metrics.scoring, start);
+
+        Ok(select_top_k(scores.into_iter().enumerate(), top_k)
+            .into_iter()
+            .map(|(index, score)| SearchResult {
+                index,
+                score,
+                id: None,
+            })
+            .collect())
+    }
+
+    /// Returns the `(row, score)` of the `top_k` rows of `candidates`, an arbitrary `[m, hidden]`
+    /// tensor such as a shortlist from another system, most similar to `query` by cosine
+    /// similarity. The stored embeddings are not involved, nor is the standardizer fitted on
+    /// them.
+    pub fn score_against(
+        &self,
+        query: &Tensor,
+        candidates: &Tensor,
+        top_k: usize,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        anyhow::ensure!(
+            candidates.rank() == 2,
+            "Candidates must be a [m, hidden] tensor, got shape {:?}",
+            candidates.dims()
+        );
+        check_query_hidden_size(candidates, query)?;
+        let start = Instant::now();
+        let query = query
+            .to_device(candidates.device())?
+            .to_dtype(candidates.dtype())?;
+        let scores = self.rescale_cosine(cosine_scores(candidates, &query)?);
+        self.record_metric(|metrics| &mut metrics.scoring, start);
+
+        Ok(select_top_k(scores.into_iter().enumerate(), top_k))
+    }
+
+    /// Moves a query to the device and dtype of the stored embeddings, where it may not live
+    /// when it comes from another model, and standardizes it like them. Fails when its hidden
+    /// size differs from theirs, or with strict pooling when it is pooled differently.
+    fn prepare_query(&self, vector: Tensor) -> anyhow::Result<Tensor> {
+        if self.strict_pooling {
+            self.check_pooling()?;
+        }
+        check_query_hidden_size(&self.embeddings, &vector)?;
+        let vector = vector
+            .to_device(self.embeddings.device())?
+            .to_dtype(self.embeddings.dtype())?;
+
+        match &self.standardizer {
+            Some(standardizer) => standardizer.transform(&vector),
+            None => Ok(vector),
+        }
+    }
+
+    /// Returns every stored embedding whose cosine similarity to `vector` is at least
+    /// `min_score`, best first, keeping at most `max_results` of them when given.
+    ///
+    /// Returns an empty `Vec` when nothing clears the threshold.
+    pub fn score_vector_similarity_threshold(
+        &self,
+        vector: Tensor,
+        min_score: f32,
+        max_results: Option<usize>,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        let scores = self.similarity_scores(vector, SimilarityMetric::Cosine)?;
+        let max_results = max_results.unwrap_or(scores.len());
+        let candidates = scores
+            .into_iter()
+            .enumerate()
+            .filter(|(_, score)| *score >= min_score);
+
+        Ok(select_top_k(candidates, max_results))
+    }
+
+    /// Scores `vector` against every stored embedding, in index order.
+    fn similarity_scores(
+        &self,
+        vector: Tensor,
+        metric: SimilarityMetric,
+    ) -> anyhow::Result<Vec<f32>> {
+        self.similarity_scores_on(vector, metric, self.embeddings.device())
+    }
+
+    /// Like [`Self::similarity_scores`], computed on `device`.
+    fn similarity_scores_on(
+        &self,
+        vector: Tensor,
+        metric: SimilarityMetric,
+        device: &Device,
+    ) -> anyhow::Result<Vec<f32>> {
+        let start = Instant::now();
+        let vector = self.prepare_query(vector)?.to_device(device)?;
+        // A no-op when the embeddings already live on `device`
+        let embeddings = self.embeddings.to_device(device)?;
+        let scores = match metric {
+            SimilarityMetric::Cosine => self.rescale_cosine(cosine_scores(&embeddings, &vector)?),
+            SimilarityMetric::Dot => {
+                // [n, hidden] x [hidden, 1] -> [n, 1]
+                let dot_products = embeddings.matmul(&vector.t()?)?;
+                dot_products
+                    .squeeze(1)?
+                    .to_dtype(DType::F32)?
+                    .to_vec1::<f32>()?
+            }
+        };
+        self.record_metric(|metrics| &mut metrics.scoring, start);
+
+        Ok(scores)
+    }
+
+    /// Runs [`Self::score_vector_similarity`] for every row of `queries` (`[q, hidden]`) with a
+    /// single `[q, n]` matmul, returning the top-k of each query in order.
+    pub fn score_batch(
+        &self,
+        queries: Tensor,
+        top_k: usize,
+    ) -> anyhow::Result<Vec<Vec<(usize, f32)>>> {
+        let start = Instant::now();
+        let queries = self.prepare_query(queries)?;
+
+        // [q, hidden] x [hidden, n] -> [q, n]
+        let dot_products = queries.matmul(&self.embeddings.t()?)?;
+        let query_norms = queries.sqr()?.sum_keepdim(1)?.sqrt()?;
+        let norms = self.embeddings.sqr()?.sum_keepdim(1)?.sqrt()?.t()?;
+        let scores = dot_products
+            .broadcast_div(&query_norms)?
+            .broadcast_div(&norms)?
+            .to_dtype(DType::F32)?
+            .to_vec2::<f32>()?;
+        self.record_metric(|metrics| &mut metrics.scoring, start);
+
+        Ok(scores
+            .into_iter()
+            .map(|scores| select_top_k(self.rescale_cosine(scores).into_iter().enumerate(), top_k))
+            .collect())
+    }
+
+    /// Ranks documents by late interaction (ColBERT's MaxSim): every query token is matched to
+    /// its most similar document token by cosine similarity, and those best similarities are
+    /// summed into the document score.
+    ///
+    /// `query_tokens` and each of `doc_tokens` are per-token embeddings, `[tokens, hidden]` or
+    /// `[1, tokens, hidden]` as returned by [`Self::infer_token_embeddings`]. Returns the `top_k`
+    /// best `(document index, score)` pairs, highest score first.
+    pub fn score_maxsim(
+        &self,
+        query_tokens: &Tensor,
+        doc_tokens: &[Tensor],
+        top_k: usize,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        let token_matrix = |tokens: &Tensor| -> anyhow::Result<Tensor> {
+            let tokens = match tokens.rank() {
+                3 => tokens.squeeze(0)?,
+                _ => tokens.clone(),
+            };
+            let tokens = tokens.to_device(&self.device)?.to_dtype(DType::F32)?;
+            Self::l2_normalize(&tokens)
+        };
+        let query_tokens = token_matrix(query_tokens)?;
+
+        let scores = doc_tokens
+            .iter()
+            .enumerate()
+            .map(|(index, doc_tokens)| {
+                // [q, hidden] x [hidden, d] -> [q, d]
+                let similarities = query_tokens.matmul(&token_matrix(doc_tokens)?.t()?)?;
+                let score = similarities.max(1)?.sum_all()?.to_scalar::<f32>()?;
+                Ok((index, score))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(select_top_k(scores, top_k))
+    }
+
+    /// Ranks the stored embeddings by `alpha * dense + (1 - alpha) * lexical`, where `dense` is
+    /// the cosine similarity to the embedding of `query` and `lexical` the BM25 score of their
+    /// texts, set with [`Self::set_doc_texts`], both min-max scaled to [0, 1] over the corpus.
+    /// Catches exact keyword matches, such as names or codes, that dense retrieval misses.
+    ///
+    /// `alpha = 1` gives the dense ranking, `alpha = 0` the BM25 one. The returned scores are
+    /// the fused ones.
+    pub fn search_hybrid(
+        &self,
+        query: &str,
+        top_k: usize,
+        alpha: f32,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        anyhow::ensure!(
+            (0. ..=1.).contains(&alpha),
+            "alpha must be between 0 and 1, got {alpha}"
+        );
+        let bm25 = self
+            .bm25
+            .as_ref()
+            .context("No document texts set, use set_doc_texts first")?;
+        let n_embeddings = self.embeddings.dim(0)?;
+        anyhow::ensure!(
+            bm25.len() == n_embeddings,
+            "Got {} document texts for {n_embeddings} embeddings, set them again after adding \
+             embeddings",
+            bm25.len()
+        );
+
+        let embedding = self.infer_sentence_embedding(query)?;
+        let dense = min_max_scale(self.similarity_scores(embedding, SimilarityMetric::Cosine)?);
+        let lexical = min_max_scale(bm25.scores(query));
+        let fused = dense
+            .into_iter()
+            .zip(lexical)
+            .map(|(dense, lexical)| alpha * dense + (1. - alpha) * lexical);
+
+        self.top_k_results(fused.enumerate(), top_k)
+    }
+
+    /// Returns `top_k` results picked by maximal marginal relevance: from the
+    /// `MMR_POOL_FACTOR * top_k` most similar embeddings, each next pick maximizes
+    /// `lambda * relevance - (1 - lambda) * redundancy`, where redundancy is the highest cosine
+    /// similarity to an already picked result.
+    ///
+    /// `lambda = 1` gives plain top-k; lower values favour diverse results. Scores in the output
+    /// are the cosine similarities to `query`, in pick order.
+    pub fn search_mmr(
+        &self,
+        query: Tensor,
+        top_k: usize,
+        lambda: f32,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        anyhow::ensure!(
+            (0. ..=1.).contains(&lambda),
+            "lambda must be between 0 and 1, got {lambda}"
+        );
+        let scores = self.similarity_scores(query, SimilarityMetric::Cosine)?;
+        let candidates = select_top_k(
+            scores.into_iter().enumerate(),
+            top_k.saturating_mul(MMR_POOL_FACTOR),
+        );
+        if candidates.is_empty() {
+            return Ok(candidates);
+        }
+
+        let indices: Vec<u32> = candidates.iter().map(|&(index, _)| index as u32).collect();
+        let indices = Tensor::new(indices.as_slice(), self.embeddings.device())?;
+        let pool = self
+            .embeddings
+            .index_select(&indices, 0)?
+            .to_dtype(DType::F32)?;
+        let pool = Self::l2_normalize(&pool)?;
+        // [c, c] cosine similarities between candidates
+        let pairwise = pool.matmul(&pool.t()?)?.to_vec2::<f32>()?;
+
+        // The most relevant candidate always goes first
+        let mut selected = vec![0];
+        let mut redundancy = pairwise[0].clone();
+        let mut remaining: Vec<usize> = (1..candidates.len()).collect();
+        while selected.len() < top_k && !remaining.is_empty() {
+            let (position, _) = remaining
+                .iter()
+                .enumerate()
+                .map(|(position, &candidate)| {
+                    let relevance = candidates[candidate].1;
+                    let score = lambda * relevance - (1. - lambda) * redundancy[candidate];
+                    (position, score)
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap();
+            let picked = remaining.remove(position);
+
+            for (redundancy, similarity) in redundancy.iter_mut().zip(&pairwise[picked]) {
+                *redundancy = redundancy.max(*similarity);
+            }
+            selected.push(picked);
+        }
+
+        Ok(selected
+            .into_iter()
+            .map(|candidate| candidates[candidate])
+            .collect())
+    }
+
+    /// Builds an int8 copy of the stored embeddings for
+    /// [`Self::score_vector_similarity_quantized`]. Call it again after changing the embeddings.
+    pub fn quantize_embeddings(&mut self) -> anyhow::Result<()> {
+        self.quantized = Some(QuantizedEmbeddings::quantize(&self.embeddings)?);
+        Ok(())
+    }
+
+    pub fn quantized_embeddings(&self) -> Option<&QuantizedEmbeddings> {
+        self.quantized.as_ref()
+    }
+
+    /// Like [`Self::score_vector_similarity`], but scores against the int8 embeddings built by
+    /// [`Self::quantize_embeddings`]. Scores are approximate; for normalized embeddings the
+    /// ranking closely follows the f32 one.
+    pub fn score_vector_similarity_quantized(
+        &self,
+        vector: Tensor,
+        top_k: usize,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        let quantized = self
+            .quantized
+            .as_ref()
+            .context("No quantized embeddings, use quantize_embeddings first")?;
+        let scores = quantized.cosine_scores(&self.prepare_query(vector)?)?;
+
+        Ok(select_top_k(scores.into_iter().enumerate(), top_k))
+    }
+
+    /// Mean (`[1, hidden]`) of the stored embeddings at `indices`, e.g. to represent a group of
+    /// documents; L2-normalized when `normalize` is set, to search with it like a query.
+    pub fn centroid(&self, indices: &[usize], normalize: bool) -> anyhow::Result<Tensor> {
+        let centroid = self.rows(indices)?.mean_keepdim(0)?;
+
+        match normalize {
+            true => Self::l2_normalize(&centroid),
+            false => Ok(centroid),
+        }
+    }
+
+    /// The one of `indices` whose stored embedding is closest to their centroid, by Euclidean
+    /// distance: an actual member that represents the group. Ties go to the first of `indices`.
+    pub fn medoid(&self, indices: &[usize]) -> anyhow::Result<usize> {
+        let rows = self.rows(indices)?;
+        let centroid = rows.mean_keepdim(0)?;
+        let distances = rows
+            .broadcast_sub(&centroid)?
+            .sqr()?
+            .sum(1)?
+            .to_vec1::<f32>()?;
+
+        let (position, _) = distances
+            .iter()
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(b.1))
+            .context("No indices given")?;
+        Ok(indices[position])
+    }
+
+    /// The f32 stored embeddings at `indices`, `[indices.len(), hidden]`.
+    fn rows(&self, indices: &[usize]) -> anyhow::Result<Tensor> {
+        anyhow::ensure!(!indices.is_empty(), "No indices given");
+        anyhow::ensure!(self.embeddings.rank() == 2, "No embeddings stored");
+        let n_embeddings = self.embeddings.dim(0)?;
+        if let Some(index) = indices.iter().find(|&&index| index >= n_embeddings) {
+            anyhow::bail!("Index {index} out of range for {n_embeddings} embeddings");
+        }
+
+        let indices: Vec<u32> = indices.iter().map(|&index| index as u32).collect();
+        let indices = Tensor::new(indices.as_slice(), self.embeddings.device())?;
+        Ok(self
+            .embeddings
+            .index_select(&indices, 0)?
+            .to_dtype(DType::F32)?)
+    }
+
+    /// Clusters the stored embeddings into `k` groups by cosine similarity (spherical k-means),
+    /// running at most `iters` rounds after seeding with k-means++. Returns the `[k, hidden]`
+    /// unit-norm f32 centroids and the cluster of every stored embedding, in row order.
+    pub fn kmeans(&self, k: usize, iters: usize) -> anyhow::Result<(Tensor, Vec<usize>)> {
+        anyhow::ensure!(self.embeddings.rank() == 2, "No embeddings to cluster");
+        let points = Self::l2_normalize(&self.embeddings.to_dtype(DType::F32)?)?;
+        let n_embeddings = points.dim(0)?;
+        anyhow::ensure!(
+            (1..=n_embeddings).contains(&k),
+            "k must be between 1 and the number of embeddings ({n_embeddings}), got {k}"
+        );
+
+        let assign = |centroids: &Tensor| -> anyhow::Result<Vec<u32>> {
+            // [n, hidden] x [hidden, k] -> [n, k] cosine similarities
+            Ok(points.matmul(&centroids.t()?)?.argmax(1)?.to_vec1()?)
+        };
+        let clusters = Tensor::arange(0u32, k as u32, points.device())?.unsqueeze(0)?;
+        let mut centroids = kmeans_plus_plus(&points, k)?;
+        let mut assignments = assign(&centroids)?;
+        for _ in 0..iters {
+            let assigned = Tensor::new(assignments.as_slice(), points.device())?;
+            // [n, k], 1 where a row belongs to a cluster
+            let members = assigned
+                .unsqueeze(1)?
+                .broadcast_eq(&clusters)?
+                .to_dtype(DType::F32)?;
+            let sums = Self::l2_normalize(&members.t()?.matmul(&points)?)?;
+            // A cluster left empty keeps its centroid
+            let empty = members
+                .sum(0)?
+                .eq(0f32)?
+                .unsqueeze(1)?
+                .broadcast_as(centroids.shape())?;
+            centroids = empty.where_cond(&centroids, &sums)?;
+
+            let reassigned = assign(&centroids)?;
+            if reassigned == assignments {
+                break;
+            }
+            assignments = reassigned;
+        }
+
+        Ok((
+            centroids,
+            assignments
+                .into_iter()
+                .map(|cluster| cluster as usize)
+                .collect(),
+        ))
+    }
+
+    /// Trains a product quantizer on the stored embeddings, with `n_subspaces` subspaces of
+    /// `n_centroids` centroids each, and encodes them to `n_subspaces` bytes per vector for
+    /// [`Self::search_pq`]. Call it again after changing the embeddings.
+    pub fn train_pq(&mut self, n_subspaces: usize, n_centroids: usize) -> anyhow::Result<()> {
+        anyhow::ensure!(self.embeddings.rank() == 2, "No embeddings to quantize");
+        let quantizer = ProductQuantizer::train(
+            &self.embeddings,
+            n_subspaces,
+            n_centroids,
+            PQ_KMEANS_ITERATIONS,
+        )?;
+        let codes = quantizer.encode(&self.embeddings)?;
+        self.pq = Some((quantizer, codes));
+
+        Ok(())
+    }
+
+    /// The quantizer trained by [`Self::train_pq`], e.g. to encode embeddings stored elsewhere.
+    pub fn product_quantizer(&self) -> Option<&ProductQuantizer> {
+        self.pq.as_ref().map(|(quantizer, _codes)| quantizer)
+    }
+
+    /// Like [`Self::score_vector_l2`], but against the product-quantized embeddings built by
+    /// [`Self::train_pq`]: returns the `top_k` nearest by approximate squared Euclidean
+    /// distance, smallest first.
+    pub fn search_pq(&self, query: Tensor, top_k: usize) -> anyhow::Result<Vec<(usize, f32)>> {
+        let (quantizer, codes) = self
+            .pq
+            .as_ref()
+            .context("No product quantizer, use train_pq first")?;
+        let distances = quantizer.distances(codes, &self.prepare_query(query)?)?;
+        let negated = distances
+            .into_iter()
+            .enumerate()
+            .map(|(index, distance)| (index, -distance));
+
+        Ok(select_top_k(negated, top_k)
+            .into_iter()
+            .map(|(index, distance)| (index, -distance))
+            .collect())
+    }
+
+    /// Memory-maps the embeddings stored under `key` in the safetensors file `filename`, for
+    /// [`Self::score_vector_similarity_mmap`]. Unlike loading them with the builder, only the
+    /// header is read: rows are read from disk while scoring, a chunk at a time, so a corpus
+    /// larger than RAM can be searched. The main embeddings are left untouched.
+    pub fn load_embeddings_mmap(&mut self, filename: &str, key: &str) -> anyhow::Result<()> {
+        let mmaped = MmapedEmbeddings::open(Path::new(filename), key)?;
+        anyhow::ensure!(
+            mmaped.hidden_size() == self.hidden_size,
+            "The embeddings in {filename} have hidden size {} but the model has hidden size {}, \
+             were they created with another model?",
+            mmaped.hidden_size(),
+            self.hidden_size
+        );
+        info!(
+            "Mapped embedding shape: [{}, {}]",
+            mmaped.len(),
+            mmaped.hidden_size()
+        );
+        self.mmaped = Some(mmaped);
+
+        Ok(())
+    }
+
+    pub fn mmaped_embeddings(&self) -> Option<&MmapedEmbeddings> {
+        self.mmaped.as_ref()
+    }
+
+    /// Like [`Self::score_vector_similarity`], but scores against the embeddings mapped by
+    /// [`Self::load_embeddings_mmap`], with the same results. A fitted standardizer is applied to
+    /// their rows as they are read. The results carry no document IDs, which belong to the
+    /// in-memory embeddings.
+    pub fn score_vector_similarity_mmap(
+        &self,
+        vector: Tensor,
+        top_k: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let mmaped = self
+            .mmaped
+            .as_ref()
+            .context("No memory-mapped embeddings, use load_embeddings_mmap first")?;
+        if self.strict_pooling {
+            self.check_pooling()?;
+        }
+        let actual = vector.dim(D::Minus1)?;
+        anyhow::ensure!(
+            actual == mmaped.hidden_size(),
+            "Query has hidden size {actual} but the stored embeddings have hidden size {}, were \
+             they created with another model?",
+            mmaped.hidden_size()
+        );
+        let standardize = |rows: Tensor| match &self.standardizer {
+            Some(standardizer) => standardizer.transform(&rows),
+            None => Ok(rows),
+        };
+        let vector = standardize(vector.to_device(&self.device)?)?.to_dtype(mmaped.dtype())?;
+
+        let start = Instant::now();
+        let mut scores = Vec::with_capacity(mmaped.len());
+        for chunk_start in (0..mmaped.len()).step_by(MMAP_CHUNK_ROWS) {
+            let chunk_end = (chunk_start + MMAP_CHUNK_ROWS).min(mmaped.len());
+            let rows = standardize(mmaped.rows(chunk_start..chunk_end, &self.device)?)?;
+            scores.extend(self.rescale_cosine(cosine_scores(&rows, &vector)?));
+        }
+        self.record_metric(|metrics| &mut metrics.scoring, start);
+
+        let results = self.top_k_results_from(scores.into_iter().enumerate(), top_k, |index| {
+            let row = standardize(mmaped.rows(index..index + 1, &self.device)?)?;
+            Ok(row.to_dtype(DType::F32)?.flatten_all()?.to_vec1()?)
+        })?;
+
+        Ok(results
+            .into_iter()
+            .map(|result| SearchResult { id: None, ..result })
+            .collect())
+    }
+
+    /// Quantizes the stored embeddings to 1 bit per dimension (set when positive), packed into a
+    /// `[n, ceil(hidden / 8)]` u8 tensor: 32x smaller than f32.
+    pub fn binarize_embeddings(&self) -> anyhow::Result<Tensor> {
+        quantization::binarize(&self.embeddings)
+    }
+
+    /// Returns the `top_k` stored embeddings closest to `query` by Hamming distance between
+    /// their binarized forms, smallest distance first.
+    ///
+    /// This is approximate: binarization drops magnitudes, so the ranking only roughly follows
+    /// cosine similarity. It is best used to shortlist candidates for exact re-scoring.
+    pub fn search_binary(&self, query: Tensor, top_k: usize) -> anyhow::Result<Vec<(usize, u32)>> {
+        let codes = match self.binary.get() {
+            Some(codes) => codes,
+            None => {
+                let codes = self.binarize_embeddings()?;
+                self.binary.get_or_init(|| codes)
+            }
+        };
+        let query = quantization::binarize(&self.prepare_query(query)?)?;
+
+        let mut distances: Vec<_> = quantization::hamming_distances(codes, &query)?
+            .into_iter()
+            .enumerate()
+            .collect();
+        distances.sort_by_key(|&(index, distance)| (distance, index));
+        distances.truncate(top_k);
+
+        Ok(distances)
+    }
+
+    /// Builds an HNSW index over the stored embeddings for [`Self::search_ann`], with the
+    /// default [`HnswParams`]. Adding embeddings drops the index, so call it again afterwards.
+    #[cfg(feature = "hnsw")]
+    pub fn build_index(&mut self) -> anyhow::Result<()> {
+        self.build_index_with(HnswParams::default())
+    }
+
+    /// Like [`Self::build_index`], with explicit graph parameters.
+    #[cfg(feature = "hnsw")]
+    pub fn build_index_with(&mut self, params: HnswParams) -> anyhow::Result<()> {
+        self.index = Some(HnswIndex::build(&self.embeddings, params)?);
+        Ok(())
+    }
+
+    #[cfg(feature = "hnsw")]
+    pub fn index(&self) -> Option<&HnswIndex> {
+        self.index.as_ref()
+    }
+
+    /// Approximate version of [`Self::score_vector_similarity`] using the index built by
+    /// [`Self::build_index`]. `ef` trades speed for recall: larger values explore more
+    /// candidates; a few times `top_k` is usually enough.
+    #[cfg(feature = "hnsw")]
+    pub fn search_ann(
+        &self,
+        query: Tensor,
+        top_k: usize,
+        ef: usize,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        let index = self
+            .index
+            .as_ref()
+            .context("No HNSW index, use build_index first")?;
+
+        index.search(&self.prepare_query(query)?, top_k, ef)
+    }
+
+    /// Returns the `top_k` stored embeddings nearest to `vector` by squared Euclidean distance,
+    /// smallest distance first.
+    pub fn score_vector_l2(
+        &self,
+        vector: Tensor,
+        top_k: usize,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        let vector = self.prepare_query(vector)?;
+        let distances = self.score_rows(|cur_vec| {
+            let distance = (cur_vec - &vector)?.sqr()?.sum_all()?;
+            Ok(distance.to_dtype(DType::F32)?.to_scalar::<f32>()?)
+        })?;
+
+        // Nearest first: the highest negated distances
+        let negated = distances
+            .into_iter()
+            .map(|(index, distance)| (index, -distance));
+
+        Ok(select_top_k(negated, top_k)
+            .into_iter()
+            .map(|(index, distance)| (index, -distance))
+            .collect())
+    }
+
+    /// Applies `score_row` to every stored embedding (as a `[1, hidden]` tensor), returning
+    /// `(index, score)` pairs in index order. Runs across threads with the `rayon` feature.
+    fn score_rows<F>(&self, score_row: F) -> anyhow::Result<Vec<(usize, f32)>>
+    where
+        F: Fn(&Tensor) -> anyhow::Result<f32> + Sync,
+    {
+        #[cfg(feature = "rayon")]
+        return self.score_rows_parallel(score_row);
+        #[cfg(not(feature = "rayon"))]
+        return self.score_rows_serial(score_row);
+    }
+
+    fn score_row_at<F>(&self, embedding_index: usize, score_row: &F) -> anyhow::Result<(usize, f32)>
+    where
+        F: Fn(&Tensor) -> anyhow::Result<f32>,
+    {
+        let cur_vec = self.embeddings.get(embedding_index)?.unsqueeze(0)?;
+        Ok((embedding_index, score_row(&cur_vec)?))
+    }
+
+    #[cfg_attr(feature = "rayon", allow(dead_code))]
+    fn score_rows_serial<F>(&self, score_row: F) -> anyhow::Result<Vec<(usize, f32)>>
+    where
+        F: Fn(&Tensor) -> anyhow::Result<f32>,
+    {
+        (0..self.embeddings.dim(0)?)
+            .map(|embedding_index| self.score_row_at(embedding_index, &score_row))
+            .collect()
+    }
+
+    #[cfg(feature = "rayon")]
+    fn score_rows_parallel<F>(&self, score_row: F) -> anyhow::Result<Vec<(usize, f32)>>
+    where
+        F: Fn(&Tensor) -> anyhow::Result<f32> + Sync,
+    {
+        use rayon::prelude::*;
+
+        (0..self.embeddings.dim(0)?)
+            .into_par_iter()
+            .map(|embedding_index| self.score_row_at(embedding_index, &score_row))
+            .collect()
+    }
+
+    fn normalize_if_enabled(&self, embeddings: Tensor) -> anyhow::Result<Tensor> {
+        match self.normalize {
+            true => Self::l2_normalize(&embeddings),
+            false => Ok(embeddings),
+        }
+    }
+
+    /// Like [`Self::normalize_if_enabled`], also returning the `[n]` f32 L2 norms of the rows
+    /// before normalization.
+    fn normalize_with_norms(&self, embeddings: Tensor) -> anyhow::Result<(Tensor, Tensor)> {
+        let norms = embeddings.sqr()?.sum_keepdim(1)?.sqrt()?;
+        let normalized = match self.normalize {
+            true => embeddings.broadcast_div(&norms.maximum(norm_epsilon(embeddings.dtype()))?)?,
+            false => embeddings,
+        };
+
+        Ok((normalized, norms.squeeze(1)?.to_dtype(DType::F32)?))
+    }
+
+    fn pool(&self, embeddings: &Tensor, attention_mask: &Tensor) -> anyhow::Result<Tensor> {
+        match self.pooling {
+            PoolingStrategy::Max => Self::apply_max_pooling(embeddings),
+            PoolingStrategy::MaxMasked => {
+                Self::apply_max_pooling_masked(embeddings, attention_mask)
+            }
+            PoolingStrategy::Mean => Self::apply_mean_pooling(embeddings),
+            PoolingStrategy::MeanMasked => {
+                Self::apply_mean_pooling_masked(embeddings, attention_mask)
+            }
+            PoolingStrategy::Cls => Self::apply_cls_pooling(embeddings),
+        }
+    }
+
+    /// Takes the per-dimension max over the tokens of every sentence. A sentence reduced to a
+    /// single token, e.g. a one-word input without special tokens, pools to that token's hidden
+    /// state.
+    pub fn apply_max_pooling(embeddings: &Tensor) -> anyhow::Result<Tensor> {
+        let (_n_sentences, n_tokens, _hidden_size) = embeddings.dims3()?;
+        anyhow::ensure!(n_tokens > 0, "Cannot pool sentences of 0 tokens");
+        // Nothing to reduce, so skip the reduction over a dimension of size 1
+        if n_tokens == 1 {
+            return Ok(embeddings.squeeze(1)?);
+        }
+
+        Ok(embeddings.max(1)?)
+    }
+
+    /// Max pooling that ignores padding positions: they are replaced by the lowest value of the
+    /// dtype before taking the max, so they never win it.
+    ///
+    /// `embeddings` is `[n_sentences, n_tokens, hidden]` and `attention_mask` is
+    /// `[n_sentences, n_tokens]` with 1 for real tokens and 0 for padding.
+    pub fn apply_max_pooling_masked(
+        embeddings: &Tensor,
+        attention_mask: &Tensor,
+    ) -> anyhow::Result<Tensor> {
+        let (_n_sentences, n_tokens, _hidden_size) = embeddings.dims3()?;
+        anyhow::ensure!(n_tokens > 0, "Cannot pool sentences of 0 tokens");
+        let lowest = match embeddings.dtype() {
+            DType::F16 => -65504.,
+            DType::BF16 => -3.38e38,
+            _ => f32::MIN as f64,
+        };
+        let padding = (embeddings.zeros_like()? + lowest)?;
+        let attention_mask = attention_mask
+            .to_dtype(DType::U8)?
+            .unsqueeze(2)?
+            .broadcast_as(embeddings.shape())?;
+
+        Ok(attention_mask.where_cond(embeddings, &padding)?.max(1)?)
+    }
+
+    pub fn apply_mean_pooling(embeddings: &Tensor) -> anyhow::Result<Tensor> {
+        let (_n_sentence, n_tokens, _hidden_size) = embeddings.dims3()?;
+        // TODO: Check if this is correct
+        // The number of tokens is different sentence by sentence
+        // Wondering if all the hidden vectors are valid
+        // If there are zero-padding tokens, their hidden vectors should be ignored.
+        let embeddings = (embeddings.sum(1)? / (n_tokens as f64))?;
+
+        Ok(embeddings)
+    }
+
+    /// Takes the hidden state of the first (`[CLS]`) token of every sentence.
+    pub fn apply_cls_pooling(embeddings: &Tensor) -> anyhow::Result<Tensor> {
+        Ok(embeddings.get_on_dim(1, 0)?)
+    }
+
+    /// Mean pooling that ignores padding positions, as done by sentence-transformers.
+    ///
+    /// `embeddings` is `[n_sentences, n_tokens, hidden]` and `attention_mask` is
+    /// `[n_sentences, n_tokens]` with 1 for real tokens and 0 for padding.
+    pub fn apply_mean_pooling_masked(
+        embeddings: &Tensor,
+        attention_mask: &Tensor,
+    ) -> anyhow::Result<Tensor> {
+        let attention_mask = attention_mask.to_dtype(embeddings.dtype())?.unsqueeze(2)?;
+        let summed = embeddings.broadcast_mul(&attention_mask)?.sum(1)?;
+        // Clamp so that a fully-masked sentence doesn't divide by zero
+        let n_tokens = attention_mask.sum(1)?.maximum(1f64)?;
+
+        Ok(summed.broadcast_div(&n_tokens)?)
+    }
+
+    /// Weighted mean of the token hidden states, e.g. by IDF or attention weight.
+    ///
+    /// `embeddings` is `[n_sentences, n_tokens, hidden]` and `weights` is `[n_sentences, n_tokens]`
+    /// with one non-negative weight per token; weighting padding by 0 ignores it.
+    pub fn apply_weighted_mean_pooling(
+        embeddings: &Tensor,
+        weights: &Tensor,
+    ) -> anyhow::Result<Tensor> {
+        let weights = weights.to_dtype(embeddings.dtype())?.unsqueeze(2)?;
+        let summed = embeddings.broadcast_mul(&weights)?.sum(1)?;
+        // Keep all-zero weights from dividing by zero
+        let total_weight = weights.sum(1)?.maximum(1e-12)?;
+
+        Ok(summed.broadcast_div(&total_weight)?)
+    }
+
+    /// Scales every row to unit L2 norm. All-zero rows stay zero instead of turning into NaNs.
+    pub fn l2_normalize(embeddings: &Tensor) -> anyhow::Result<Tensor> {
+        let norms = embeddings
+            .sqr()?
+            .sum_keepdim(1)?
+            .sqrt()?
+            .maximum(norm_epsilon(embeddings.dtype()))?;
+
+        Ok(embeddings.broadcast_div(&norms)?)
+    }
+}
+
+pub const DEFAULT_MODEL_NAME: &str = "sentence-transformers/all-MiniLM-L6-v2";
+pub const DEFAULT_REVISION: &str = "main";
+pub const DEFAULT_CONFIG_FILENAME: &str = "config.json";
+
+/// Configures and loads a [`BertInferenceModel`].
+///
+/// ```no_run
+/// # use models_hf::bert::{BertInferenceModel, PoolingStrategy};
+/// let model = BertInferenceModel::builder()
+///     .model_name("sentence-transformers/all-MiniLM-L6-v2")
+///     .revision("refs/pr/21")
+///     .pooling(PoolingStrategy::MeanMasked)
+///     .embeddings("../data/embeddings.bin", "my_embedding")
+///     .build()?;
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct BertInferenceModelBuilder {
+    model_name: String,
+    revision: String,
+    model_dir: Option<PathBuf>,
+    embeddings_filename: String,
+    /// Keys of the tensors concatenated into the embeddings.
+    embeddings_keys: Vec<String>,
+    device: Device,
+    dtype: DType,
+    pooling: PoolingStrategy,
+    strict_pooling: bool,
+    normalize: bool,
+    add_special_tokens: bool,
+    unit_interval_scores: bool,
+    duplicate_tolerance: Option<f32>,
+    output_dtype: Option<DType>,
+    max_length: usize,
+    prefixes: Prefixes,
+    instructions: Instructions,
+    query_cache_size: usize,
+    metrics: bool,
+    /// `(name, filename, key)` of each named embedding set to load.
+    embedding_sets: Vec<(String, String, String)>,
+    /// Expected SHA-256 digests (hex) of model files, by file name.
+    expected_sha256: HashMap<String, String>,
+    /// Used instead of the `tokenizer.json` of the model.
+    tokenizer: Option<Tokenizer>,
+    /// Left to the tokenizer when unset.
+    truncation_strategy: Option<TruncationStrategy>,
+    num_threads: Option<usize>,
+    /// Name of the config file in the model directory or repo.
+    config_filename: String,
+    /// Used instead of the config file of the model.
+    config: Option<serde_json::Value>,
+}
+
+impl Default for BertInferenceModelBuilder {
+    fn default() -> Self {
+        Self {
+            model_name: DEFAULT_MODEL_NAME.to_string(),
+            revision: DEFAULT_REVISION.to_string(),
+            model_dir: None,
+            embeddings_filename: String::new(),
+            embeddings_keys: Vec::new(),
+            device: Device::Cpu,
+            dtype: DType::F32,
+            pooling: PoolingStrategy::default(),
+            strict_pooling: false,
+            normalize: true,
+            add_special_tokens: true,
+            unit_interval_scores: false,
+            duplicate_tolerance: None,
+            output_dtype: None,
+            max_length: DEFAULT_MAX_LENGTH,
+            prefixes: Prefixes::default(),
+            instructions: Instructions::default(),
+            query_cache_size: 0,
+            metrics: false,
+            embedding_sets: Vec::new(),
+            expected_sha256: HashMap::new(),
+            tokenizer: None,
+            truncation_strategy: None,
+            num_threads: None,
+            config_filename: DEFAULT_CONFIG_FILENAME.to_string(),
+            config: None,
+        }
+    }
+}
+
+impl BertInferenceModelBuilder {
+    /// HF Hub model id, ignored when a [`Self::model_dir`] is set.
+    pub fn model_name(mut self, model_name: impl Into<String>) -> Self {
+        self.model_name = model_name.into();
+        self
+    }
+
+    pub fn revision(mut self, revision: impl Into<String>) -> Self {
+        self.revision = revision.into();
+        self
+    }
+
+    /// Reads the model files from a local directory instead of the HF Hub.
+    pub fn model_dir(mut self, model_dir: impl Into<PathBuf>) -> Self {
+        self.model_dir = Some(model_dir.into());
+        self
+    }
+
+    /// Loads the tensor under `key` in the safetensors file `filename` as the embeddings to
+    /// search. An empty `filename` leaves the model without embeddings.
+    pub fn embeddings(mut self, filename: impl Into<String>, key: impl Into<String>) -> Self {
+        self.embeddings_filename = filename.into();
+        self.embeddings_keys = vec![key.into()];
+        self
+    }
+
+    /// Like [`Self::embeddings`], but concatenates the rows of the tensors under each of `keys`
+    /// in `filename`, in order, e.g. when the embeddings were saved one shard per key. Every
+    /// tensor must have the same hidden size.
+    pub fn embeddings_concat(
+        mut self,
+        filename: impl Into<String>,
+        keys: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.embeddings_filename = filename.into();
+        self.embeddings_keys = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Also loads the tensor under `key` in `filename` as the embedding set `name`; see
+    /// [`BertInferenceModel::add_embedding_set`]. May be called once per set.
+    pub fn embedding_set(
+        mut self,
+        name: impl Into<String>,
+        filename: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Self {
+        self.embedding_sets
+            .push((name.into(), filename.into(), key.into()));
+        self
+    }
+
+    pub fn device(mut self, device: Device) -> Self {
+        self.device = device;
+        self
+    }
+
+    pub fn dtype(mut self, dtype: DType) -> Self {
+        self.dtype = dtype;
+        self
+    }
+
+    pub fn pooling(mut self, pooling: PoolingStrategy) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
+    /// See [`BertInferenceModel::set_strict_pooling`]. Also makes loading fail on a mismatch.
+    pub fn strict_pooling(mut self, strict: bool) -> Self {
+        self.strict_pooling = strict;
+        self
+    }
+
+    /// See [`BertInferenceModel::set_normalize`].
+    pub fn normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Uses `tokenizer` instead of the `tokenizer.json` of the model, e.g. one with custom
+    /// normalization, special tokens or vocabulary, or padding already configured. Its
+    /// truncation is still set from [`Self::max_length`].
+    pub fn tokenizer(mut self, tokenizer: Tokenizer) -> Self {
+        self.tokenizer = Some(tokenizer);
+        self
+    }
+
+    /// See [`BertInferenceModel::set_truncation_strategy`].
+    pub fn truncation_strategy(mut self, strategy: TruncationStrategy) -> Self {
+        self.truncation_strategy = Some(strategy);
+        self
+    }
+
+    /// See [`BertInferenceModel::set_unit_interval_scores`].
+    pub fn unit_interval_scores(mut self, unit_interval_scores: bool) -> Self {
+        self.unit_interval_scores = unit_interval_scores;
+        self
+    }
+
+    /// See [`BertInferenceModel::set_add_special_tokens`].
+    pub fn add_special_tokens(mut self, add_special_tokens: bool) -> Self {
+        self.add_special_tokens = add_special_tokens;
+        self
+    }
+
+    /// See [`BertInferenceModel::set_collapse_duplicates`].
+    pub fn collapse_duplicates(mut self, tolerance: f32) -> Self {
+        self.duplicate_tolerance = Some(tolerance);
+        self
+    }
+
+    /// See [`BertInferenceModel::set_output_dtype`].
+    pub fn output_dtype(mut self, dtype: DType) -> Self {
+        self.output_dtype = Some(dtype);
+        self
+    }
+
+    pub fn prefixes(mut self, prefixes: Prefixes) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
+
+    pub fn instructions(mut self, instructions: Instructions) -> Self {
+        self.instructions = instructions;
+        self
+    }
+
+    /// Fails the build unless the model file `file_name` (e.g. `"model.safetensors"`) has the
+    /// SHA-256 digest `sha256`, given in hex. Together with a commit hash as the revision, this
+    /// guarantees the exact same weights on every deployment.
+    pub fn expected_sha256(
+        mut self,
+        file_name: impl Into<String>,
+        sha256: impl Into<String>,
+    ) -> Self {
+        self.expected_sha256.insert(file_name.into(), sha256.into());
+        self
+    }
+
+    /// See [`BertInferenceModel::set_query_cache_size`].
+    pub fn query_cache_size(mut self, size: usize) -> Self {
+        self.query_cache_size = size;
+        self
+    }
+
+    /// See [`BertInferenceModel::set_num_threads`].
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = Some(num_threads);
+        self
+    }
+
+    /// See [`BertInferenceModel::set_metrics_enabled`].
+    pub fn metrics(mut self, enabled: bool) -> Self {
+        self.metrics = enabled;
+        self
+    }
+
+    pub fn max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Reads the model config from `filename` in the model directory or repo instead of
+    /// `config.json`, e.g. a patched copy with other hyperparameters.
+    pub fn config_filename(mut self, filename: impl Into<String>) -> Self {
+        self.config_filename = filename.into();
+        self
+    }
+
+    /// Uses `config`, the parsed contents of a `config.json`, instead of the config file of the
+    /// model, e.g. to override hyperparameters without writing a file.
+    pub fn config(mut self, config: serde_json::Value) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<BertInferenceModel> {
+        let files = match &self.model_dir {
+            Some(model_dir) => ModelFiles::from_dir(model_dir, &self.config_filename)?,
+            None => ModelFiles::download(&self.model_name, &self.revision, &self.config_filename)?,
+        };
+
+        self.build_from_files(&files)
+    }
+
+    /// Like [`Self::build`], but downloads from the HF Hub without blocking the async runtime.
+    /// Building the model once the files are available stays synchronous.
+    #[cfg(feature = "tokio")]
+    pub async fn build_async(self) -> anyhow::Result<BertInferenceModel> {
+        let files = match &self.model_dir {
+            Some(model_dir) => ModelFiles::from_dir(model_dir, &self.config_filename)?,
+            None => {
+                ModelFiles::download_async(&self.model_name, &self.revision, &self.config_filename)
+                    .await?
+            }
+        };
+
+        self.build_from_files(&files)
+    }
+
+    fn build_from_files(mut self, files: &ModelFiles) -> anyhow::Result<BertInferenceModel> {
+        files.verify_sha256(&self.expected_sha256)?;
+
+        let embeddings = BertInferenceModel::load_embeddings_or_empty(
+            &self.embeddings_filename,
+            self.embeddings_keys.as_slice(),
+            &self.device,
+        )?;
+
+        let mut model = BertInferenceModel::load_from_files(
+            files,
+            self.config.take(),
+            self.tokenizer.take(),
+            embeddings,
+            self.max_length,
+            self.device,
+            self.dtype,
+        )?;
+        if !self.embeddings_filename.is_empty() {
+            model.embeddings_pooling =
+                BertInferenceModel::read_embeddings_pooling(&self.embeddings_filename)?;
+        }
+        model.pooling = self.pooling;
+        model.set_strict_pooling(self.strict_pooling);
+        model.check_pooling()?;
+        model.set_normalize(self.normalize);
+        model.set_add_special_tokens(self.add_special_tokens);
+        if let Some(strategy) = self.truncation_strategy {
+            model.set_truncation_strategy(strategy)?;
+        }
+        model.set_num_threads(self.num_threads)?;
+        model.set_unit_interval_scores(self.unit_interval_scores);
+        model.set_collapse_duplicates(self.duplicate_tolerance);
+        model.set_output_dtype(self.output_dtype);
+        model.set_prefixes(self.prefixes);
+        model.set_instructions(self.instructions);
+        model.set_query_cache_size(self.query_cache_size);
+        model.set_metrics_enabled(self.metrics);
+        for (name, filename, key) in self.embedding_sets {
+            model.load_embedding_set(name, &filename, &key)?;
+        }
+        model.source = Some(ModelSource {
+            model_name: self.model_name,
+            revision: self.revision,
+            model_dir: self.model_dir,
+        });
+
+        Ok(model)
+    }
+}
+
+/// Paths of the files making up a model repo.
+pub(crate) struct ModelFiles {
+    pub(crate) config: PathBuf,
+    pub(crate) tokenizer: PathBuf,
+    /// A single `model.safetensors`, or every shard listed in `model.safetensors.index.json`.
+    pub(crate) weights: Vec<PathBuf>,
+}
+
+/// Index of the shards of a model too large for a single safetensors file.
+const WEIGHTS_INDEX: &str = "model.safetensors.index.json";
+
+impl ModelFiles {
+    /// Paths of the files in `model_dir`, the config being `config_filename`.
+    pub(crate) fn from_dir(model_dir: &Path, config_filename: &str) -> anyhow::Result<Self> {
+        let index = model_dir.join(WEIGHTS_INDEX);
+        let weights = match index.exists() {
+            true => shard_names(&index)?
+                .into_iter()
+                .map(|shard| model_dir.join(shard))
+                .collect(),
+            false => vec![model_dir.join("model.safetensors")],
+        };
+
+        Ok(Self {
+            config: model_dir.join(config_filename),
+            tokenizer: model_dir.join("tokenizer.json"),
+            weights,
+        })
+    }
+
+    /// Fetches the model files from the HF Hub, or from its local cache. With `HF_HUB_OFFLINE`
+    /// set, only the cache is read.
+    pub(crate) fn download(
+        model_name: &str,
+        revision: &str,
+        config_filename: &str,
+    ) -> anyhow::Result<Self> {
+        Self::download_with_cache(
+            model_name,
+            revision,
+            config_filename,
+            Cache::default(),
+            hub_offline(),
+        )
+    }
+
+    /// Like [`Self::download`], with an explicit cache, that is the only source when `offline`.
+    /// Logs whether each file was read from the cache or downloaded.
+    fn download_with_cache(
+        model_name: &str,
+        revision: &str,
+        config_filename: &str,
+        cache: Cache,
+        offline: bool,
+    ) -> anyhow::Result<Self> {
+        let api = match offline {
+            true => None,
+            false => {
+                let repo =
+                    Repo::with_revision(model_name.parse()?, RepoType::Model, revision.parse()?);
+                let api = ApiBuilder::from_cache(cache.clone())
+                    .build()
+                    .map_err(|err| hub_error(model_name, "the model files", &cache, err))?;
+                Some(api.repo(repo))
+            }
+        };
+
+        Self::download_with(model_name, revision, config_filename, &cache, |file| {
+            api.as_ref().map(|api| api.get(file))
+        })
+    }
+
+    /// Like [`Self::download_with_cache`], with `fetch` downloading a file from the HF Hub, or
+    /// returning `None` when offline.
+    fn download_with(
+        model_name: &str,
+        revision: &str,
+        config_filename: &str,
+        cache: &Cache,
+        fetch: impl Fn(&str) -> Option<Result<PathBuf, hf_hub::api::sync::ApiError>>,
+    ) -> anyhow::Result<Self> {
+        let repo = Repo::with_revision(model_name.parse()?, RepoType::Model, revision.parse()?);
+        let cached = cache.repo(repo);
+        let get = |file: &str| -> anyhow::Result<PathBuf> {
+            if let Some(path) = cached.get(file) {
+                info!("{file} of {model_name} read from the HF cache");
+                return Ok(path);
+            }
+            let Some(path) = fetch(file) else {
+                anyhow::bail!(
+                    "{file} of {model_name} at revision {revision} is not in the HF cache in {} \
+                     and HF_HUB_OFFLINE is set: fetch the model once with network access, or \
+                     load a local copy with BertInferenceModel::load_from_path",
+                    cache.path().display()
+                )
+            };
+            let path = path.map_err(|err| hub_error(model_name, file, cache, err))?;
+            info!("{file} of {model_name} downloaded from the HF Hub");
+            Ok(path)
+        };
+
+        // Repos without an index hold their weights in a single file. Offline, an index that
+        // isn't cached is taken to be missing too
+        let index = match cached.get(WEIGHTS_INDEX) {
+            Some(index) => Some(index),
+            None => match fetch(WEIGHTS_INDEX) {
+                None => None,
+                Some(Err(err)) if is_not_found(&err) => None,
+                Some(index) => {
+                    Some(index.map_err(|err| hub_error(model_name, WEIGHTS_INDEX, cache, err))?)
+                }
+            },
+        };
+        let weights = match index {
+            Some(index) => shard_names(&index)?
+                .iter()
+                .map(|shard| get(shard))
+                .collect::<anyhow::Result<_>>()?,
+            None => vec![get("model.safetensors")?],
+        };
+
+        Ok(Self {
+            config: get(config_filename)?,
+            tokenizer: get("tokenizer.json")?,
+            weights,
+        })
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn download_async(
+        model_name: &str,
+        revision: &str,
+        config_filename: &str,
+    ) -> anyhow::Result<Self> {
+        // Reading the cache doesn't block on the network
+        if hub_offline() {
+            return Self::download_with_cache(
+                model_name,
+                revision,
+                config_filename,
+                Cache::default(),
+                true,
+            );
+        }
+        let repo = Repo::with_revision(model_name.parse()?, RepoType::Model, revision.parse()?);
+        // The async API always uses the default cache
+        let cache = Cache::default();
+        let api = hf_hub::api::tokio::Api::new()
+            .map_err(|err| hub_error(model_name, "the model files", &cache, err))?;
+        let api = api.repo(repo);
+
+        let weights = match api.get(WEIGHTS_INDEX).await {
+            Ok(index) => {
+                let mut weights = Vec::new();
+                for shard in shard_names(&index)? {
+                    let shard_path = api
+                        .get(&shard)
+                        .await
+                        .map_err(|err| hub_error(model_name, &shard, &cache, err))?;
+                    weights.push(shard_path);
+                }
+                weights
+            }
+            Err(err) if is_not_found_async(&err) => vec![api
+                .get("model.safetensors")
+                .await
+                .map_err(|err| hub_error(model_name, "model.safetensors", &cache, err))?],
+            Err(err) => return Err(hub_error(model_name, WEIGHTS_INDEX, &cache, err)),
+        };
+
+        Ok(Self {
+            config: api
+                .get(config_filename)
+                .await
+                .map_err(|err| hub_error(model_name, config_filename, &cache, err))?,
+            tokenizer: api
+                .get("tokenizer.json")
+                .await
+                .map_err(|err| hub_error(model_name, "tokenizer.json", &cache, err))?,
+            weights,
+        })
+    }
+}
+
+impl ModelFiles {
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        [&self.config, &self.tokenizer]
+            .into_iter()
+            .chain(&self.weights)
+    }
+
+    /// Checks the files named in `expected` against their expected hex SHA-256 digests.
+    fn verify_sha256(&self, expected: &HashMap<String, String>) -> anyhow::Result<()> {
+        for (file_name, expected) in expected {
+            let path = self
+                .paths()
+                .find(|path| {
+                    path.file_name()
+                        .is_some_and(|name| name == file_name.as_str())
+                })
+                .with_context(|| format!("No model file named {file_name} to verify"))?;
+
+            let actual = sha256_file(path)?;
+            anyhow::ensure!(
+                actual.eq_ignore_ascii_case(expected),
+                "SHA-256 mismatch for {file_name}: expected {expected}, got {actual}"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `HF_HUB_OFFLINE` asks for the HF cache alone to be used, as the Python
+/// `huggingface_hub` does.
+fn hub_offline() -> bool {
+    std::env::var("HF_HUB_OFFLINE").is_ok_and(|value| {
+        ["1", "on", "yes", "true"]
+            .iter()
+            .any(|enabled| value.eq_ignore_ascii_case(enabled))
+    })
+}
+
+/// Explains a failure to fetch `file` of `model_name` from the HF Hub into `cache`, which
+/// otherwise surfaces as a bare HTTP or IO error, with the underlying cause and what to do
+/// about it.
+fn hub_error(
+    model_name: &str,
+    file: &str,
+    cache: &Cache,
+    err: impl std::fmt::Display,
+) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Could not fetch {file} of {model_name} from the HF Hub: {err}. The Hub may be \
+         unreachable or rate limiting requests: check the network, or the files cached in {}, \
+         or load a local copy of the model with BertInferenceModel::load_from_path",
+        cache.path().display()
+    )
+}
+
+/// Whether the HF Hub answered that a file doesn't exist, rather than failing to answer.
+fn is_not_found(err: &hf_hub::api::sync::ApiError) -> bool {
+    use hf_hub::api::sync::ApiError;
+
+    matches!(err, ApiError::RequestError(err) if matches!(**err, ureq::Error::Status(404, _)))
+}
+
+/// Like [`is_not_found`], for the async API.
+#[cfg(feature = "tokio")]
+fn is_not_found_async(err: &hf_hub::api::tokio::ApiError) -> bool {
+    use hf_hub::api::tokio::ApiError;
+    use reqwest::StatusCode;
+
+    matches!(err, ApiError::RequestError(err) if err.status() == Some(StatusCode::NOT_FOUND))
+}
+
+/// Hex SHA-256 digest of the file at `path`.
+fn sha256_file(path: &Path) -> anyhow::Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("Error opening {} to verify it", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Fails with both sizes when the last dimension of `query` does not match the hidden size of
+/// the `[n, hidden]` stored `embeddings`, which would otherwise surface as a shape error from
+/// deep inside the matmul.
+pub(crate) fn check_query_hidden_size(embeddings: &Tensor, query: &Tensor) -> anyhow::Result<()> {
+    // Without an embeddings file the model holds a rank-1 placeholder
+    if embeddings.rank() != 2 {
+        return Ok(());
+    }
+    let expected = embeddings.dim(1)?;
+    let actual = query.dim(D::Minus1)?;
+    anyhow::ensure!(
+        actual == expected,
+        "Query has hidden size {actual} but the stored embeddings have hidden size {expected}, \
+         were they created with another model?"
+    );
+
+    Ok(())
+}
+
+/// Fails when loaded `embeddings`, described by `origin` in the error, are not a contiguous
+/// `[n, hidden]` matrix, e.g. when the wrong key was picked, which would otherwise only surface
+/// as a confusing shape error on the first search.
+fn check_embeddings_layout(embeddings: &Tensor, origin: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        embeddings.rank() == 2,
+        "Embeddings {origin} must be a [n, hidden] matrix, got a rank {} tensor of shape {:?}",
+        embeddings.rank(),
+        embeddings.dims()
+    );
+    anyhow::ensure!(
+        embeddings.is_contiguous(),
+        "Embeddings {origin} must be stored contiguously in row-major order"
+    );
+
+    Ok(())
+}
+
+/// Lower bound of the norms embeddings are divided by when normalized, so all-zero rows stay
+/// zero instead of turning into NaNs.
+fn norm_epsilon(dtype: DType) -> f64 {
+    // 1e-12 rounds to zero in f16, so use its smallest normal value there
+    match dtype {
+        DType::F16 => 6.1e-5,
+        _ => 1e-12,
+    }
+}
+
+/// Prepends `instruction`, when given, to `text` with a space in between.
+fn with_instruction(instruction: Option<&str>, text: String) -> String {
+    match instruction {
+        Some(instruction) => format!("{instruction} {text}"),
+        None => text,
+    }
+}
+
+/// Picks `k` of the unit-norm `points` (`[n, hidden]`) as initial k-means centroids: the first
+/// at random, each next one with probability proportional to its squared cosine distance to
+/// the nearest centroid already picked, which spreads them across the clusters.
+fn kmeans_plus_plus(points: &Tensor, k: usize) -> anyhow::Result<Tensor> {
+    let n_points = points.dim(0)?;
+    let draws = Tensor::rand(0f32, 1., k, &Device::Cpu)?.to_vec1::<f32>()?;
+    let mut picked = vec![((draws[0] * n_points as f32) as usize).min(n_points - 1)];
+    let mut distances = vec![f32::INFINITY; n_points];
+
+    for &draw in &draws[1..] {
+        let last = points.get(picked[picked.len() - 1])?.unsqueeze(1)?;
+        let similarities = points.matmul(&last)?.squeeze(1)?.to_vec1::<f32>()?;
+        for (distance, similarity) in distances.iter_mut().zip(similarities) {
+            *distance = distance.min((1. - similarity).max(0.).powi(2));
+        }
+
+        let mut remaining = draw * distances.iter().sum::<f32>();
+        let next = distances
+            .iter()
+            .enumerate()
+            .filter(|(_, &distance)| distance > 0.)
+            .find_map(|(index, &distance)| {
+                remaining -= distance;
+                (remaining <= 0.).then_some(index)
+            })
+            // Rounding left a little of the draw, or every point sits on a centroid
+            .or_else(|| distances.iter().rposition(|&distance| distance > 0.))
+            .or_else(|| (0..n_points).find(|index| !picked.contains(index)))
+            .context("Not enough points to pick centroids from")?;
+        picked.push(next);
+    }
+
+    let picked: Vec<u32> = picked.into_iter().map(|index| index as u32).collect();
+    Ok(points.index_select(&Tensor::new(picked.as_slice(), points.device())?, 0)?)
+}
+
+/// Cosine similarity of `vector` (`[1, hidden]`) to every row of `embeddings`, in row order.
+pub(crate) fn cosine_scores(embeddings: &Tensor, vector: &Tensor) -> anyhow::Result<Vec<f32>> {
+    // [n, hidden] x [hidden, 1] -> [n, 1]
+    let dot_products = embeddings.matmul(&vector.t()?)?;
+    let vector_norm = vector.sqr()?.sum_all()?.sqrt()?;
+    let norms = embeddings.sqr()?.sum_keepdim(1)?.sqrt()?;
+    let scores = dot_products.broadcast_div(&norms.broadcast_mul(&vector_norm)?)?;
+
+    Ok(scores.squeeze(1)?.to_dtype(DType::F32)?.to_vec1::<f32>()?)
+}
+
+/// Returns the distinct shard file names referenced by a safetensors index, in sorted order.
+fn shard_names(index: &Path) -> anyhow::Result<Vec<String>> {
+    let index: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(index)?)?;
+    let weight_map = index["weight_map"]
+        .as_object()
+        .context("safetensors index has no weight_map")?;
+
+    let mut shards: Vec<String> = weight_map
+        .values()
+        .map(|shard| {
+            shard
+                .as_str()
+                .map(str::to_string)
+                .context("safetensors index maps a tensor to a non-string shard")
+        })
+        .collect::<anyhow::Result<_>>()?;
+    shards.sort();
+    shards.dedup();
+
+    Ok(shards)
+}
+
+/// A `(index, score)` pair ordered by score, so it can live in a [`BinaryHeap`]. NaN scores
+/// rank below every other score, and equal scores rank the lower index higher, so results are
+/// reproducible.
+#[derive(Debug)]
+struct ScoredIndex(usize, f32);
+
+impl PartialEq for ScoredIndex {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ScoredIndex {}
+
+impl PartialOrd for ScoredIndex {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScoredIndex {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let by_score = match (self.1.is_nan(), other.1.is_nan()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => self.1.partial_cmp(&other.1).unwrap_or(Ordering::Equal),
+        };
+
+        by_score.then_with(|| other.0.cmp(&self.0))
+    }
+}
+
+/// Keeps the `top_k` highest scores in a bounded min-heap, so selecting from `n` scores costs
+/// O(n log k) instead of sorting all of them. Returns the highest score first, ties in index order.
+pub(crate) fn select_top_k(
+    scores: impl IntoIterator<Item = (usize, f32)>,
+    top_k: usize,
+) -> Vec<(usize, f32)> {
+    let mut heap = BinaryHeap::with_capacity(top_k + 1);
+    for (index, score) in scores {
+        heap.push(Reverse(ScoredIndex(index, score)));
+        if heap.len() > top_k {
+            heap.pop();
+        }
+    }
+
+    // Sorting ascending by `Reverse` yields descending scores
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(ScoredIndex(index, score))| (index, score))
+        .collect()
+}
+
+/// Turns the scores of `results`, e.g. the top-k of
+/// [`BertInferenceModel::score_vector_similarity`], into a probability distribution summing to 1
+/// with `softmax(score / temperature)`, e.g. to weight retrieved passages. Lower temperatures
+/// concentrate the weight on the best results, higher ones spread it evenly. The order of the
+/// results is kept.
+pub fn softmax_scores(
+    mut results: Vec<SearchResult>,
+    temperature: f32,
+) -> anyhow::Result<Vec<SearchResult>> {
+    anyhow::ensure!(
+        temperature > 0. && temperature.is_finite(),
+        "Softmax temperature must be positive, got {temperature}"
+    );
+    // Shifted by the best score, so the exponentials can't overflow
+    let max = results
+        .iter()
+        .map(|result| result.score)
+        .fold(f32::NEG_INFINITY, f32::max);
+    for result in &mut results {
+        result.score = ((result.score - max) / temperature).exp();
+    }
+    let total: f32 = results.iter().map(|result| result.score).sum();
+    for result in &mut results {
+        result.score /= total;
+    }
+
+    Ok(results)
+}
+
+/// Scales `scores` linearly to [0, 1], the lowest to 0 and the highest to 1. All zero when they
+/// are all equal.
+fn min_max_scale(mut scores: Vec<f32>) -> Vec<f32> {
+    let min = scores.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let range = max - min;
+    for score in &mut scores {
+        *score = match range > 0. {
+            true => (*score - min) / range,
+            false => 0.,
+        };
+    }
+
+    scores
+}
+
+/// Like [`select_top_k`], but skips candidates whose `row` is within `tolerance` of a better
+/// ranked one in every dimension, until `top_k` distinct ones are found. Candidates are ranked
+/// in rounds of doubling size, so only as many rows are read as duplicates get in the way.
+fn select_top_k_distinct(
+    scores: impl IntoIterator<Item = (usize, f32)>,
+    top_k: usize,
+    tolerance: f32,
+    row: impl Fn(usize) -> anyhow::Result<Vec<f32>>,
+) -> anyhow::Result<Vec<(usize, f32)>> {
+    let scores: Vec<_> = scores.into_iter().collect();
+    let is_duplicate =
+        |a: &[f32], b: &[f32]| a.iter().zip(b).all(|(a, b)| (a - b).abs() <= tolerance);
+
+    let mut kept: Vec<((usize, f32), Vec<f32>)> = Vec::with_capacity(top_k);
+    let mut seen = 0;
+    let mut fetch = top_k.saturating_mul(2);
+    while kept.len() < top_k && seen < scores.len() {
+        // Rankings are deterministic, so each round extends the previous one
+        let candidates = select_top_k(scores.iter().copied(), fetch);
+        for &(index, score) in &candidates[seen..] {
+            let vector = row(index)?;
+            if !kept.iter().any(|(_, other)| is_duplicate(&vector, other)) {
+                kept.push(((index, score), vector));
+                if kept.len() == top_k {
+                    break;
+                }
+            }
+        }
+        seen = candidates.len();
+        fetch = fetch.saturating_mul(2);
+    }
+
+    Ok(kept.into_iter().map(|(scored, _)| scored).collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+    use candle_transformers::models::bert::DTYPE;
+    use tokenizers::{PaddingDirection, PaddingStrategy};
+
+    pub(crate) const HIDDEN_SIZE: usize = 32;
+
+    const VOCAB: &str = "[PAD] [UNK] [CLS] [SEP] a an the of for and in on with to is are deep \
+        learning survey neural network networks model models language image segmentation graph \
+        reinforcement driving autonomous theory principles search dense retrieval vector query \
+        document text embedding embeddings transformer attention cat dog sat mat quick brown fox \
+        jumps over lazy";
+
+    /// Builds a BERT-style WordLevel tokenizer over [`VOCAB`] without touching the network.
+    pub(crate) fn tiny_tokenizer() -> Tokenizer {
+        let vocab: serde_json::Map<String, serde_json::Value> = VOCAB
+            .split_whitespace()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id.into()))
+            .collect();
+        let added_tokens: Vec<_> = VOCAB
+            .split_whitespace()
+            .take(4)
+            .enumerate()
+            .map(|(id, token)| {
+                serde_json::json!({
+                    "id": id, "content": token, "single_word": false, "lstrip": false,
+                    "rstrip": false, "normalized": false, "special": true
+                })
+            })
+            .collect();
+        let tokenizer = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": added_tokens,
+            "normalizer": {
+                "type": "BertNormalizer", "clean_text": true, "handle_chinese_chars": true,
+                "strip_accents": null, "lowercase": true
+            },
+            "pre_tokenizer": { "type": "BertPreTokenizer" },
+            "post_processor": { "type": "BertProcessing", "sep": ["[SEP]", 3], "cls": ["[CLS]", 2] },
+            "decoder": null,
+            "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]" }
+        });
+
+        Tokenizer::from_bytes(tokenizer.to_string()).unwrap()
+    }
+
+    /// Config of a randomly initialised two-layer BERT small enough to run in unit tests.
+    pub(crate) fn tiny_config() -> serde_json::Value {
+        serde_json::json!({
+            "vocab_size": VOCAB.split_whitespace().count(),
+            "hidden_size": HIDDEN_SIZE,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 4,
+            "intermediate_size": 64,
+            "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.1,
+            "max_position_embeddings": 512,
+            "type_vocab_size": 2,
+            "initializer_range": 0.02,
+            "layer_norm_eps": 1e-12,
+            "pad_token_id": 0
+        })
+    }
+
+    pub(crate) fn tiny_model() -> BertInferenceModel {
+        let device = Device::Cpu;
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DTYPE, &device);
+        let config: Config = serde_json::from_value(tiny_config()).unwrap();
+        let model = BertModel::load(vb, &config).unwrap();
+
+        let embeddings = Tensor::new(&[0.0], &device).unwrap();
+
+        BertInferenceModel::from_parts(
+            Encoder::Bert(model),
+            HIDDEN_SIZE,
+            tiny_tokenizer(),
+            device,
+            DTYPE,
+            embeddings,
+            DEFAULT_MAX_LENGTH,
+        )
+        .unwrap()
+    }
+
+    /// Random unit vectors, shaped like the output of `create_embeddings`.
+    pub(crate) fn random_embeddings(n: usize) -> Tensor {
+        let embeddings = Tensor::randn(0f32, 1., (n, HIDDEN_SIZE), &Device::Cpu).unwrap();
+        BertInferenceModel::l2_normalize(&embeddings).unwrap()
+    }
+
+    /// `rows` padded with zeros to [`HIDDEN_SIZE`], for hand-picked vectors whose scores are
+    /// easy to work out.
+    pub(crate) fn padded_embeddings(rows: &[&[f32]]) -> Tensor {
+        let values: Vec<f32> = rows
+            .iter()
+            .flat_map(|row| {
+                let mut row = row.to_vec();
+                row.resize(HIDDEN_SIZE, 0.);
+                row
+            })
+            .collect();
+        Tensor::from_vec(values, (rows.len(), HIDDEN_SIZE), &Device::Cpu).unwrap()
+    }
+
+    /// Fraction of the `expected` indices also found in `actual`.
+    pub(crate) fn recall(actual: &[(usize, f32)], expected: &[SearchResult]) -> f32 {
+        let hits = expected
+            .iter()
+            .filter(|expected| actual.iter().any(|(index, _)| *index == expected.index))
+            .count();
+        hits as f32 / expected.len() as f32
+    }
+
+    /// Returns a fresh scratch directory under the system temp dir.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join("models_hf_tests")
+            .join(format!("{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a complete tiny model (`config.json`, `tokenizer.json`, `model.safetensors`) to a
+    /// local directory, mirroring the layout of a HF Hub model repo.
+    pub(crate) fn tiny_model_dir(name: &str) -> PathBuf {
+        model_dir_with_config(name, tiny_config())
+    }
+
+    /// Like [`tiny_model_dir`], with randomly initialised weights for `config`.
+    pub(crate) fn model_dir_with_config(name: &str, config: serde_json::Value) -> PathBuf {
+        let dir = temp_dir(name);
+        let device = Device::Cpu;
+
+        std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+        tiny_tokenizer()
+            .save(dir.join("tokenizer.json"), false)
+            .unwrap();
+
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DTYPE, &device);
+        BertModel::load(vb, &serde_json::from_value(config).unwrap()).unwrap();
+        varmap.save(dir.join("model.safetensors")).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn create_embeddings_pads_mixed_length_batch() {
+        let model = tiny_model();
+        let sentences = vec![
+            "deep learning".to_string(),
+            "a survey of dense retrieval models for text search".to_string(),
+            "the quick brown fox jumps over the lazy dog and the cat sat on the mat".to_string(),
+        ];
+
+        let (token_ids, attention_mask) = model.encode_batch_padded(sentences.clone()).unwrap();
+        assert_eq!(token_ids.dims(), &[3, 18]);
+        assert_eq!(
+            attention_mask.sum(1).unwrap().to_vec1::<u32>().unwrap(),
+            vec![4, 11, 18]
+        );
+
+        let embeddings = model.create_embeddings(sentences).unwrap();
+        assert_eq!(embeddings.dims(), &[3, HIDDEN_SIZE]);
+    }
+
+    #[test]
+    fn embeddings_convert_to_plain_vectors() {
+        let mut model = tiny_model();
+        model.set_output_dtype(Some(DType::F16));
+        let sentences = vec![
+            "deep learning".to_string(),
+            "graph search".to_string(),
+            "the cat sat on the mat".to_string(),
+        ];
+
+        let embeddings = model.create_embeddings_vec(sentences.clone()).unwrap();
+
+        assert_eq!(embeddings.len(), sentences.len());
+        assert!(embeddings
+            .iter()
+            .all(|embedding| embedding.len() == HIDDEN_SIZE));
+        let tensor = model.create_embeddings(sentences).unwrap();
+        assert_eq!(
+            embeddings,
+            tensor
+                .to_dtype(DType::F32)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn masked_mean_pooling_ignores_padding() {
+        let device = Device::Cpu;
+        // Second sentence has one real token followed by one padding position
+        let embeddings =
+            Tensor::new(&[[[1f32, 2.], [3., 4.]], [[5., 6.], [100., 100.]]], &device).unwrap();
+        let attention_mask = Tensor::new(&[[1u32, 1], [1, 0]], &device).unwrap();
+
+        let masked =
+            BertInferenceModel::apply_mean_pooling_masked(&embeddings, &attention_mask).unwrap();
+        let unmasked = BertInferenceModel::apply_mean_pooling(&embeddings).unwrap();
+
+        assert_eq!(masked.to_vec2::<f32>().unwrap(), vec![[2., 3.], [5., 6.]]);
+        assert_eq!(
+            unmasked.to_vec2::<f32>().unwrap(),
+            vec![[2., 3.], [52.5, 53.]]
+        );
+    }
+
+    #[test]
+    fn weighted_mean_pooling_skips_zero_weight_tokens() {
+        let embeddings = Tensor::randn(0f32, 1., (2, 5, HIDDEN_SIZE), &Device::Cpu).unwrap();
+        let weights = Tensor::new(
+            &[[1f32, 1., 0., 1., 1.], [2., 2., 2., 2., 2.]],
+            &Device::Cpu,
+        )
+        .unwrap();
+
+        let pooled =
+            BertInferenceModel::apply_weighted_mean_pooling(&embeddings, &weights).unwrap();
+
+        // The weighted-out token 2 of the first sentence doesn't count
+        let kept = Tensor::new(&[0u32, 1, 3, 4], &Device::Cpu).unwrap();
+        let first = embeddings.narrow(0, 0, 1).unwrap();
+        let expected_first =
+            BertInferenceModel::apply_mean_pooling(&first.index_select(&kept, 1).unwrap()).unwrap();
+        // Uniform weights give the plain mean
+        let second = embeddings.narrow(0, 1, 1).unwrap();
+        let expected_second = BertInferenceModel::apply_mean_pooling(&second).unwrap();
+        let expected = Tensor::cat(&[expected_first, expected_second], 0).unwrap();
+        let max_diff = (pooled - expected)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(max_diff < 1e-5, "{max_diff}");
+    }
+
+    #[test]
+    fn masked_max_pooling_ignores_padding() {
+        let device = Device::Cpu;
+        // The padding position of the first sentence holds the largest activations
+        let embeddings = Tensor::new(
+            &[
+                [[1f32, -2.], [3., -1.], [9., 9.]],
+                [[0., 4.], [2., 1.], [-1., 5.]],
+            ],
+            &device,
+        )
+        .unwrap();
+        let attention_mask = Tensor::new(&[[1u32, 1, 0], [1, 1, 1]], &device).unwrap();
+
+        let unmasked = BertInferenceModel::apply_max_pooling(&embeddings).unwrap();
+        let masked =
+            BertInferenceModel::apply_max_pooling_masked(&embeddings, &attention_mask).unwrap();
+
+        assert_eq!(unmasked.to_vec2::<f32>().unwrap(), [[9., 9.], [2., 5.]]);
+        assert_eq!(masked.to_vec2::<f32>().unwrap(), [[3., -1.], [2., 5.]]);
+    }
+
+    #[test]
+    fn single_token_sequences_pool_to_the_token() {
+        let mut model = tiny_model();
+        let strategies = [
+            PoolingStrategy::Max,
+            PoolingStrategy::MaxMasked,
+            PoolingStrategy::Mean,
+            PoolingStrategy::MeanMasked,
+            PoolingStrategy::Cls,
+        ];
+        for (n_sentences, n_tokens) in [(1, 1), (3, 1), (1, 5)] {
+            let embeddings =
+                Tensor::randn(0f32, 1., (n_sentences, n_tokens, HIDDEN_SIZE), &Device::Cpu)
+                    .unwrap();
+            let attention_mask =
+                Tensor::ones((n_sentences, n_tokens), DType::U32, &Device::Cpu).unwrap();
+            for strategy in strategies {
+                model.set_pooling(strategy);
+                let pooled = model.pool(&embeddings, &attention_mask).unwrap();
+                assert_eq!(pooled.dims(), &[n_sentences, HIDDEN_SIZE], "{strategy:?}");
+                if n_tokens == 1 {
+                    assert_eq!(
+                        pooled.to_vec2::<f32>().unwrap(),
+                        embeddings.squeeze(1).unwrap().to_vec2::<f32>().unwrap(),
+                        "{strategy:?}"
+                    );
+                }
+            }
+        }
+
+        let empty = Tensor::zeros((1, 0, HIDDEN_SIZE), DType::F32, &Device::Cpu).unwrap();
+        assert!(BertInferenceModel::apply_max_pooling(&empty).is_err());
+    }
+
+    #[test]
+    fn one_word_without_special_tokens_embeds_to_one_vector() {
+        let mut model = tiny_model();
+        model.set_add_special_tokens(false);
+        model.set_pooling(PoolingStrategy::Max);
+
+        let single = model.infer_sentence_embedding("cat").unwrap();
+        assert_eq!(single.dims(), &[1, HIDDEN_SIZE]);
+        let batch = model.create_embeddings(vec!["cat".to_string()]).unwrap();
+        assert_eq!(batch.dims(), &[1, HIDDEN_SIZE]);
+        let diff = (single - batch)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-5, "{diff}");
+        let mixed = model
+            .create_embeddings(vec!["cat".to_string(), "the lazy dog".to_string()])
+            .unwrap();
+        assert_eq!(mixed.dims(), &[2, HIDDEN_SIZE]);
+    }
+
+    #[test]
+    fn cls_pooling_drops_token_dimension() {
+        let embeddings = Tensor::randn(0f32, 1., (3, 7, HIDDEN_SIZE), &Device::Cpu).unwrap();
+
+        let pooled = BertInferenceModel::apply_cls_pooling(&embeddings).unwrap();
+
+        assert_eq!(pooled.dims(), &[3, HIDDEN_SIZE]);
+        assert_eq!(
+            pooled.get(1).unwrap().to_vec1::<f32>().unwrap(),
+            embeddings
+                .get(1)
+                .unwrap()
+                .get(0)
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn every_pooling_strategy_yields_sentence_vectors() {
+        let mut model = tiny_model();
+        assert_eq!(model.pooling(), PoolingStrategy::Max);
+
+        for pooling in [
+            PoolingStrategy::Max,
+            PoolingStrategy::MaxMasked,
+            PoolingStrategy::Mean,
+            PoolingStrategy::MeanMasked,
+            PoolingStrategy::Cls,
+        ] {
+            model.set_pooling(pooling);
+
+            let sentence = model.infer_sentence_embedding("deep learning").unwrap();
+            assert_eq!(sentence.dims(), &[1, HIDDEN_SIZE], "{pooling:?}");
+
+            let batch = model
+                .create_embeddings(vec![
+                    "graph".to_string(),
+                    "a dense vector search".to_string(),
+                ])
+                .unwrap();
+            assert_eq!(batch.dims(), &[2, HIDDEN_SIZE], "{pooling:?}");
+        }
+    }
+
+    #[test]
+    fn long_input_is_truncated_to_max_length() {
+        let mut model = tiny_model();
+        assert_eq!(model.max_length(), DEFAULT_MAX_LENGTH);
+
+        let paragraph = ["dense", "retrieval", "survey", "of", "text"]
+            .repeat(100)
+            .join(" ");
+
+        let (token_ids, _) = model.encode_batch_padded(vec![paragraph.clone()]).unwrap();
+        assert_eq!(token_ids.dim(1).unwrap(), DEFAULT_MAX_LENGTH);
+
+        model.set_max_length(64).unwrap();
+        let (token_ids, _) = model.encode_batch_padded(vec![paragraph.clone()]).unwrap();
+        assert_eq!(token_ids.dim(1).unwrap(), 64);
+
+        let embedding = model.infer_sentence_embedding(&paragraph).unwrap();
+        assert_eq!(embedding.dims(), &[1, HIDDEN_SIZE]);
+    }
+
+    #[test]
+    fn auto_device_falls_back_to_cpu() {
+        let device = BertInferenceModel::auto_device();
+
+        if !candle::utils::cuda_is_available() && !candle::utils::metal_is_available() {
+            assert!(device.is_cpu());
+        }
+        // Whatever was picked must be usable
+        Tensor::zeros((2, 2), DTYPE, &device).unwrap();
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn limiting_threads_keeps_the_output() {
+        let mut model = tiny_model();
+        let sentences = vec![
+            "deep learning survey".to_string(),
+            "the quick brown fox jumps over the lazy dog".to_string(),
+        ];
+        let expected = model.create_embeddings(sentences.clone()).unwrap();
+
+        model.set_num_threads(Some(1)).unwrap();
+        assert_eq!(model.num_threads(), 1);
+        let limited = model.create_embeddings(sentences).unwrap();
+        let diff = (limited - expected)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-5, "{diff}");
+
+        assert!(model.set_num_threads(Some(0)).is_err());
+        model.set_num_threads(None).unwrap();
+        assert_eq!(model.num_threads(), candle::utils::get_num_threads());
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    #[test]
+    fn limiting_threads_needs_rayon() {
+        let mut model = tiny_model();
+        let err = model.set_num_threads(Some(1)).unwrap_err();
+        assert!(err.to_string().contains("rayon feature"), "{err}");
+        model.set_num_threads(None).unwrap();
+    }
+
+    #[test]
+    fn load_from_path_reads_local_files() {
+        let model_dir = tiny_model_dir("load_from_path");
+
+        let model = BertInferenceModel::load_from_path(
+            &model_dir,
+            "",
+            "",
+            DEFAULT_MAX_LENGTH,
+            Device::Cpu,
+            DTYPE,
+        )
+        .unwrap();
+
+        let embedding = model.infer_sentence_embedding("deep learning").unwrap();
+        assert_eq!(embedding.dims(), &[1, HIDDEN_SIZE]);
+    }
+
+    #[test]
+    fn missing_embeddings_key_lists_available_keys() {
+        let dir = temp_dir("missing_embeddings_key");
+        let filename = dir.join("embeddings.bin");
+        let filename = filename.to_str().unwrap();
+        let embeddings = Tensor::zeros((2, HIDDEN_SIZE), DTYPE, &Device::Cpu).unwrap();
+        embeddings
+            .save_safetensors("my_embedding", filename)
+            .unwrap();
+
+        let loaded =
+            BertInferenceModel::load_embeddings(filename, "my_embedding", &Device::Cpu).unwrap();
+        assert_eq!(loaded.dims(), &[2, HIDDEN_SIZE]);
+
+        let err = BertInferenceModel::load_embeddings(filename, "my_embeding", &Device::Cpu)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("\"my_embeding\""), "{err}");
+        assert!(err.contains("[\"my_embedding\"]"), "{err}");
+    }
+
+    #[test]
+    fn config_can_be_overridden() {
+        let model_dir = tiny_model_dir("config_override");
+        let mut patched = tiny_config();
+        patched["hidden_act"] = "relu".into();
+        std::fs::write(model_dir.join("patched.json"), patched.to_string()).unwrap();
+        let embed = |builder: BertInferenceModelBuilder| {
+            builder
+                .model_dir(&model_dir)
+                .build()
+                .unwrap()
+                .infer_sentence_embedding("deep learning survey")
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+        };
+
+        let original = embed(BertInferenceModel::builder());
+        let from_file = embed(BertInferenceModel::builder().config_filename("patched.json"));
+        let in_memory = embed(BertInferenceModel::builder().config(patched));
+        assert_ne!(from_file, original);
+        assert_eq!(from_file, in_memory);
+
+        let err = BertInferenceModel::builder()
+            .model_dir(&model_dir)
+            .config_filename("missing.json")
+            .build()
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains("missing.json"), "{err:#}");
+    }
+
+    #[test]
+    fn embeddings_of_the_wrong_rank_fail_to_load() {
+        let model_dir = tiny_model_dir("wrong_rank_embeddings");
+        let filename = model_dir.join("embeddings.bin");
+        let filename = filename.to_str().unwrap();
+        let vector = Tensor::zeros(HIDDEN_SIZE, DTYPE, &Device::Cpu).unwrap();
+        vector.save_safetensors("my_embedding", filename).unwrap();
+
+        let err = BertInferenceModel::builder()
+            .model_dir(&model_dir)
+            .embeddings(filename, "my_embedding")
+            .build()
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("[n, hidden]"), "{err}");
+        assert!(err.contains("rank 1"), "{err}");
+        assert!(err.contains("\"my_embedding\""), "{err}");
+        let err = check_embeddings_layout(&random_embeddings(4).t().unwrap(), "in a test")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("contiguously"), "{err}");
+    }
+
+    #[test]
+    fn embeddings_under_several_keys_are_concatenated() {
+        let model_dir = tiny_model_dir("embeddings_concat");
+        let filename = model_dir.join("embeddings.bin");
+        let first = random_embeddings(3);
+        let second = random_embeddings(5);
+        let narrow = Tensor::zeros((2, HIDDEN_SIZE / 2), DType::F32, &Device::Cpu).unwrap();
+        safetensors::save(
+            &HashMap::from([
+                ("shard-0".to_string(), first.clone()),
+                ("shard-1".to_string(), second.clone()),
+                ("narrow".to_string(), narrow),
+            ]),
+            &filename,
+        )
+        .unwrap();
+        let filename = filename.to_str().unwrap();
+
+        let model = BertInferenceModel::builder()
+            .model_dir(&model_dir)
+            .embeddings_concat(filename, ["shard-0", "shard-1"])
+            .build()
+            .unwrap();
+        assert_eq!(model.embeddings().dims(), &[3 + 5, HIDDEN_SIZE]);
+        let expected = Tensor::cat(&[&first, &second], 0).unwrap();
+        assert_eq!(
+            model.embeddings().to_vec2::<f32>().unwrap(),
+            expected.to_vec2::<f32>().unwrap()
+        );
+
+        let err = BertInferenceModel::load_embeddings_concat(
+            filename,
+            &["shard-0", "narrow"],
+            &Device::Cpu,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("hidden size"), "{err}");
+        let err = BertInferenceModel::load_embeddings_concat(
+            filename,
+            &["shard-0", "shard-2"],
+            &Device::Cpu,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("\"shard-2\" not found"), "{err}");
+    }
+
+    #[test]
+    fn saved_embeddings_round_trip_through_load() {
+        let model_dir = tiny_model_dir("saved_embeddings_round_trip");
+        let filename = model_dir.join("embeddings.bin");
+        let filename = filename.to_str().unwrap();
+        let model = tiny_model();
+        let embeddings = model
+            .create_embeddings(vec![
+                "deep learning".to_string(),
+                "graph search".to_string(),
+            ])
+            .unwrap();
+
+        model
+            .save_embeddings(&embeddings, filename, "my_embedding")
+            .unwrap();
+        let reloaded = BertInferenceModel::load_from_path(
+            &model_dir,
+            filename,
+            "my_embedding",
+            DEFAULT_MAX_LENGTH,
+            Device::Cpu,
+            DTYPE,
+        )
+        .unwrap();
+
+        assert_eq!(
+            reloaded.embeddings().to_vec2::<f32>().unwrap(),
+            embeddings.to_vec2::<f32>().unwrap()
+        );
+    }
+
+    #[test]
+    fn index_round_trips_with_ids_and_config() {
+        let model_dir = tiny_model_dir("index_round_trip");
+        let index_dir = model_dir.join("index");
+        let mut model = BertInferenceModel::builder()
+            .model_dir(&model_dir)
+            .dtype(DTYPE)
+            .pooling(PoolingStrategy::MeanMasked)
+            .normalize(false)
+            .max_length(32)
+            .build()
+            .unwrap();
+        let sentences: Vec<String> = ["deep learning survey", "graph neural network"]
+            .iter()
+            .map(|sentence| sentence.to_string())
+            .collect();
+        let embeddings = model.create_embeddings(sentences).unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        model
+            .set_doc_ids(vec!["survey".to_string(), "graph".to_string()])
+            .unwrap();
+
+        model.save_index(&index_dir).unwrap();
+        let reloaded = BertInferenceModel::load_index(&index_dir, Device::Cpu).unwrap();
+
+        assert_eq!(reloaded.doc_ids(), model.doc_ids());
+        assert_eq!(reloaded.pooling(), PoolingStrategy::MeanMasked);
+        assert_eq!(
+            reloaded.embeddings_pooling(),
+            Some(PoolingStrategy::MeanMasked)
+        );
+        assert!(!reloaded.normalize());
+        assert_eq!(reloaded.max_length(), 32);
+        assert_eq!(reloaded.source(), model.source());
+        assert_eq!(
+            reloaded.source().unwrap().model_dir.as_deref(),
+            Some(model_dir.as_path())
+        );
+        assert_eq!(
+            reloaded.embeddings().to_vec2::<f32>().unwrap(),
+            model.embeddings().to_vec2::<f32>().unwrap()
+        );
+        let query = reloaded.infer_sentence_embedding("graph network").unwrap();
+        assert_eq!(
+            reloaded.search(query.clone(), 2).unwrap(),
+            model.search(query, 2).unwrap()
+        );
+    }
+
+    #[test]
+    fn added_embeddings_are_searchable() {
+        let mut model = tiny_model();
+        model.add_embeddings(random_embeddings(10)).unwrap();
+        let new = random_embeddings(5);
+
+        model.add_embeddings(new.clone()).unwrap();
+
+        assert_eq!(model.embeddings().dim(0).unwrap(), 15);
+        let query = new.get(3).unwrap().unsqueeze(0).unwrap();
+        let results = model.score_vector_similarity(query, 1).unwrap();
+        assert_eq!(results[0].index, 13);
+
+        let narrow = Tensor::zeros((1, 8), DTYPE, &Device::Cpu).unwrap();
+        let err = model.add_embeddings(narrow.clone()).unwrap_err();
+        assert!(err.to_string().contains("hidden size 8"), "{err}");
+        // Also when nothing is stored yet
+        assert!(tiny_model().add_embeddings(narrow).is_err());
+    }
+
+    #[test]
+    fn cosine_and_dot_rank_unnormalized_vectors_differently() {
+        let mut model = tiny_model();
+        // Row 0 points the same way as the query, row 1 is off-axis but much longer
+        let embeddings = padded_embeddings(&[&[1., 0.], &[3., 3.]]);
+        model.add_embeddings(embeddings).unwrap();
+        let query = padded_embeddings(&[&[1., 0.]]);
+
+        let cosine = model
+            .score_vector_similarity_with(query.clone(), 2, SimilarityMetric::Cosine)
+            .unwrap();
+        let dot = model
+            .score_vector_similarity_with(query, 2, SimilarityMetric::Dot)
+            .unwrap();
+
+        assert_eq!((cosine[0].index, cosine[0].score), (0, 1.));
+        assert_eq!((dot[0].index, dot[0].score), (1, 3.));
+    }
+
+    #[test]
+    fn l2_ranks_nearest_first_unlike_cosine() {
+        let mut model = tiny_model();
+        // Row 0 has the query's direction but is far away, row 1 is close but off-axis
+        let embeddings = padded_embeddings(&[&[10., 0.], &[0.5, 0.5]]);
+        model.add_embeddings(embeddings).unwrap();
+        let query = padded_embeddings(&[&[1., 0.]]);
+
+        let l2 = model.score_vector_l2(query.clone(), 2).unwrap();
+        let cosine = model.score_vector_similarity(query, 2).unwrap();
+
+        assert_eq!(l2, vec![(1, 0.5), (0, 81.)]);
+        assert_eq!(cosine[0].index, 0);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_embedding_matches_serial() {
+        let model = tiny_model();
+        let sentences: Vec<String> = (0..100)
+            .map(|i| format!("the cat sat on the mat {}", "dog ".repeat(i % 5)))
+            .collect();
+
+        let serial = model
+            .create_embeddings_chunked(sentences.clone(), 8)
+            .unwrap();
+        let parallel = model.create_embeddings_parallel(sentences, 8).unwrap();
+
+        assert_eq!(parallel.dims(), [100, HIDDEN_SIZE]);
+        assert_eq!(
+            parallel.to_vec2::<f32>().unwrap(),
+            serial.to_vec2::<f32>().unwrap()
+        );
+        assert!(model.create_embeddings_parallel(vec![], 8).is_err());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_scoring_matches_serial() {
+        let mut model = tiny_model();
+        model.add_embeddings(random_embeddings(50_000)).unwrap();
+        let query = random_embeddings(1);
+        let score_row = |cur_vec: &Tensor| Ok((cur_vec * &query)?.sum_all()?.to_scalar::<f32>()?);
+
+        let serial = model.score_rows_serial(score_row).unwrap();
+        let parallel = model.score_rows_parallel(score_row).unwrap();
+
+        assert_eq!(serial, parallel);
+        let top_k = model.score_vector_similarity(query, 10).unwrap();
+        let mut expected = serial;
+        expected.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let top_k: Vec<_> = top_k.iter().map(|r| r.index).collect();
+        let expected: Vec<_> = expected[..10].iter().map(|r| r.0).collect();
+        assert_eq!(top_k, expected);
+    }
+
+    #[test]
+    fn excluded_embeddings_are_skipped() {
+        let mut model = tiny_model();
+        model.add_embeddings(random_embeddings(20)).unwrap();
+        let query = random_embeddings(1);
+        let ranking = model.score_vector_similarity(query.clone(), 3).unwrap();
+
+        let exclude = HashSet::from([ranking[0].index]);
+        let results = model
+            .score_vector_similarity_excluding(query.clone(), 3, &exclude)
+            .unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], ranking[1]);
+        assert_eq!(results[1], ranking[2]);
+        assert!(results
+            .iter()
+            .all(|result| result.index != ranking[0].index));
+        let everything: HashSet<usize> = (0..20).collect();
+        assert!(model
+            .score_vector_similarity_excluding(query, 3, &everything)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn scoring_against_the_stored_embeddings_matches_the_index() {
+        let mut model = tiny_model();
+        let embeddings = random_embeddings(50);
+        model.add_embeddings(embeddings.clone()).unwrap();
+        let query = random_embeddings(1);
+
+        let expected: Vec<(usize, f32)> = model
+            .score_vector_similarity(query.clone(), 5)
+            .unwrap()
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        assert_eq!(
+            model.score_against(&query, &embeddings, 5).unwrap(),
+            expected
+        );
+        let shortlist = embeddings.narrow(0, 10, 5).unwrap();
+        let rescored = model.score_against(&query, &shortlist, 10).unwrap();
+        assert_eq!(rescored.len(), 5);
+        assert!(rescored.iter().all(|(index, _)| *index < 5));
+        assert!(model
+            .score_against(&query, &shortlist.flatten_all().unwrap(), 1)
+            .is_err());
+    }
+
+    #[test]
+    fn softmax_scores_sum_to_one() {
+        let mut model = tiny_model();
+        model.add_embeddings(random_embeddings(50)).unwrap();
+        let results = model
+            .score_vector_similarity(random_embeddings(1), 5)
+            .unwrap();
+
+        let sharp = softmax_scores(results.clone(), 0.05).unwrap();
+        let flat = softmax_scores(results.clone(), 10.).unwrap();
+        for softmaxed in [&sharp, &flat] {
+            let total: f32 = softmaxed.iter().map(|result| result.score).sum();
+            assert!((total - 1.).abs() < 1e-5, "{total}");
+            assert!(softmaxed
+                .windows(2)
+                .all(|pair| pair[0].score >= pair[1].score));
+        }
+        assert_eq!(sharp[0].index, results[0].index);
+        // A low temperature favours the best result, a high one tends to uniform weights
+        assert!(sharp[0].score > flat[0].score);
+        assert!(flat.iter().all(|result| (result.score - 0.2).abs() < 0.01));
+        let expected = 1. / (1. + ((results[1].score - results[0].score) / 0.05).exp());
+        let pair = softmax_scores(results[..2].to_vec(), 0.05).unwrap();
+        assert!((pair[0].score - expected).abs() < 1e-5);
+        assert!(softmax_scores(results, 0.).is_err());
+        assert!(softmax_scores(Vec::new(), 1.).unwrap().is_empty());
+    }
+
+    #[test]
+    fn hybrid_search_lifts_keyword_matches() {
+        let mut model = tiny_model();
+        let query = "transformer attention";
+        // The only document with the keywords is the one dense retrieval likes least
+        let query_embedding = model.infer_sentence_embedding(query).unwrap();
+        let embeddings = Tensor::cat(
+            &[
+                &random_embeddings(4),
+                &query_embedding.neg().unwrap(),
+                &random_embeddings(3),
+            ],
+            0,
+        )
+        .unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        let texts: Vec<String> = [
+            "deep learning survey",
+            "graph neural network",
+            "autonomous driving",
+            "image segmentation",
+            "the transformer attention model",
+            "dense retrieval",
+            "vector search",
+            "reinforcement learning theory",
+        ]
+        .iter()
+        .map(|text| text.to_string())
+        .collect();
+        assert!(model.search_hybrid(query, 3, 0.5).is_err());
+        model.set_doc_texts(&texts).unwrap();
+
+        let dense = model.search_hybrid(query, 8, 1.).unwrap();
+        assert_eq!(dense.last().unwrap().index, 4);
+        let expected: Vec<usize> = model
+            .search_text(query, 8)
+            .unwrap()
+            .iter()
+            .map(|result| result.index)
+            .collect();
+        let dense: Vec<usize> = dense.iter().map(|result| result.index).collect();
+        assert_eq!(dense, expected);
+
+        let hybrid = model.search_hybrid(query, 3, 0.3).unwrap();
+        assert_eq!(hybrid[0].index, 4);
+        assert!((hybrid[0].score - 0.7).abs() < 1e-6);
+        assert!(model.search_hybrid(query, 3, 1.5).is_err());
+        model.add_embeddings(random_embeddings(1)).unwrap();
+        assert!(model.search_hybrid(query, 3, 0.5).is_err());
+    }
+
+    #[test]
+    fn embedding_stats_describe_the_index() {
+        let mut model = tiny_model();
+        assert_eq!(model.embedding_stats().unwrap().count, 0);
+        model.add_embeddings(random_embeddings(20)).unwrap();
+
+        let stats = model.embedding_stats().unwrap();
+        assert_eq!(stats.count, 20);
+        assert_eq!(stats.hidden_size, HIDDEN_SIZE);
+        for norm in [stats.mean_norm, stats.min_norm, stats.max_norm] {
+            assert!((norm - 1.).abs() < 1e-5, "{stats:?}");
+        }
+        assert_eq!(stats.near_zero_fraction, 0.);
+
+        let zeros = Tensor::zeros((5, HIDDEN_SIZE), DType::F32, &Device::Cpu).unwrap();
+        model.add_embeddings(zeros).unwrap();
+        let stats = model.embedding_stats().unwrap();
+        assert_eq!(stats.count, 25);
+        assert_eq!(stats.min_norm, 0.);
+        assert!((stats.mean_norm - 0.8).abs() < 1e-5, "{stats:?}");
+        assert_eq!(stats.near_zero_fraction, 0.2);
+    }
+
+    #[test]
+    fn custom_scoring_reproduces_cosine() {
+        let mut model = tiny_model();
+        // Not normalized, so a plain dot product would rank differently
+        let scales = Tensor::rand(0.5f32, 2., (30, 1), &Device::Cpu).unwrap();
+        let embeddings = random_embeddings(30).broadcast_mul(&scales).unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        let query = random_embeddings(1);
+        let cosine = |a: &Tensor, b: &Tensor| -> anyhow::Result<f32> {
+            let dot = (a * b)?.sum_all()?.to_scalar::<f32>()?;
+            let norms = a.sqr()?.sum_all()?.sqrt()? * b.sqr()?.sum_all()?.sqrt()?;
+            Ok(dot / norms?.to_scalar::<f32>()?)
+        };
+
+        let expected = model.score_vector_similarity(query.clone(), 5).unwrap();
+        let custom = model.score_with(query.clone(), 5, cosine).unwrap();
+        assert_eq!(custom.len(), 5);
+        for (custom, expected) in custom.iter().zip(&expected) {
+            assert_eq!(custom.index, expected.index);
+            assert!((custom.score - expected.score).abs() < 1e-5);
+        }
+        assert!(model
+            .score_with(query, 5, |_, _| anyhow::bail!("no score"))
+            .is_err());
+    }
+
+    #[test]
+    fn unit_interval_scores_keep_the_ranking() {
+        let mut model = tiny_model();
+        // Includes opposite vectors, for scores down to -1
+        let embeddings = random_embeddings(50);
+        let embeddings = Tensor::cat(&[&embeddings, &embeddings.neg().unwrap()], 0).unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        let query = random_embeddings(1);
+        let raw = model.score_vector_similarity(query.clone(), 100).unwrap();
+
+        model.set_unit_interval_scores(true);
+        let rescaled = model.score_vector_similarity(query, 100).unwrap();
+
+        assert_eq!(rescaled.len(), 100);
+        for (raw, rescaled) in raw.iter().zip(&rescaled) {
+            assert_eq!(raw.index, rescaled.index);
+            assert!((0. ..=1.).contains(&rescaled.score), "{}", rescaled.score);
+            assert!((rescaled.score - (raw.score + 1.) / 2.).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn collapsed_duplicates_leave_room_for_distinct_results() {
+        let mut model = tiny_model();
+        // Every vector stored three times, plus a near-copy of the first one at row 30
+        let distinct = random_embeddings(10);
+        let near_copy = (distinct.get(0).unwrap().unsqueeze(0).unwrap() + 1e-4).unwrap();
+        let embeddings = Tensor::cat(&[&distinct, &distinct, &distinct, &near_copy], 0).unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        let query = distinct.get(0).unwrap().unsqueeze(0).unwrap();
+        // Copies share their row modulo 10
+        let distinct_vectors = |results: &[SearchResult]| {
+            results
+                .iter()
+                .map(|result| result.index % 10)
+                .collect::<HashSet<_>>()
+                .len()
+        };
+
+        let with_copies = model.score_vector_similarity(query.clone(), 5).unwrap();
+        assert!(distinct_vectors(&with_copies) < 5);
+
+        model.set_collapse_duplicates(Some(0.));
+        let exact = model.score_vector_similarity(query.clone(), 5).unwrap();
+        assert_eq!(exact.len(), 5);
+        // Only the near-copy, not bit-identical, may repeat a vector
+        let others: Vec<_> = exact
+            .iter()
+            .filter(|result| result.index != 30)
+            .cloned()
+            .collect();
+        assert_eq!(distinct_vectors(&others), others.len());
+
+        model.set_collapse_duplicates(Some(1e-3));
+        let within_tolerance = model.score_vector_similarity(query, 5).unwrap();
+        assert_eq!(within_tolerance.len(), 5);
+        assert_eq!(distinct_vectors(&within_tolerance), 5);
+        let everything = model
+            .score_vector_similarity(distinct.get(3).unwrap().unsqueeze(0).unwrap(), 20)
+            .unwrap();
+        assert_eq!(everything.len(), 10);
+    }
+
+    #[test]
+    fn scoring_on_another_device_matches() {
+        let mut model = tiny_model();
+        model.add_embeddings(random_embeddings(100)).unwrap();
+        model
+            .set_doc_ids((0..100).map(|index| format!("doc-{index}")).collect())
+            .unwrap();
+        let query = random_embeddings(1);
+
+        let on_cpu = model
+            .score_vector_similarity_on(query.clone(), 5, &Device::Cpu)
+            .unwrap();
+        assert_eq!(on_cpu, model.score_vector_similarity(query, 5).unwrap());
+        assert_eq!(on_cpu[0].id, Some(format!("doc-{}", on_cpu[0].index)));
+    }
+
+    #[test]
+    fn matmul_scoring_matches_per_row_loop() {
+        let mut model = tiny_model();
+        let embeddings = Tensor::randn(0f32, 1., (200, HIDDEN_SIZE), &Device::Cpu).unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        let query = Tensor::randn(0f32, 1., (1, HIDDEN_SIZE), &Device::Cpu).unwrap();
+
+        let query_norm = query.sqr().unwrap().sum_all().unwrap().sqrt().unwrap();
+        let query_norm = query_norm.to_scalar::<f32>().unwrap();
+        let mut expected = model
+            .score_rows_serial(|cur_vec| {
+                let dot_product = (cur_vec * &query)?.sum_all()?.to_scalar::<f32>()?;
+                let cur_norm = cur_vec.sqr()?.sum_all()?.sqrt()?.to_scalar::<f32>()?;
+                Ok(dot_product / (cur_norm * query_norm))
+            })
+            .unwrap();
+        expected.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+        let scores = model.score_vector_similarity(query, 200).unwrap();
+
+        assert_eq!(scores.len(), expected.len());
+        for (score, expected) in scores.iter().zip(&expected) {
+            assert_eq!(score.index, expected.0);
+            assert!(
+                (score.score - expected.1).abs() < 1e-5,
+                "{score:?} vs {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn tied_scores_are_returned_in_index_order() {
+        let scores = vec![0.5, f32::NAN, 0.9, 0.5, 0.9, 0.5, f32::NAN, 0.1];
+
+        let top_k = select_top_k(scores.iter().copied().enumerate(), 5);
+        let indices: Vec<_> = top_k.iter().map(|&(index, _)| index).collect();
+        assert_eq!(indices, [2, 4, 0, 3, 5]);
+
+        let all = select_top_k(scores.into_iter().enumerate(), 8);
+        let indices: Vec<_> = all.iter().map(|&(index, _)| index).collect();
+        assert_eq!(indices, [2, 4, 0, 3, 5, 7, 1, 6]);
+
+        let mut model = tiny_model();
+        let embeddings = Tensor::new(&[[1f32; HIDDEN_SIZE]; 4], &Device::Cpu).unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        let query = Tensor::ones((1, HIDDEN_SIZE), DType::F32, &Device::Cpu).unwrap();
+        let results = model.score_vector_similarity(query.clone(), 3).unwrap();
+        let indices: Vec<_> = results.iter().map(|result| result.index).collect();
+        assert_eq!(indices, [0, 1, 2]);
+        let results = model.score_vector_l2(query, 3).unwrap();
+        let indices: Vec<_> = results.iter().map(|&(index, _)| index).collect();
+        assert_eq!(indices, [0, 1, 2]);
+    }
+
+    #[test]
+    fn heap_top_k_matches_full_sort() {
+        let scores = Tensor::randn(0f32, 1., 10_000, &Device::Cpu)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        let mut expected: Vec<_> = scores.iter().copied().enumerate().collect();
+        expected.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        expected.truncate(5);
+
+        let top_k = select_top_k(scores.into_iter().enumerate(), 5);
+
+        assert_eq!(top_k, expected);
+        assert!(select_top_k([(0, 1.)], 0).is_empty());
+    }
+
+    #[test]
+    fn threshold_search_filters_and_caps() {
+        let mut model = tiny_model();
+        let embeddings = padded_embeddings(&[&[1., 0.], &[0.8, 0.6], &[0.6, 0.8], &[0., 1.]]);
+        model.add_embeddings(embeddings).unwrap();
+        let query = padded_embeddings(&[&[1., 0.]]);
+
+        let results = model
+            .score_vector_similarity_threshold(query.clone(), 0.5, None)
+            .unwrap();
+        let indices: Vec<_> = results.iter().map(|r| r.0).collect();
+        assert_eq!(indices, vec![0, 1, 2]);
+
+        let results = model
+            .score_vector_similarity_threshold(query.clone(), 0.5, Some(2))
+            .unwrap();
+        let indices: Vec<_> = results.iter().map(|r| r.0).collect();
+        assert_eq!(indices, vec![0, 1]);
+
+        let results = model
+            .score_vector_similarity_threshold(query, 1.5, Some(2))
+            .unwrap();
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn indexed_texts_are_found_by_id() {
+        let mut model = tiny_model();
+        // Three tokens each, so batching pads none of them
+        let texts: Vec<String> = [
+            "deep learning survey",
+            "graph neural network",
+            "autonomous driving theory",
+            "image segmentation model",
+            "dense retrieval models",
+            "vector search query",
+            "transformer attention embeddings",
+            "quick brown fox",
+            "lazy dog sat",
+            "reinforcement learning principles",
+        ]
+        .iter()
+        .map(|text| text.to_string())
+        .collect();
+        let ids: Vec<String> = (0..10).map(|index| format!("doc-{index}")).collect();
+
+        model
+            .index_texts(texts[..6].to_vec(), ids[..6].to_vec())
+            .unwrap();
+        model
+            .index_texts(texts[6..].to_vec(), ids[6..].to_vec())
+            .unwrap();
+        assert_eq!(model.embeddings().dims(), &[10, HIDDEN_SIZE]);
+        let top = model.search_text(&texts[4], 1).unwrap();
+        assert_eq!(top[0].id.as_deref(), Some("doc-4"));
+
+        // Upserting replaces the embedding of an ID
+        model
+            .index_texts(vec!["the cat sat".to_string()], vec!["doc-4".to_string()])
+            .unwrap();
+        assert_eq!(model.embeddings().dims(), &[10, HIDDEN_SIZE]);
+        assert_eq!(model.doc_ids().unwrap().last().unwrap(), "doc-4");
+        let top = model.search_text("the cat sat", 1).unwrap();
+        assert_eq!(top[0].id.as_deref(), Some("doc-4"));
+
+        assert!(model
+            .index_texts(texts[..2].to_vec(), ids[..1].to_vec())
+            .is_err());
+        let duplicate_ids = vec!["doc-a".to_string(), "doc-a".to_string()];
+        assert!(model
+            .index_texts(texts[..2].to_vec(), duplicate_ids)
+            .is_err());
+    }
+
+    #[test]
+    fn search_maps_indices_to_doc_ids() {
+        let mut model = tiny_model();
+        let embeddings = random_embeddings(4);
+        model.add_embeddings(embeddings.clone()).unwrap();
+        let doc_ids = ["doc-c", "doc-a", "doc-d", "doc-b"];
+        model
+            .set_doc_ids(doc_ids.iter().map(|id| id.to_string()).collect())
+            .unwrap();
+
+        for (index, doc_id) in doc_ids.iter().enumerate() {
+            let query = embeddings.get(index).unwrap().unsqueeze(0).unwrap();
+            let results = model.search(query, 1).unwrap();
+            assert_eq!(results[0].0, *doc_id);
+        }
+
+        let err = model.set_doc_ids(vec!["doc-a".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("1 document IDs for 4"), "{err}");
+    }
+
+    #[test]
+    fn half_precision_weights_give_comparable_scores() {
+        let model_dir = tiny_model_dir("half_precision_weights");
+        let load = |dtype| {
+            BertInferenceModel::load_from_path(
+                &model_dir,
+                "",
+                "",
+                DEFAULT_MAX_LENGTH,
+                Device::Cpu,
+                dtype,
+            )
+            .unwrap()
+        };
+        let mut model_f32 = load(DType::F32);
+        let mut model_f16 = load(DType::F16);
+        assert_eq!(model_f16.dtype(), DType::F16);
+        let sentences: Vec<_> = [
+            "deep learning survey",
+            "graph neural networks",
+            "cat on a mat",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let query_f16 = model_f16.infer_sentence_embedding("deep learning").unwrap();
+        assert_eq!(query_f16.dtype(), DType::F16);
+        let query_f32 = model_f32.infer_sentence_embedding("deep learning").unwrap();
+        let embeddings = model_f16.create_embeddings(sentences.clone()).unwrap();
+        model_f16.add_embeddings(embeddings).unwrap();
+        let embeddings = model_f32.create_embeddings(sentences).unwrap();
+        model_f32.add_embeddings(embeddings).unwrap();
+
+        let mut scores_f16 = model_f16.score_vector_similarity(query_f16, 3).unwrap();
+        let mut scores_f32 = model_f32.score_vector_similarity(query_f32, 3).unwrap();
+        scores_f16.sort_by_key(|r| r.index);
+        scores_f32.sort_by_key(|r| r.index);
+        for (score_f16, score_f32) in scores_f16.iter().zip(&scores_f32) {
+            assert!((-1.01..=1.01).contains(&score_f16.score), "{score_f16:?}");
+            assert!(
+                (score_f16.score - score_f32.score).abs() < 0.05,
+                "{score_f16:?} vs {score_f32:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn adaptive_batches_stay_within_the_token_budget() {
+        let model = tiny_model();
+        let long = "the quick brown fox jumps over the lazy dog and the cat sat on the mat";
+        let sentences: Vec<String> = (0..40)
+            .map(|i| match i % 10 {
+                0 | 1 => long.to_string(),
+                _ => "the cat".to_string(),
+            })
+            .collect();
+        let token_count = |sentence: &String| {
+            model
+                .tokenizer
+                .encode(sentence.as_str(), true)
+                .unwrap()
+                .len()
+        };
+
+        let batches = model.adaptive_batches(&sentences, 64).unwrap();
+        assert_eq!(batches.first().unwrap().start, 0);
+        assert_eq!(batches.last().unwrap().end, sentences.len());
+        assert!(batches.windows(2).all(|pair| pair[0].end == pair[1].start));
+        for batch in &batches {
+            let longest = sentences[batch.clone()]
+                .iter()
+                .map(token_count)
+                .max()
+                .unwrap();
+            assert!(batch.len() * longest <= 64, "{batch:?} of {longest} tokens");
+        }
+        // Short sentences share large batches, long ones don't
+        assert!(batches.iter().any(|batch| batch.len() >= 6));
+        assert!(batches.iter().any(|batch| batch.len() <= 3));
+
+        let embeddings = model
+            .create_embeddings_adaptive(sentences.clone(), 64)
+            .unwrap();
+        assert_eq!(embeddings.dims(), &[40, HIDDEN_SIZE]);
+        let err = model
+            .create_embeddings_adaptive(sentences, 8)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Sentence 0 has"), "{err}");
+    }
+
+    #[test]
+    fn chunked_embeddings_match_single_pass() {
+        let model = tiny_model();
+        let words: Vec<_> = VOCAB.split_whitespace().skip(4).collect();
+        // Same number of words, and so of tokens, per sentence to avoid any padding
+        let sentences: Vec<_> = (0..1000)
+            .map(|i| {
+                let word = |offset: usize| words[(i * 7 + offset * 13) % words.len()];
+                format!("{} {} {}", word(0), word(1), word(2))
+            })
+            .collect();
+
+        let single_pass = model.create_embeddings(sentences.clone()).unwrap();
+        let chunked = model.create_embeddings_chunked(sentences, 64).unwrap();
+
+        assert_eq!(chunked.dims(), &[1000, HIDDEN_SIZE]);
+        let max_diff = (single_pass - chunked).unwrap().abs().unwrap();
+        let max_diff = max_diff.flatten_all().unwrap().max(0).unwrap();
+        let max_diff = max_diff.to_scalar::<f32>().unwrap();
+        assert!(max_diff < 1e-5, "{max_diff}");
+    }
+
+    #[test]
+    fn builder_configures_model() {
+        let model_dir = tiny_model_dir("builder_configures_model");
+
+        let model = BertInferenceModel::builder()
+            .model_dir(&model_dir)
+            .device(Device::Cpu)
+            .dtype(DType::F16)
+            .pooling(PoolingStrategy::Cls)
+            .max_length(16)
+            .build()
+            .unwrap();
+
+        assert_eq!(model.dtype(), DType::F16);
+        assert_eq!(model.pooling(), PoolingStrategy::Cls);
+        assert_eq!(model.max_length(), 16);
+        let embedding = model.infer_sentence_embedding("deep learning").unwrap();
+        assert_eq!(embedding.dims(), &[1, HIDDEN_SIZE]);
+    }
+
+    #[test]
+    fn batched_queries_match_single_queries() {
+        let mut model = tiny_model();
+        model.add_embeddings(random_embeddings(20)).unwrap();
+        let queries = random_embeddings(3);
+
+        let results = model.score_batch(queries.clone(), 5).unwrap();
+
+        assert_eq!(results.len(), 3);
+        for (query_index, results) in results.iter().enumerate() {
+            let query = queries.get(query_index).unwrap().unsqueeze(0).unwrap();
+            let expected = model.score_vector_similarity(query, 5).unwrap();
+            assert_eq!(results.len(), 5);
+            for (result, expected) in results.iter().zip(&expected) {
+                assert_eq!(result.0, expected.index);
+                assert!((result.1 - expected.score).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn quantized_search_keeps_recall() {
+        let mut model = tiny_model();
+        model.add_embeddings(random_embeddings(2000)).unwrap();
+        model.quantize_embeddings().unwrap();
+        let queries = random_embeddings(20);
+
+        let mut total_recall = 0.;
+        for query_index in 0..20 {
+            let query = queries.get(query_index).unwrap().unsqueeze(0).unwrap();
+            let exact = model.score_vector_similarity(query.clone(), 10).unwrap();
+            let quantized = model.score_vector_similarity_quantized(query, 10).unwrap();
+            total_recall += recall(&quantized, &exact);
+        }
+
+        let recall_at_10 = total_recall / 20.;
+        assert!(recall_at_10 > 0.95, "recall@10: {recall_at_10}");
+    }
+
+    #[test]
+    fn mmaped_search_matches_in_memory_search() {
+        let mut model = tiny_model();
+        let dir = tiny_model_dir("mmaped_search");
+        let path = dir.join("embeddings.safetensors");
+        let path = path.to_str().unwrap();
+        // More rows than one chunk, to cover the chunk boundaries
+        let embeddings = random_embeddings(MMAP_CHUNK_ROWS + 100);
+        model
+            .save_embeddings(&embeddings, path, "embeddings")
+            .unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        assert!(model
+            .score_vector_similarity_mmap(random_embeddings(1), 10)
+            .is_err());
+
+        model.load_embeddings_mmap(path, "embeddings").unwrap();
+        let mmaped = model.mmaped_embeddings().unwrap();
+        assert_eq!(mmaped.len(), MMAP_CHUNK_ROWS + 100);
+        assert_eq!(
+            mmaped
+                .rows(3..5, &Device::Cpu)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap(),
+            model
+                .embeddings()
+                .narrow(0, 3, 2)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap()
+        );
+        let assert_same_results = |model: &BertInferenceModel, tolerance: f32| {
+            let query = random_embeddings(1);
+            let in_memory = model.score_vector_similarity(query.clone(), 10).unwrap();
+            let mmaped = model.score_vector_similarity_mmap(query, 10).unwrap();
+            let indices = |results: &[SearchResult]| -> Vec<usize> {
+                results.iter().map(|result| result.index).collect()
+            };
+            assert_eq!(indices(&mmaped), indices(&in_memory));
+            for (mmaped, in_memory) in mmaped.iter().zip(&in_memory) {
+                assert!((mmaped.score - in_memory.score).abs() < tolerance);
+                assert_eq!(mmaped.id, None);
+            }
+        };
+        for _ in 0..5 {
+            assert_same_results(&model, 1e-6);
+        }
+        // Mapped rows are standardized like the in-memory ones, whose IDs they don't share
+        model
+            .set_doc_ids(
+                (0..MMAP_CHUNK_ROWS + 100)
+                    .map(|index| format!("doc-{index}"))
+                    .collect(),
+            )
+            .unwrap();
+        model.fit_standardizer().unwrap();
+        assert_same_results(&model, 1e-5);
+
+        assert!(model.load_embeddings_mmap(path, "missing").is_err());
+        let wide_path = dir.join("wide.safetensors");
+        let wide_path = wide_path.to_str().unwrap();
+        let wide = Tensor::zeros((4, HIDDEN_SIZE * 2), DType::F32, &Device::Cpu).unwrap();
+        model
+            .save_embeddings(&wide, wide_path, "embeddings")
+            .unwrap();
+        assert!(model.load_embeddings_mmap(wide_path, "embeddings").is_err());
+    }
+
+    #[test]
+    fn removed_embeddings_are_no_longer_found() {
+        let mut model = tiny_model();
+        let embeddings = random_embeddings(6);
+        model.add_embeddings(embeddings.clone()).unwrap();
+        model
+            .set_doc_ids((0..6).map(|index| format!("doc-{index}")).collect())
+            .unwrap();
+
+        // Duplicates count once, out of range indices are skipped
+        assert_eq!(model.remove_embeddings(&[1, 4, 4, 17]).unwrap(), 4);
+
+        assert_eq!(
+            model.doc_ids().unwrap(),
+            ["doc-0", "doc-2", "doc-3", "doc-5"]
+        );
+        for removed in [1, 4] {
+            let query = embeddings.get(removed).unwrap().unsqueeze(0).unwrap();
+            let results = model.score_vector_similarity(query, 4).unwrap();
+            let ids: Vec<_> = results
+                .iter()
+                .map(|result| result.id.clone().unwrap())
+                .collect();
+            assert!(!ids.contains(&format!("doc-{removed}")));
+        }
+        for kept in [0, 2, 3, 5] {
+            let query = embeddings.get(kept).unwrap().unsqueeze(0).unwrap();
+            let results = model.search(query, 1).unwrap();
+            assert_eq!(results[0].0, format!("doc-{kept}"));
+        }
+        assert_eq!(model.remove_embeddings(&[]).unwrap(), 4);
+    }
+
+    #[test]
+    fn centroid_and_medoid_represent_a_group() {
+        let mut model = tiny_model();
+        let vector = random_embeddings(1);
+        let opposite = vector.neg().unwrap();
+        let other = random_embeddings(1);
+        let embeddings = Tensor::cat(&[&other, &vector, &opposite], 0).unwrap();
+        model.add_embeddings(embeddings).unwrap();
+
+        let centroid = model.centroid(&[1, 2], false).unwrap();
+        assert_eq!(centroid.dims(), [1, HIDDEN_SIZE]);
+        let norm = centroid.sqr().unwrap().sum_all().unwrap();
+        assert!(norm.to_scalar::<f32>().unwrap() < 1e-10);
+        assert!([1, 2].contains(&model.medoid(&[1, 2]).unwrap()));
+
+        let centroid = model.centroid(&[0, 1, 2], true).unwrap();
+        let norm = centroid.sqr().unwrap().sum_all().unwrap();
+        assert!((norm.to_scalar::<f32>().unwrap() - 1.).abs() < 1e-5);
+        assert!(model.centroid(&[], false).is_err());
+        assert!(model.medoid(&[0, 3]).is_err());
+    }
+
+    #[test]
+    fn kmeans_recovers_separated_clusters() {
+        let mut model = tiny_model();
+        // 3 clusters of 10 noisy copies of a random center each
+        let centers = random_embeddings(3);
+        let noise = Tensor::randn(0f32, 0.05, (3, 10, HIDDEN_SIZE), &Device::Cpu).unwrap();
+        let embeddings = centers
+            .unsqueeze(1)
+            .unwrap()
+            .broadcast_add(&noise)
+            .unwrap()
+            .reshape((30, HIDDEN_SIZE))
+            .unwrap();
+        model.add_embeddings(embeddings).unwrap();
+
+        let (centroids, assignments) = model.kmeans(3, 20).unwrap();
+
+        assert_eq!(centroids.dims(), [3, HIDDEN_SIZE]);
+        assert_eq!(assignments.len(), 30);
+        let groups: Vec<&[usize]> = assignments.chunks(10).collect();
+        for group in &groups {
+            assert!(
+                group.iter().all(|&cluster| cluster == group[0]),
+                "{assignments:?}"
+            );
+        }
+        assert_ne!(groups[0][0], groups[1][0]);
+        assert_ne!(groups[0][0], groups[2][0]);
+        assert_ne!(groups[1][0], groups[2][0]);
+        assert!(model.kmeans(31, 20).is_err());
+    }
+
+    #[test]
+    fn pq_search_keeps_recall() {
+        let mut model = tiny_model();
+        // 100 clusters of 10 noisy copies of a random center each, so the exact top 10 of a
+        // center is its cluster
+        let centers = random_embeddings(100);
+        let noise = Tensor::randn(0f32, 0.05, (100, 10, HIDDEN_SIZE), &Device::Cpu).unwrap();
+        let embeddings = centers
+            .unsqueeze(1)
+            .unwrap()
+            .broadcast_add(&noise)
+            .unwrap()
+            .reshape((1000, HIDDEN_SIZE))
+            .unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        assert!(model.search_pq(random_embeddings(1), 10).is_err());
+        model.train_pq(8, 64).unwrap();
+
+        let mut total_recall = 0.;
+        for cluster in 0..20 {
+            let query = centers.get(cluster).unwrap().unsqueeze(0).unwrap();
+            let exact = model.score_vector_l2(query.clone(), 10).unwrap();
+            let approximate = model.search_pq(query, 10).unwrap();
+            let hits = approximate
+                .iter()
+                .filter(|(index, _)| exact.iter().any(|(expected, _)| expected == index))
+                .count();
+            total_recall += hits as f32 / 10.;
+        }
+
+        let recall_at_10 = total_recall / 20.;
+        assert!(recall_at_10 > 0.9, "recall@10: {recall_at_10}");
+        assert_eq!(model.product_quantizer().unwrap().n_subspaces(), 8);
+    }
+
+    #[test]
+    fn binary_search_recovers_clusters() {
+        let mut model = tiny_model();
+        // 20 clusters of 10 noisy copies of a random center each
+        let centers = random_embeddings(20);
+        let noise = Tensor::randn(0f32, 0.05, (20, 10, HIDDEN_SIZE), &Device::Cpu).unwrap();
+        let embeddings = centers
+            .unsqueeze(1)
+            .unwrap()
+            .broadcast_add(&noise)
+            .unwrap()
+            .reshape((200, HIDDEN_SIZE))
+            .unwrap();
+        model.add_embeddings(embeddings).unwrap();
+
+        let mut total_recall = 0.;
+        for cluster in 0..20 {
+            let query = centers.get(cluster).unwrap().unsqueeze(0).unwrap();
+            let exact = model.score_vector_similarity(query.clone(), 10).unwrap();
+            let binary: Vec<_> = model
+                .search_binary(query, 10)
+                .unwrap()
+                .into_iter()
+                .map(|(index, distance)| (index, distance as f32))
+                .collect();
+            total_recall += recall(&binary, &exact);
+        }
+
+        let recall_at_10 = total_recall / 20.;
+        assert!(recall_at_10 > 0.8, "recall@10: {recall_at_10}");
+    }
+
+    #[cfg(feature = "hnsw")]
+    #[test]
+    fn ann_search_matches_brute_force() {
+        let mut model = tiny_model();
+        model.add_embeddings(random_embeddings(10_000)).unwrap();
+        model.build_index().unwrap();
+        let queries = random_embeddings(20);
+
+        let mut total_recall = 0.;
+        for query_index in 0..20 {
+            let query = queries.get(query_index).unwrap().unsqueeze(0).unwrap();
+            let exact = model.score_vector_similarity(query.clone(), 10).unwrap();
+            let approximate = model.search_ann(query, 10, 100).unwrap();
+            total_recall += recall(&approximate, &exact);
+        }
+
+        let recall_at_10 = total_recall / 20.;
+        assert!(recall_at_10 > 0.9, "recall@10: {recall_at_10}");
+    }
+
+    #[cfg(feature = "gguf")]
+    #[test]
+    fn quantized_weights_keep_top_k_overlap() {
+        use candle::quantized::{gguf_file, GgmlDType, QTensor};
+
+        let model_dir = tiny_model_dir("quantized_weights");
+        let mut full = BertInferenceModel::load_from_path(
+            &model_dir,
+            "",
+            "",
+            DEFAULT_MAX_LENGTH,
+            Device::Cpu,
+            DType::F32,
+        )
+        .unwrap();
+
+        // Matrices go to 8 bits, vectors such as biases stay in f32
+        let tensors = safetensors::load(model_dir.join("model.safetensors"), &Device::Cpu).unwrap();
+        let tensors: Vec<(String, QTensor)> = tensors
+            .iter()
+            .map(|(name, tensor)| {
+                let quantized = match tensor.rank() {
+                    2 => QTensor::quantize(tensor, GgmlDType::Q8_0),
+                    _ => QTensor::quantize(tensor, GgmlDType::F32),
+                };
+                (name.clone(), quantized.unwrap())
+            })
+            .collect();
+        let tensors: Vec<_> = tensors
+            .iter()
+            .map(|(name, tensor)| (name.as_str(), tensor))
+            .collect();
+        let gguf_path = model_dir.join("model.gguf");
+        let mut file = std::fs::File::create(&gguf_path).unwrap();
+        gguf_file::write(&mut file, &[], &tensors).unwrap();
+
+        let mut quantized = BertInferenceModel::load_quantized(
+            &gguf_path,
+            &model_dir,
+            "",
+            "",
+            DEFAULT_MAX_LENGTH,
+            Device::Cpu,
+        )
+        .unwrap();
+        assert_eq!(quantized.dtype(), DType::F32);
+
+        let sentences: Vec<String> = [
+            "deep learning survey",
+            "graph neural network",
+            "autonomous driving model",
+            "dense vector retrieval",
+            "the cat sat on the mat",
+            "the quick brown fox",
+            "image segmentation model",
+            "language model attention",
+        ]
+        .iter()
+        .map(|sentence| sentence.to_string())
+        .collect();
+        let full_embeddings = full.create_embeddings(sentences.clone()).unwrap();
+        let quantized_embeddings = quantized.create_embeddings(sentences).unwrap();
+        let agreement = (&full_embeddings * &quantized_embeddings)
+            .unwrap()
+            .sum(1)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert!(
+            agreement.iter().all(|cosine| *cosine > 0.99),
+            "{agreement:?}"
+        );
+        full.add_embeddings(full_embeddings).unwrap();
+        quantized.add_embeddings(quantized_embeddings).unwrap();
+
+        let mut total_recall = 0.;
+        let queries = ["neural retrieval", "a dog on a mat", "deep model"];
+        for query in queries {
+            let exact = full.search_text(query, 3).unwrap();
+            let approximate: Vec<_> = quantized
+                .search_text(query, 3)
+                .unwrap()
+                .into_iter()
+                .map(<(usize, f32)>::from)
+                .collect();
+            total_recall += recall(&approximate, &exact);
+        }
+
+        let recall_at_3 = total_recall / queries.len() as f32;
+        assert!(recall_at_3 > 0.6, "recall@3: {recall_at_3}");
+    }
+
+    #[test]
+    fn mmr_spreads_out_near_duplicates() {
+        let device = Device::Cpu;
+        let mut model = tiny_model();
+        // Orthogonal directions: `center` is the first one, `others` the next six
+        let basis = Tensor::eye(HIDDEN_SIZE, DType::F32, &device).unwrap();
+        let center = basis.narrow(0, 0, 1).unwrap();
+        let others = basis.narrow(0, 1, 6).unwrap();
+        // Rows 0..5 are near-identical copies of `center`
+        let noise = Tensor::randn(0f32, 0.01, (5, HIDDEN_SIZE), &device).unwrap();
+        let cluster = center.broadcast_add(&noise).unwrap();
+        model
+            .add_embeddings(Tensor::cat(&[&cluster, &others], 0).unwrap())
+            .unwrap();
+
+        // Closest to the cluster, but also related to a few of the other rows
+        let related = others.narrow(0, 0, 3).unwrap().sum_keepdim(0).unwrap();
+        let query = ((center * 2.).unwrap() + related).unwrap();
+
+        let in_cluster =
+            |results: &[(usize, f32)]| results.iter().filter(|(index, _)| *index < 5).count();
+        let top_k: Vec<_> = model
+            .score_vector_similarity(query.clone(), 4)
+            .unwrap()
+            .into_iter()
+            .map(<(usize, f32)>::from)
+            .collect();
+        assert_eq!(in_cluster(&top_k), 4);
+
+        let mmr = model.search_mmr(query.clone(), 4, 0.5).unwrap();
+        assert_eq!(mmr.len(), 4);
+        assert_eq!(in_cluster(&mmr), 1);
+        assert_eq!(mmr[0], top_k[0]);
+
+        let plain = model.search_mmr(query, 4, 1.).unwrap();
+        assert_eq!(plain, top_k);
+    }
+
+    /// Keeps every log record so tests can check what the crate logs.
+    struct CapturingLogger(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(std::sync::Mutex::new(Vec::new()));
+
+    #[test]
+    fn inference_reports_through_the_logger() {
+        // Another test may have installed it already
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let model = tiny_model();
+        model.infer_sentence_embedding("the cat sat").unwrap();
+        model
+            .create_embeddings(vec!["the dog ran".to_string()])
+            .unwrap();
+
+        let messages = LOGGER.0.lock().unwrap();
+        assert!(messages
+            .iter()
+            .any(|message| message.starts_with("Time taken for inference")));
+        assert!(messages
+            .iter()
+            .any(|message| message.starts_with("create_embeddings completed")));
+    }
+
+    #[test]
+    fn inference_reports_truncation() {
+        let model = tiny_model();
+
+        let long_sentence = "the quick brown fox ".repeat(100);
+        let (embedding, stats) = model.infer_with_stats(&long_sentence).unwrap();
+        assert!(stats.truncated);
+        assert_eq!(stats.token_count, model.max_length());
+        assert_eq!(embedding.dims(), &[1, HIDDEN_SIZE]);
+
+        let (_embedding, stats) = model.infer_with_stats("the quick brown fox").unwrap();
+        assert!(!stats.truncated);
+        // With [CLS] and [SEP]
+        assert_eq!(stats.token_count, 6);
+    }
+
+    #[test]
+    fn l2_normalize_keeps_zero_rows_finite() {
+        let device = Device::Cpu;
+        let embeddings = Tensor::new(&[[0f32, 0., 0.], [3., 0., 4.]], &device).unwrap();
+
+        let normalized = BertInferenceModel::l2_normalize(&embeddings)
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        assert!(normalized.iter().flatten().all(|value| !value.is_nan()));
+        assert_eq!(normalized, [[0., 0., 0.], [0.6, 0., 0.8]]);
+
+        let half = BertInferenceModel::l2_normalize(&embeddings.to_dtype(DType::F16).unwrap())
+            .unwrap()
+            .to_dtype(DType::F32)
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        assert_eq!(half[0], [0., 0., 0.]);
+    }
+
+    #[test]
+    fn truncation_strategy_decides_which_sentence_is_cut() {
+        let mut model = tiny_model();
+        model.set_max_length(13).unwrap();
+        assert_eq!(
+            model.truncation_strategy(),
+            TruncationStrategy::LongestFirst
+        );
+        let pair = (
+            "the quick brown fox jumps over the lazy",
+            "deep learning survey of neural network language models",
+        );
+        // Tokens kept from the first and the second sentence, special tokens aside
+        let kept = |model: &BertInferenceModel| {
+            let (tokens, _, _) = model.encode_input(pair).unwrap();
+            let type_ids = tokens.get_type_ids();
+            let second = type_ids.iter().filter(|&&type_id| type_id == 1).count();
+            (type_ids.len() - second - 2, second - 1)
+        };
+
+        assert_eq!(kept(&model), (5, 5));
+        model
+            .set_truncation_strategy(TruncationStrategy::OnlySecond)
+            .unwrap();
+        assert_eq!(kept(&model), (8, 2));
+        assert_eq!(model.max_length(), 13);
+    }
+
+    #[test]
+    fn fixed_padding_gives_equal_lengths() {
+        let mut model = tiny_model();
+        model.set_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::Fixed(16),
+            direction: PaddingDirection::Left,
+            ..Default::default()
+        }));
+        let sentences = [
+            "the cat",
+            "the quick brown fox jumps over the lazy dog",
+            "search",
+        ];
+
+        let (token_ids, attention_mask) = model
+            .encode_batch_padded(sentences.iter().map(|s| s.to_string()).collect())
+            .unwrap();
+        assert_eq!(token_ids.dims(), &[3, 16]);
+        let attention_mask = attention_mask.to_vec2::<u32>().unwrap();
+        // [CLS] the cat [SEP], padded on the left
+        assert_eq!(attention_mask[0][..12], [0; 12]);
+        assert_eq!(attention_mask[0][12..], [1; 4]);
+
+        let embeddings = model
+            .create_embeddings(sentences.iter().map(|s| s.to_string()).collect())
+            .unwrap();
+        assert_eq!(embeddings.dims(), &[3, HIDDEN_SIZE]);
+    }
+
+    /// [`tiny_tokenizer`] with `[PAD]` renamed to `pad`, and given the id of `lazy` when `pad_id`
+    /// is set.
+    fn tokenizer_with_pad(pad: &str, pad_id: Option<u32>) -> Tokenizer {
+        let mut tokenizer: serde_json::Value =
+            serde_json::from_str(&tiny_tokenizer().to_string(false).unwrap()).unwrap();
+        let vocab = tokenizer["model"]["vocab"].as_object_mut().unwrap();
+        let mut id = vocab.remove("[PAD]").unwrap();
+        if let Some(pad_id) = pad_id {
+            vocab.insert("lazy".to_string(), id);
+            id = pad_id.into();
+        }
+        vocab.insert(pad.to_string(), id.clone());
+        tokenizer["added_tokens"][0]["id"] = id;
+        tokenizer["added_tokens"][0]["content"] = pad.into();
+
+        Tokenizer::from_bytes(tokenizer.to_string()).unwrap()
+    }
+
+    #[test]
+    fn batches_are_padded_with_the_tokenizer_pad_id() {
+        let mut model = tiny_model();
+        let lazy = model.tokenizer.token_to_id("lazy").unwrap();
+        model.tokenizer = tokenizer_with_pad("[PAD]", Some(lazy));
+        assert_eq!(model.pad_token().unwrap(), (lazy, "[PAD]".to_string()));
+
+        let (token_ids, attention_mask) = model
+            .encode_batch_padded(vec!["the cat sat".to_string(), "search".to_string()])
+            .unwrap();
+        // [CLS] search [SEP], then two pads
+        assert_eq!(token_ids.to_vec2::<u32>().unwrap()[1][3..], [lazy, lazy]);
+        assert_eq!(attention_mask.to_vec2::<u32>().unwrap()[1][3..], [0, 0]);
+
+        model.tokenizer = tokenizer_with_pad("<pad>", None);
+        assert_eq!(model.pad_token().unwrap(), (0, "<pad>".to_string()));
+    }
+
+    #[test]
+    fn padding_without_a_pad_token_fails() {
+        let mut model = tiny_model();
+        model.tokenizer = tokenizer_with_pad("[NOTHING]", None);
+
+        let err = model
+            .encode_batch_padded(vec!["the cat sat".to_string(), "search".to_string()])
+            .unwrap_err();
+        assert!(err.to_string().contains("set_padding"), "{err}");
+        // Nothing to pad
+        assert!(model
+            .encode_batch_padded(vec!["the cat".to_string(), "the dog".to_string()])
+            .is_ok());
+        model.set_padding(Some(PaddingParams {
+            pad_id: 5,
+            pad_token: "an".to_string(),
+            ..Default::default()
+        }));
+        assert_eq!(model.pad_token().unwrap(), (5, "an".to_string()));
+    }
+
+    #[test]
+    fn padded_queries_pool_like_passages() {
+        let mut model = tiny_model();
+        model.set_pooling(PoolingStrategy::MeanMasked);
+        model.set_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::Fixed(16),
+            ..Default::default()
+        }));
+        let sentence = "graph neural network";
+
+        let query = model.infer_sentence_embedding(sentence).unwrap();
+        let passage = model.create_embeddings(vec![sentence.to_string()]).unwrap();
+        let diff = (query - passage)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-6, "{diff}");
+        // [CLS] graph neural network [SEP], without the padding
+        let (_, stats) = model.infer_with_stats(sentence).unwrap();
+        assert_eq!(stats.token_count, 5);
+    }
+
+    #[test]
+    fn builder_uses_the_given_tokenizer() {
+        let mut tokenizer = tiny_tokenizer();
+        tokenizer.with_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::Fixed(16),
+            ..Default::default()
+        }));
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                strategy: TruncationStrategy::OnlySecond,
+                ..Default::default()
+            }))
+            .unwrap();
+        let model = BertInferenceModel::builder()
+            .model_dir(tiny_model_dir("given_tokenizer"))
+            .tokenizer(tokenizer)
+            .build()
+            .unwrap();
+
+        let (token_ids, _) = model
+            .encode_batch_padded(vec!["the cat".to_string(), "search".to_string()])
+            .unwrap();
+        assert_eq!(token_ids.dims(), &[2, 16]);
+        assert!(matches!(
+            model.tokenizer.get_padding().unwrap().strategy,
+            PaddingStrategy::Fixed(16)
+        ));
+        assert_eq!(model.truncation_strategy(), TruncationStrategy::OnlySecond);
+    }
+
+    #[test]
+    fn embed_stream_yields_batches() {
+        let model = tiny_model();
+        let sentences = (0..500).map(|i| format!("the cat sat {}", i % 7));
+
+        let batches: Vec<_> = model
+            .embed_stream(sentences, 50)
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        assert_eq!(batches.len(), 10);
+        assert!(batches
+            .iter()
+            .all(|batch| batch.dims() == [50, HIDDEN_SIZE]));
+
+        let mut empty_batch = model.embed_stream(std::iter::once("the".to_string()), 0);
+        assert!(empty_batch.next().unwrap().is_err());
+        assert!(empty_batch.next().is_none());
+    }
+
+    #[test]
+    fn search_results_serialize_with_named_fields() {
+        let mut model = tiny_model();
+        model
+            .add_embeddings(padded_embeddings(&[&[1., 0.], &[0., 1.]]))
+            .unwrap();
+        model
+            .set_doc_ids(vec!["first".to_string(), "second".to_string()])
+            .unwrap();
+        let query = padded_embeddings(&[&[0., 1.]]);
+
+        let results = model.score_vector_similarity(query, 1).unwrap();
+        assert_eq!(
+            serde_json::to_value(&results).unwrap(),
+            serde_json::json!([{ "index": 1, "score": 1.0, "id": "second" }])
+        );
+        assert_eq!(<(usize, f32)>::from(results[0].clone()), (1, 1.));
+    }
+
+    #[test]
+    fn hidden_size_is_read_from_the_config() {
+        assert_eq!(tiny_model().hidden_size(), HIDDEN_SIZE);
+
+        let mut config = tiny_config();
+        config["hidden_size"] = 768.into();
+        config["num_hidden_layers"] = 1.into();
+        config["num_attention_heads"] = 12.into();
+        let model_dir = model_dir_with_config("hidden_size_768", config);
+        let embeddings_file = model_dir.join("embeddings.safetensors");
+        let embeddings_file = embeddings_file.to_str().unwrap();
+        let load = |embeddings_file: &str| {
+            BertInferenceModel::load_from_path(
+                &model_dir,
+                embeddings_file,
+                "corpus",
+                DEFAULT_MAX_LENGTH,
+                Device::Cpu,
+                DTYPE,
+            )
+        };
+
+        let model = load("").unwrap();
+        assert_eq!(model.hidden_size(), 768);
+        let embedding = model.infer_sentence_embedding("deep learning").unwrap();
+        assert_eq!(embedding.dims(), &[1, 768]);
+
+        // Embeddings of the tiny model don't fit this one
+        model
+            .save_embeddings(&random_embeddings(3), embeddings_file, "corpus")
+            .unwrap();
+        let err = load(embeddings_file).err().unwrap();
+        assert!(err.to_string().contains("hidden size 32"), "{err}");
+    }
+
+    #[test]
+    fn load_from_path_reads_sharded_weights() {
+        let model_dir = tiny_model_dir("sharded_weights");
+        let load = || {
+            BertInferenceModel::load_from_path(
+                &model_dir,
+                "",
+                "",
+                DEFAULT_MAX_LENGTH,
+                Device::Cpu,
+                DTYPE,
+            )
+            .unwrap()
+        };
+        let expected = load().infer_sentence_embedding("deep learning").unwrap();
+
+        // Split the weights: embeddings in one shard, encoder layers in another
+        let weights = model_dir.join("model.safetensors");
+        let tensors = safetensors::load(&weights, &Device::Cpu).unwrap();
+        let (embeddings, encoder): (HashMap<_, _>, HashMap<_, _>) = tensors
+            .into_iter()
+            .partition(|(name, _)| name.starts_with("embeddings."));
+        let mut weight_map = serde_json::Map::new();
+        for (shard, tensors) in [
+            ("model-00001-of-00002.safetensors", embeddings),
+            ("model-00002-of-00002.safetensors", encoder),
+        ] {
+            for name in tensors.keys() {
+                weight_map.insert(name.clone(), shard.into());
+            }
+            safetensors::save(&tensors, model_dir.join(shard)).unwrap();
+        }
+        let index = serde_json::json!({ "metadata": {}, "weight_map": weight_map });
+        std::fs::write(model_dir.join(WEIGHTS_INDEX), index.to_string()).unwrap();
+        std::fs::remove_file(weights).unwrap();
+
+        let embedding = load().infer_sentence_embedding("deep learning").unwrap();
+        assert_eq!(
+            embedding.to_vec2::<f32>().unwrap(),
+            expected.to_vec2::<f32>().unwrap()
+        );
+    }
+
+    #[test]
+    fn prefixes_are_tokenized_with_the_text() {
+        let mut model = tiny_model();
+        let sentence = "dense retrieval";
+        let (plain_query, plain_stats) = model.infer_with_stats(sentence).unwrap();
+        let plain_passage = model.create_embeddings(vec![sentence.to_string()]).unwrap();
+
+        model.set_prefixes(Prefixes {
+            query: "query: ".to_string(),
+            passage: "document: ".to_string(),
+        });
+        let (query, stats) = model.infer_with_stats(sentence).unwrap();
+        let passage = model.create_embeddings(vec![sentence.to_string()]).unwrap();
+
+        // "query" and ":" come before the text
+        assert_eq!(stats.token_count, plain_stats.token_count + 2);
+        let values = |tensor: &Tensor| tensor.to_vec2::<f32>().unwrap();
+        assert_ne!(values(&query), values(&plain_query));
+        assert_ne!(values(&passage), values(&plain_passage));
+        assert_ne!(values(&query), values(&passage));
+    }
+
+    #[test]
+    fn norms_are_taken_before_normalization() {
+        let mut model = tiny_model();
+        let sentences = vec![
+            "the cat sat".to_string(),
+            "graph neural network".to_string(),
+        ];
+        let (embeddings, norms) = model
+            .create_embeddings_with_norms(sentences.clone())
+            .unwrap();
+        let (query, query_norm) = model.infer_with_norm("the cat sat").unwrap();
+
+        model.set_normalize(false);
+        let raw = model.create_embeddings(sentences).unwrap();
+        let expected: Vec<f32> = raw
+            .to_vec2::<f32>()
+            .unwrap()
+            .iter()
+            .map(|row| row.iter().map(|value| value * value).sum::<f32>().sqrt())
+            .collect();
+        let norms = norms.to_vec1::<f32>().unwrap();
+        for (norm, expected) in norms.iter().zip(&expected) {
+            assert!((norm - expected).abs() < 1e-4, "{norm} vs {expected}");
+        }
+        let query_norm = query_norm.to_vec1::<f32>().unwrap();
+        assert!((query_norm[0] - expected[0]).abs() < 1e-4);
+
+        // Both sentences have as many tokens, so the batch needs no padding
+        let manual = (raw.get(0).unwrap() / expected[0] as f64).unwrap();
+        for embedding in [embeddings.get(0).unwrap(), query.get(0).unwrap()] {
+            let diff = (embedding - &manual)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .max(0)
+                .unwrap();
+            assert!(diff.to_scalar::<f32>().unwrap() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn instructions_change_the_embeddings() {
+        let mut model = tiny_model();
+        model.set_query_cache_size(4);
+        let sentence = "dense retrieval";
+        let plain_query = model.infer_sentence_embedding(sentence).unwrap();
+        let plain_passage = model.create_embeddings(vec![sentence.to_string()]).unwrap();
+
+        model.set_instructions(Instructions {
+            query: Some("represent the query for retrieval:".to_string()),
+            passage: Some("represent the document for retrieval:".to_string()),
+        });
+        assert_eq!(
+            model.query_text(sentence),
+            "represent the query for retrieval: dense retrieval"
+        );
+        let query = model.infer_sentence_embedding(sentence).unwrap();
+        let passage = model.create_embeddings(vec![sentence.to_string()]).unwrap();
+
+        let values = |tensor: &Tensor| tensor.to_vec2::<f32>().unwrap();
+        assert_ne!(values(&query), values(&plain_query));
+        assert_ne!(values(&passage), values(&plain_passage));
+        assert_ne!(values(&query), values(&passage));
+
+        model.set_prefixes(Prefixes {
+            query: "query: ".to_string(),
+            passage: String::new(),
+        });
+        assert_eq!(
+            model.query_text(sentence),
+            "represent the query for retrieval: query: dense retrieval"
+        );
+    }
+
+    #[test]
+    fn repeated_queries_hit_the_cache() {
+        let mut model = tiny_model();
+        assert_eq!(model.query_cache_stats(), None);
+        model.set_query_cache_size(2);
+
+        let first = model.infer_sentence_embedding("dense retrieval").unwrap();
+        let second = model.infer_sentence_embedding("dense retrieval").unwrap();
+        assert_eq!(
+            first.to_vec2::<f32>().unwrap(),
+            second.to_vec2::<f32>().unwrap()
+        );
+        let stats = model.query_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.len), (1, 1, 1));
+
+        // The least recently used query goes first
+        model.infer_sentence_embedding("graph theory").unwrap();
+        model
+            .infer_sentence_embedding("image segmentation")
+            .unwrap();
+        model.infer_sentence_embedding("dense retrieval").unwrap();
+        let stats = model.query_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.len), (1, 4, 2));
+
+        model.set_pooling(PoolingStrategy::Cls);
+        assert_eq!(model.query_cache_stats().unwrap().len, 0);
+    }
+
+    #[test]
+    fn padding_changes_invalidate_the_query_cache() {
+        let mut model = tiny_model();
+        model.set_query_cache_size(2);
+        model.infer_sentence_embedding("dense retrieval").unwrap();
+        assert_eq!(model.query_cache_stats().unwrap().len, 1);
+
+        model.set_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::Fixed(16),
+            ..Default::default()
+        }));
+        assert_eq!(model.query_cache_stats().unwrap().len, 0);
+        let cached = model.infer_sentence_embedding("dense retrieval").unwrap();
+        assert_eq!(model.query_cache_stats().unwrap().misses, 2);
+        let (padded, _stats) = model.infer_with_stats("dense retrieval").unwrap();
+        assert_eq!(
+            cached.to_vec2::<f32>().unwrap(),
+            padded.to_vec2::<f32>().unwrap()
+        );
+    }
+
+    #[test]
+    fn normalization_can_be_turned_off() {
+        let mut model = tiny_model();
+        let sentences = vec!["neural network models".to_string()];
+        let norm = |embeddings: &Tensor| {
+            embeddings
+                .sqr()
+                .unwrap()
+                .sum_all()
+                .unwrap()
+                .sqrt()
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap()
+        };
+
+        assert!(model.normalize());
+        let normalized = model.create_embeddings(sentences.clone()).unwrap();
+        assert!((norm(&normalized) - 1.).abs() < 1e-5);
+
+        model.set_normalize(false);
+        let raw = model.create_embeddings(sentences).unwrap();
+        let raw_query = model
+            .infer_sentence_embedding("neural network models")
+            .unwrap();
+        assert!((norm(&raw) - 1.).abs() > 1e-2, "{}", norm(&raw));
+        assert!((norm(&raw_query) - 1.).abs() > 1e-2);
+    }
+
+    #[test]
+    fn standardized_embeddings_have_zero_mean() {
+        let device = Device::Cpu;
+        let mut model = tiny_model();
+        // Shift every dimension away from zero
+        let offset = Tensor::randn(0f32, 1., (1, HIDDEN_SIZE), &device).unwrap();
+        let raw = random_embeddings(500).broadcast_add(&offset).unwrap();
+        model.add_embeddings(raw.clone()).unwrap();
+
+        model.fit_standardizer().unwrap();
+
+        let means = model
+            .embeddings()
+            .mean(0)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert!(means.iter().all(|mean| mean.abs() < 1e-4), "{means:?}");
+        // Queries go through the same transform as the stored rows
+        let query = raw.get(42).unwrap().unsqueeze(0).unwrap();
+        let results = model.score_vector_similarity(query, 1).unwrap();
+        assert_eq!(results[0].index, 42);
+        assert!((results[0].score - 1.).abs() < 1e-5);
+
+        let serialized = serde_json::to_string(model.standardizer().unwrap()).unwrap();
+        let standardizer: Standardizer = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(&standardizer, model.standardizer().unwrap());
+        assert!(model.set_standardizer(standardizer).is_err());
+    }
+
+    #[test]
+    fn search_text_matches_the_two_step_path() {
+        let mut model = tiny_model();
+        let sentences: Vec<String> = [
+            "deep learning survey",
+            "graph neural network",
+            "autonomous driving model",
+            "dense vector retrieval",
+        ]
+        .iter()
+        .map(|sentence| sentence.to_string())
+        .collect();
+        let embeddings = model.create_embeddings(sentences).unwrap();
+        model.add_embeddings(embeddings).unwrap();
+
+        let query = "neural retrieval";
+        let manual = model
+            .score_vector_similarity(model.infer_sentence_embedding(query).unwrap(), 3)
+            .unwrap();
+        let results = model.search_text(query, 3).unwrap();
+
+        assert_eq!(results, manual);
+    }
+
+    #[test]
+    fn sentence_similarity_is_the_cosine_of_the_query_embeddings() {
+        // The tiny model has random weights, so only the arithmetic can be checked, not whether
+        // paraphrases score higher
+        let model = tiny_model();
+        let (first, second) = ("the cat sat on the mat", "reinforcement learning theory");
+
+        let same = model.sentence_similarity(first, first).unwrap();
+        let similarity = model.sentence_similarity(first, second).unwrap();
+        let reversed = model.sentence_similarity(second, first).unwrap();
+        let embedding = |sentence| {
+            model
+                .infer_sentence_embedding(sentence)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap()
+                .remove(0)
+        };
+        let (a, b) = (embedding(first), embedding(second));
+        let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let dot: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+
+        assert!((same - 1.).abs() < 1e-5, "{same}");
+        let expected = dot / (norm(&a) * norm(&b));
+        assert!(
+            (similarity - expected).abs() < 1e-5,
+            "{similarity} vs {expected}"
+        );
+        assert!(
+            (reversed - similarity).abs() < 1e-6,
+            "{reversed} vs {similarity}"
+        );
+    }
+
+    #[test]
+    fn dedup_drops_near_duplicates() {
+        let device = Device::Cpu;
+        let mut model = tiny_model();
+        let unique = random_embeddings(5);
+        // Rows 5 and 6 nearly repeat rows 1 and 3
+        let noise = Tensor::randn(0f32, 1e-3, (2, HIDDEN_SIZE), &device).unwrap();
+        let originals = Tensor::new(&[1u32, 3], &device).unwrap();
+        let duplicates = (unique.index_select(&originals, 0).unwrap() + noise).unwrap();
+        model
+            .add_embeddings(Tensor::cat(&[&unique, &duplicates], 0).unwrap())
+            .unwrap();
+        let doc_ids = (0..7).map(|index| format!("doc-{index}")).collect();
+        model.set_doc_ids(doc_ids).unwrap();
+
+        let removed = model.dedup_embeddings(0.99).unwrap();
+
+        assert_eq!(removed, [5, 6]);
+        assert_eq!(
+            model.embeddings().to_vec2::<f32>().unwrap(),
+            unique.to_vec2::<f32>().unwrap()
+        );
+        assert_eq!(
+            model.doc_ids().unwrap(),
+            ["doc-0", "doc-1", "doc-2", "doc-3", "doc-4"]
+        );
+        assert!(model.dedup_embeddings(0.99).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pooling_mismatch_is_reported() {
+        let model_dir = tiny_model_dir("pooling_mismatch");
+        let embeddings_file = model_dir.join("embeddings.safetensors");
+        let embeddings_file = embeddings_file.to_str().unwrap();
+        let mut model = tiny_model();
+        model.set_pooling(PoolingStrategy::Mean);
+        model
+            .save_embeddings(&random_embeddings(4), embeddings_file, "corpus")
+            .unwrap();
+
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+        let builder = BertInferenceModel::builder()
+            .model_dir(&model_dir)
+            .embeddings(embeddings_file, "corpus")
+            .dtype(DTYPE)
+            .pooling(PoolingStrategy::Max);
+        let mut model = builder.clone().build().unwrap();
+
+        assert_eq!(model.embeddings_pooling(), Some(PoolingStrategy::Mean));
+        let expected =
+            "Stored embeddings were created with mean pooling but queries use max pooling";
+        assert!(LOGGER
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|message| message == expected));
+
+        model.set_strict_pooling(true);
+        let err = model.search_text("deep learning", 1).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+        let query = model.infer_sentence_embedding("deep learning").unwrap();
+        let err = model.score_batch(query.clone(), 1).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+        model.quantize_embeddings().unwrap();
+        let err = model
+            .score_vector_similarity_quantized(query, 1)
+            .unwrap_err();
+        assert_eq!(err.to_string(), expected);
+        assert!(builder.strict_pooling(true).build().is_err());
+
+        model.set_pooling(PoolingStrategy::Mean);
+        assert!(model.search_text("deep learning", 1).is_ok());
+    }
+
+    #[test]
+    fn oversized_embeddings_header_is_rejected() {
+        let file = temp_dir("oversized_header").join("embeddings.safetensors");
+        std::fs::write(&file, u64::MAX.to_le_bytes()).unwrap();
+
+        let err = BertInferenceModel::read_embeddings_pooling(file.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("byte header"), "{err}");
+    }
+
+    #[test]
+    fn dual_pooling_shares_one_forward_pass() {
+        let mut model = tiny_model();
+        model.set_metrics_enabled(true);
+        // Padding must not leak into either vector, nor normalization be forced
+        model.set_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::Fixed(16),
+            ..Default::default()
+        }));
+        model.set_normalize(false);
+
+        let (max, mean) = model.infer_dual_pooling("dense vector search").unwrap();
+
+        assert_eq!(model.metrics().unwrap().forward.count, 1);
+        let values = |tensor: &Tensor| tensor.to_vec2::<f32>().unwrap();
+        for pooling in [PoolingStrategy::MaxMasked, PoolingStrategy::MeanMasked] {
+            model.set_pooling(pooling);
+            let expected = model
+                .infer_sentence_embedding("dense vector search")
+                .unwrap();
+            let actual = match pooling {
+                PoolingStrategy::MaxMasked => &max,
+                _ => &mean,
+            };
+            assert_eq!(values(actual), values(&expected), "{pooling:?}");
+        }
+        assert_ne!(values(&max), values(&mean));
+    }
+
+    #[test]
+    fn token_embeddings_line_up_with_tokens() {
+        let model = tiny_model();
+
+        let (embeddings, tokens) = model.infer_token_embeddings("dense vector search").unwrap();
+
+        assert_eq!(tokens, ["[CLS]", "dense", "vector", "search", "[SEP]"]);
+        assert_eq!(embeddings.dims(), &[1, tokens.len(), HIDDEN_SIZE]);
+    }
+
+    #[test]
+    fn maxsim_prefers_overlapping_tokens() {
+        let device = Device::Cpu;
+        let model = tiny_model();
+        // One orthogonal direction per "word"
+        let words = Tensor::eye(HIDDEN_SIZE, DType::F32, &device).unwrap();
+        let tokens = |ids: &[u32]| {
+            let ids = Tensor::new(ids, &device).unwrap();
+            words.index_select(&ids, 0).unwrap()
+        };
+
+        let query = tokens(&[0, 1, 2]).unsqueeze(0).unwrap();
+        let docs = [tokens(&[3, 4, 5, 6]), tokens(&[7, 0, 1]), tokens(&[0, 8])];
+
+        let results = model.score_maxsim(&query, &docs, 3).unwrap();
+
+        assert_eq!(results, [(1, 2.), (2, 1.), (0, 0.)]);
+    }
+
+    #[test]
+    fn long_documents_are_embedded_in_overlapping_windows() {
+        let model = tiny_model();
+        // Every word is a single token
+        let document = ["dense", "retrieval", "survey", "of", "text"]
+            .repeat(60)
+            .join(" ");
+        let len = 300;
+        assert!(len > model.max_length());
+
+        for (window, stride) in [(100, 50), (64, 64), (120, 7)] {
+            let embeddings = model
+                .embed_long_document(&document, window, stride)
+                .unwrap();
+            let expected = (len - window).div_ceil(stride) + 1;
+            assert_eq!(
+                embeddings.dims(),
+                &[expected, HIDDEN_SIZE],
+                "{window}/{stride}"
+            );
+        }
+
+        let short = model
+            .embed_long_document("dense retrieval", 100, 50)
+            .unwrap();
+        assert_eq!(
+            short.to_vec2::<f32>().unwrap(),
+            model
+                .create_embeddings(vec!["dense retrieval".to_string()])
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap()
+        );
+        assert!(model.embed_long_document(&document, 50, 51).is_err());
+        assert!(model.embed_long_document(&document, 500, 50).is_err());
+    }
+
+    #[test]
+    fn progress_is_reported_per_batch() {
+        let model = tiny_model();
+        let sentences = vec!["graph theory".to_string(); 25];
+
+        let mut calls = Vec::new();
+        let embeddings = model
+            .create_embeddings_with_progress(sentences, 10, |processed, total| {
+                calls.push((processed, total))
+            })
+            .unwrap();
+
+        assert_eq!(embeddings.dims(), &[25, HIDDEN_SIZE]);
+        assert_eq!(calls, [(10, 25), (20, 25), (25, 25)]);
+    }
+
+    #[test]
+    fn embeddings_load_from_npy() {
+        let device = Device::Cpu;
+        let dir = temp_dir("embeddings_npy");
+        let mut model = tiny_model();
+
+        let path = dir.join("embeddings.npy");
+        let embeddings = padded_embeddings(&[&[0.5, -1., 2.], &[3., 0.25, -4.]]);
+        embeddings.write_npy(&path).unwrap();
+        model.load_embeddings_npy(path.to_str().unwrap()).unwrap();
+        assert_eq!(model.embeddings().dims(), &[2, HIDDEN_SIZE]);
+        assert_eq!(
+            model.embeddings().to_vec2::<f32>().unwrap(),
+            embeddings.to_vec2::<f32>().unwrap()
+        );
+
+        let path = dir.join("narrow.npy");
+        Tensor::new(&[[0.5f32, -1., 2.], [3., 0.25, -4.]], &device)
+            .unwrap()
+            .write_npy(&path)
+            .unwrap();
+        let err = model
+            .load_embeddings_npy(path.to_str().unwrap())
+            .unwrap_err();
+        assert!(err.to_string().contains("hidden size 3"), "{err}");
+
+        let path = dir.join("flat.npy");
+        Tensor::new(&[1f32, 2.], &device)
+            .unwrap()
+            .write_npy(&path)
+            .unwrap();
+        let err = model
+            .load_embeddings_npy(path.to_str().unwrap())
+            .unwrap_err();
+        assert!(err.to_string().contains("[n, hidden]"), "{err}");
+
+        let path = dir.join("ints.npy");
+        Tensor::new(&[[1u32, 2]], &device)
+            .unwrap()
+            .write_npy(&path)
+            .unwrap();
+        let err = model
+            .load_embeddings_npy(path.to_str().unwrap())
+            .unwrap_err();
+        assert!(err.to_string().contains("must be floats"), "{err}");
+    }
+
+    #[test]
+    fn index_jsonl_keeps_ids_with_embeddings() {
+        let dir = temp_dir("index_jsonl");
+        let path = dir.join("corpus.jsonl");
+        let lines = [
+            r#"{"id": "survey", "text": "deep learning survey"}"#,
+            r#"{"id": 7, "text": "graph neural network"}"#,
+            "not json",
+            r#"{"id": "missing-text"}"#,
+            "",
+            r#"{"id": "driving", "text": "autonomous driving model"}"#,
+        ];
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        let mut model = tiny_model();
+
+        model
+            .index_jsonl(path.to_str().unwrap(), "text", "id")
+            .unwrap();
+
+        assert_eq!(model.embeddings().dims(), &[3, HIDDEN_SIZE]);
+        assert_eq!(model.doc_ids().unwrap(), ["survey", "7", "driving"]);
+        // Same token count as every text, so padding leaves the embeddings unchanged
+        let query = model.create_embeddings(vec!["autonomous driving model".to_string()]);
+        let results = model.search(query.unwrap(), 1).unwrap();
+        assert_eq!(results[0].0, "driving");
+    }
+
+    #[test]
+    fn embeddings_can_be_output_in_half_precision() {
+        let mut model = tiny_model();
+        model.set_output_dtype(Some(DType::F16));
+        let sentences: Vec<String> = [
+            "deep learning survey",
+            "graph neural network",
+            "dense vector search",
+        ]
+        .iter()
+        .map(|sentence| sentence.to_string())
+        .collect();
+
+        let embeddings = model.create_embeddings(sentences).unwrap();
+        assert_eq!(embeddings.dtype(), DType::F16);
+
+        model.add_embeddings(embeddings.clone()).unwrap();
+        let query = embeddings.get(2).unwrap().unsqueeze(0).unwrap();
+        let query = query.to_dtype(DType::F32).unwrap();
+        let results = model.score_vector_similarity(query, 3).unwrap();
+        assert_eq!(results[0].index, 2);
+        assert!((results[0].score - 1.).abs() < 1e-2, "{results:?}");
+    }
+
+    #[test]
+    fn empty_batches_fail_cleanly() {
+        let model = tiny_model();
+
+        let err = model.create_embeddings(vec![]).unwrap_err();
+        assert!(err.to_string().contains("empty batch"), "{err}");
+        assert!(model.create_embeddings_chunked(vec![], 8).is_err());
+    }
+
+    #[test]
+    fn warmup_runs_before_any_inference() {
+        let mut model = tiny_model();
+        model.set_query_cache_size(4);
+        model.warmup().unwrap();
+        assert_eq!(model.query_cache_stats().unwrap().len, 0);
+
+        model.add_embeddings(random_embeddings(10)).unwrap();
+        model.warmup().unwrap();
+        let results = model.search_text("the cat sat", 3).unwrap();
+        assert_eq!(results.len(), 3);
+    }
+
+    #[test]
+    fn empty_sentences_embed_the_special_tokens() {
+        let model = tiny_model();
+
+        let (query, stats) = model.infer_with_stats("").unwrap();
+        assert_eq!(stats.token_count, 2);
+        let query = query.to_vec2::<f32>().unwrap();
+        assert!(query.iter().flatten().all(|value| value.is_finite()));
+
+        let embeddings = model
+            .create_embeddings(vec!["".to_string(), "   ".to_string()])
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        assert_eq!(embeddings[0], embeddings[1]);
+        assert_eq!(embeddings[0], query[0]);
+    }
+
+    #[test]
+    fn special_tokens_can_be_left_out() {
+        let mut model = tiny_model();
+        let (with_special, stats) = model.infer_with_stats("the cat sat on").unwrap();
+        let with_special_count = stats.token_count;
+        let with_special_batch = model
+            .create_embeddings(vec!["the cat sat on".to_string()])
+            .unwrap();
+
+        model.set_add_special_tokens(false);
+        let (without_special, stats) = model.infer_with_stats("the cat sat on").unwrap();
+        assert_eq!(with_special_count, stats.token_count + 2);
+        let (_, tokens) = model.infer_token_embeddings("the cat sat on").unwrap();
+        assert_eq!(tokens, ["the", "cat", "sat", "on"]);
+        assert_ne!(
+            with_special.to_vec2::<f32>().unwrap(),
+            without_special.to_vec2::<f32>().unwrap()
+        );
+
+        let (token_ids, _) = model
+            .encode_batch_padded(vec!["the cat sat on".to_string()])
+            .unwrap();
+        assert_eq!(token_ids.dims(), [1, 4]);
+        let without_special_batch = model
+            .create_embeddings(vec!["the cat sat on".to_string()])
+            .unwrap();
+        assert_ne!(
+            with_special_batch.to_vec2::<f32>().unwrap(),
+            without_special_batch.to_vec2::<f32>().unwrap()
+        );
+    }
+
+    #[test]
+    fn only_a_missing_index_falls_back_to_the_single_weights_file() {
+        use hf_hub::api::sync::ApiError;
+
+        let model_dir = tiny_model_dir("hub_fallback");
+        let cache = Cache::new(temp_dir("hub_fallback_cache"));
+        let download = |index_error: fn() -> ApiError| {
+            ModelFiles::download_with(
+                "models-hf-tests/no-such-model",
+                "main",
+                DEFAULT_CONFIG_FILENAME,
+                &cache,
+                |file| {
+                    Some(match file {
+                        WEIGHTS_INDEX => Err(index_error()),
+                        file => Ok(model_dir.join(file)),
+                    })
+                },
+            )
+        };
+
+        let files = download(|| {
+            let response = ureq::Response::new(404, "Not Found", "").unwrap();
+            ApiError::RequestError(Box::new(ureq::Error::Status(404, response)))
+        })
+        .unwrap();
+        assert_eq!(files.weights, [model_dir.join("model.safetensors")]);
+
+        let err = download(|| {
+            let err = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+            ApiError::IoError(err)
+        })
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(
+            err.starts_with(&format!(
+                "Could not fetch {WEIGHTS_INDEX} of models-hf-tests/no-such-model from the HF \
+                 Hub: I/O error refused."
+            )),
+            "{err}"
+        );
+        assert!(err.contains(&cache.path().display().to_string()), "{err}");
+        assert!(err.contains("load_from_path"), "{err}");
+    }
+
+    #[test]
+    fn offline_mode_reads_only_the_cache() {
+        let model_dir = tiny_model_dir("offline_model");
+        // Laid out like the HF cache, for a repo that doesn't exist on the Hub
+        let cache_dir = temp_dir("offline_cache");
+        let repo_dir = cache_dir.join("models--models-hf-tests--cached-model");
+        let snapshot = repo_dir.join("snapshots").join("0123abcd");
+        std::fs::create_dir_all(&snapshot).unwrap();
+        std::fs::create_dir_all(repo_dir.join("refs")).unwrap();
+        std::fs::write(repo_dir.join("refs").join("main"), "0123abcd").unwrap();
+        for file in ["config.json", "tokenizer.json", "model.safetensors"] {
+            std::fs::copy(model_dir.join(file), snapshot.join(file)).unwrap();
+        }
+        let cache = Cache::new(cache_dir);
+
+        let files = ModelFiles::download_with_cache(
+            "models-hf-tests/cached-model",
+            "main",
+            DEFAULT_CONFIG_FILENAME,
+            cache.clone(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(files.config, snapshot.join("config.json"));
+        assert_eq!(files.tokenizer, snapshot.join("tokenizer.json"));
+        assert_eq!(files.weights, [snapshot.join("model.safetensors")]);
+
+        let err = ModelFiles::download_with_cache(
+            "models-hf-tests/cached-model",
+            "v2",
+            DEFAULT_CONFIG_FILENAME,
+            cache,
+            true,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("HF_HUB_OFFLINE is set"), "{err}");
+    }
+
+    #[test]
+    fn model_files_are_checked_against_expected_sha256() {
+        let model_dir = tiny_model_dir("expected_sha256");
+
+        let fixture = model_dir.join("fixture.txt");
+        std::fs::write(&fixture, "abc").unwrap();
+        assert_eq!(
+            sha256_file(&fixture).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let config_sha256 = sha256_file(&model_dir.join("config.json")).unwrap();
+        let builder = BertInferenceModel::builder()
+            .model_dir(&model_dir)
+            .dtype(DTYPE);
+        builder
+            .clone()
+            .expected_sha256("config.json", config_sha256.to_uppercase())
+            .build()
+            .unwrap();
+
+        let err = builder
+            .expected_sha256("config.json", "00".repeat(32))
+            .build()
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string()
+                .starts_with("SHA-256 mismatch for config.json"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn named_embedding_sets_are_searched_independently() {
+        let dir = tiny_model_dir("embedding_sets");
+        let titles = random_embeddings(10);
+        let bodies = random_embeddings(20);
+        let titles_file = dir.join("titles.safetensors");
+        let bodies_file = dir.join("bodies.safetensors");
+        safetensors::save(&HashMap::from([("emb", titles.clone())]), &titles_file).unwrap();
+        safetensors::save(&HashMap::from([("emb", bodies.clone())]), &bodies_file).unwrap();
+
+        let model = BertInferenceModel::builder()
+            .model_dir(&dir)
+            .dtype(DTYPE)
+            .embedding_set("titles", titles_file.to_str().unwrap(), "emb")
+            .embedding_set("bodies", bodies_file.to_str().unwrap(), "emb")
+            .build()
+            .unwrap();
+        assert_eq!(model.embedding_set_names(), ["bodies", "titles"]);
+
+        // A row of each set ranks first in its own set only
+        let query = bodies.get(15).unwrap().unsqueeze(0).unwrap();
+        let in_bodies = model
+            .score_set_similarity("bodies", query.clone(), 20)
+            .unwrap();
+        let in_titles = model.score_set_similarity("titles", query, 20).unwrap();
+        assert_eq!(in_bodies.len(), 20);
+        assert_eq!(in_titles.len(), 10);
+        assert_eq!(in_bodies[0].index, 15);
+        assert!((in_bodies[0].score - 1.).abs() < 1e-5);
+        assert!(in_titles[0].score < 1. - 1e-3);
+
+        let query = titles.get(3).unwrap().unsqueeze(0).unwrap();
+        let in_titles = model.score_set_similarity("titles", query, 1).unwrap();
+        assert_eq!(in_titles[0].index, 3);
+
+        let err = model
+            .score_set_similarity("abstracts", random_embeddings(1), 1)
+            .unwrap_err();
+        assert!(err.to_string().contains("\"abstracts\""), "{err}");
+    }
+
+    #[test]
+    fn similarity_matrix_is_symmetric_with_unit_diagonal() {
+        let mut model = tiny_model();
+        // Unnormalized rows still get cosine similarities
+        model
+            .add_embeddings((random_embeddings(6) * 3.).unwrap())
+            .unwrap();
+
+        let matrix = model.similarity_matrix().unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(matrix.len(), 6);
+        for (i, row) in matrix.iter().enumerate() {
+            assert_eq!(row.len(), 6);
+            assert!((row[i] - 1.).abs() < 1e-5, "diagonal {}", row[i]);
+            for (j, similarity) in row.iter().enumerate() {
+                assert!((similarity - matrix[j][i]).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn forward_raw_returns_unpooled_hidden_states() {
+        let model = tiny_model();
+        let tokens = model.tokenizer.encode("the cat sat on", true).unwrap();
+        let token_ids = Tensor::new(tokens.get_ids(), &Device::Cpu).unwrap();
+        // The same sentence twice makes a batch of 2
+        let token_ids = Tensor::stack(&[&token_ids, &token_ids], 0).unwrap();
+        let token_type_ids = token_ids.zeros_like().unwrap();
+
+        let hidden_states = model.forward_raw(&token_ids, &token_type_ids).unwrap();
+        assert_eq!(hidden_states.dims(), [2, tokens.len(), HIDDEN_SIZE]);
+
+        let (token_embeddings, _tokens) = model.infer_token_embeddings("the cat sat on").unwrap();
+        assert_eq!(
+            hidden_states.get(1).unwrap().to_vec2::<f32>().unwrap(),
+            token_embeddings
+                .squeeze(0)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn sentence_pairs_get_segment_type_ids() {
+        let model = tiny_model();
+
+        let (_tokens, _token_ids, token_type_ids) = model.encode_input("the cat sat").unwrap();
+        let type_ids = token_type_ids.squeeze(0).unwrap().to_vec1::<u32>().unwrap();
+        assert!(type_ids.iter().all(|&type_id| type_id == 0));
+
+        let (tokens, _token_ids, token_type_ids) =
+            model.encode_input(("the cat sat", "on the mat")).unwrap();
+        let type_ids = token_type_ids.squeeze(0).unwrap().to_vec1::<u32>().unwrap();
+        assert_eq!(type_ids.len(), tokens.len());
+        assert!(
+            type_ids.contains(&0) && type_ids.contains(&1),
+            "{type_ids:?}"
+        );
+        // The second segment follows the first
+        assert!(type_ids.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        let embedding = model
+            .infer_pair_embedding("the cat sat", "on the mat")
+            .unwrap();
+        assert_eq!(embedding.dims(), [1, HIDDEN_SIZE]);
+    }
+
+    #[test]
+    fn metrics_count_inference_stages() {
+        let mut model = tiny_model();
+        assert_eq!(model.metrics(), None);
+        model.infer_sentence_embedding("the cat sat").unwrap();
+
+        model.set_metrics_enabled(true);
+        assert_eq!(model.metrics(), Some(Metrics::default()));
+        for sentence in ["the cat sat", "on the mat", "a dog ran"] {
+            model.infer_sentence_embedding(sentence).unwrap();
+        }
+        let embeddings = model
+            .create_embeddings(vec!["the cat".to_string(), "a dog".to_string()])
+            .unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        model.search_text("the cat", 1).unwrap();
+
+        let metrics = model.metrics().unwrap();
+        // 3 sentences, 1 batch and 1 query
+        assert_eq!(metrics.forward.count, 5);
+        assert_eq!(metrics.tokenization.count, 5);
+        assert_eq!(metrics.scoring.count, 1);
+        assert!(metrics.forward.max <= metrics.forward.total);
+        assert!(metrics.forward.mean().unwrap() <= metrics.forward.max);
+    }
+
+    #[test]
+    fn query_of_another_hidden_size_is_rejected() {
+        let mut model = tiny_model();
+        model.add_embeddings(random_embeddings(5)).unwrap();
+        let query = Tensor::ones((1, 2 * HIDDEN_SIZE), DType::F32, &Device::Cpu).unwrap();
+
+        let err = model.score_vector_similarity(query.clone(), 3).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Query has hidden size {} but the stored embeddings have hidden size {}, \
+                 were they created with another model?",
+                2 * HIDDEN_SIZE,
+                HIDDEN_SIZE
+            )
+        );
+        assert!(model.score_batch(query.clone(), 3).is_err());
+        assert!(model.score_vector_l2(query, 3).is_err());
     }
 }
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+/// Term saturation of [`Bm25`]: how quickly repeating a term stops raising the score.
+pub const DEFAULT_K1: f32 = 1.2;
+/// Length normalization of [`Bm25`], from 0 (none) to 1 (full).
+pub const DEFAULT_B: f32 = 0.75;
+
+/// Okapi BM25 lexical scores over a fixed set of documents, to catch the exact keyword matches
+/// dense retrieval misses. Text is lowercased and split on anything but letters and digits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bm25 {
+    k1: f32,
+    b: f32,
+    /// Count of every term of each document.
+    term_freqs: Vec<HashMap<String, u32>>,
+    doc_lengths: Vec<usize>,
+    /// Number of documents each term appears in.
+    doc_freqs: HashMap<String, usize>,
+    avg_doc_length: f32,
+}
+
+impl Bm25 {
+    /// Indexes `documents` with the usual [`DEFAULT_K1`] and [`DEFAULT_B`].
+    pub fn new(documents: &[String]) -> Self {
+        Self::with_params(documents, DEFAULT_K1, DEFAULT_B)
+    }
+
+    pub fn with_params(documents: &[String], k1: f32, b: f32) -> Self {
+        let mut term_freqs = Vec::with_capacity(documents.len());
+        let mut doc_lengths = Vec::with_capacity(documents.len());
+        let mut doc_freqs: HashMap<String, usize> = HashMap::new();
+        for document in documents {
+            let mut freqs: HashMap<String, u32> = HashMap::new();
+            let terms = terms(document);
+            doc_lengths.push(terms.len());
+            for term in terms {
+                *freqs.entry(term).or_default() += 1;
+            }
+            for term in freqs.keys() {
+                *doc_freqs.entry(term.clone()).or_default() += 1;
+            }
+            term_freqs.push(freqs);
+        }
+        let avg_doc_length = match documents.is_empty() {
+            true => 0.,
+            false => doc_lengths.iter().sum::<usize>() as f32 / documents.len() as f32,
+        };
+
+        Self {
+            k1,
+            b,
+            term_freqs,
+            doc_lengths,
+            doc_freqs,
+            avg_doc_length,
+        }
+    }
+
+    /// Number of documents.
+    pub fn len(&self) -> usize {
+        self.doc_lengths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.doc_lengths.is_empty()
+    }
+
+    /// BM25 score of every document for `query`, in document order. Documents sharing no term
+    /// with the query score 0.
+    pub fn scores(&self, query: &str) -> Vec<f32> {
+        let n_docs = self.len() as f32;
+        let mut scores = vec![0.; self.len()];
+        for term in terms(query) {
+            let Some(&doc_freq) = self.doc_freqs.get(&term) else {
+                continue;
+            };
+            // The "plus one" variant, which keeps terms found in most documents from going negative
+            let idf = ((n_docs - doc_freq as f32 + 0.5) / (doc_freq as f32 + 0.5) + 1.).ln();
+            for (score, (freqs, &length)) in scores
+                .iter_mut()
+                .zip(self.term_freqs.iter().zip(&self.doc_lengths))
+            {
+                let Some(&freq) = freqs.get(&term) else {
+                    continue;
+                };
+                let freq = freq as f32;
+                let length_norm = 1. - self.b + self.b * length as f32 / self.avg_doc_length;
+                *score += idf * freq * (self.k1 + 1.) / (freq + self.k1 * length_norm);
+            }
+        }
+
+        scores
+    }
+}
+
+/// Lowercased runs of letters and digits.
+fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rare_and_repeated_terms_score_higher() {
+        let documents: Vec<String> = [
+            "Graph neural networks for graph search",
+            "A survey of graph theory",
+            "Neural networks, a survey",
+            "Autonomous driving",
+        ]
+        .iter()
+        .map(|document| document.to_string())
+        .collect();
+        let bm25 = Bm25::new(&documents);
+        assert_eq!(bm25.len(), 4);
+
+        let scores = bm25.scores("GRAPH search");
+        // Repeats "graph" and is the only one with "search"
+        assert!(scores[0] > scores[1], "{scores:?}");
+        assert_eq!(scores[2], 0.);
+        assert_eq!(scores[3], 0.);
+        // "driving" is in one document, "survey" in two
+        let scores = bm25.scores("driving survey");
+        assert!(scores[3] > scores[1], "{scores:?}");
+        assert!(bm25.scores("unknown").iter().all(|&score| score == 0.));
+    }
+}
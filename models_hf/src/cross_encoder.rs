@@ -0,0 +1,200 @@
+use std::path::Path;
+
+use candle::{DType, Device, Tensor};
+use candle_nn::{Linear, Module, VarBuilder};
+use candle_transformers::models::bert::{BertModel, Config};
+use tokenizers::{Tokenizer, TruncationParams};
+
+use crate::bert::{select_top_k, ModelFiles, DEFAULT_CONFIG_FILENAME};
+
+/// A BERT cross-encoder: scores a `(query, document)` pair by reading both at once, which is
+/// slower but more precise than comparing separately computed embeddings.
+///
+/// Meant as a second stage: retrieve candidates with
+/// [`BertInferenceModel`](crate::bert::BertInferenceModel), then [`rerank`](Self::rerank) the
+/// best few of them.
+pub struct CrossEncoderModel {
+    model: BertModel,
+    /// Dense + tanh over the `[CLS]` token, as in the HF `BertPooler`.
+    pooler: Linear,
+    /// Maps the pooled output to the relevance logits; the first one is used as the score.
+    classifier: Linear,
+    tokenizer: Tokenizer,
+    device: Device,
+    max_length: usize,
+}
+
+impl CrossEncoderModel {
+    /// Loads a sequence-classification BERT checkpoint (e.g.
+    /// `cross-encoder/ms-marco-MiniLM-L-6-v2`) from the HF Hub, or from its local cache.
+    pub fn load(
+        model_name: &str,
+        revision: &str,
+        max_length: usize,
+        device: Device,
+        dtype: DType,
+    ) -> anyhow::Result<Self> {
+        let files = ModelFiles::download(model_name, revision, DEFAULT_CONFIG_FILENAME)?;
+        Self::load_from_files(&files, max_length, device, dtype)
+    }
+
+    /// Like [`Self::load`], but reads `config.json`, `tokenizer.json` and `model.safetensors`
+    /// from `model_dir`.
+    pub fn load_from_path(
+        model_dir: impl AsRef<Path>,
+        max_length: usize,
+        device: Device,
+        dtype: DType,
+    ) -> anyhow::Result<Self> {
+        let files = ModelFiles::from_dir(model_dir.as_ref(), DEFAULT_CONFIG_FILENAME)?;
+        Self::load_from_files(&files, max_length, device, dtype)
+    }
+
+    fn load_from_files(
+        files: &ModelFiles,
+        max_length: usize,
+        device: Device,
+        dtype: DType,
+    ) -> anyhow::Result<Self> {
+        let config = std::fs::read_to_string(&files.config)?;
+        // `Config` keeps its fields private, so read the head sizes from the raw JSON
+        let raw_config: serde_json::Value = serde_json::from_str(&config)?;
+        let config: Config = serde_json::from_str(&config)?;
+        let hidden_size = raw_config["hidden_size"]
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("config.json has no hidden_size"))?
+            as usize;
+        let num_labels = raw_config["id2label"]
+            .as_object()
+            .map_or(1, |labels| labels.len());
+
+        let tokenizer = Tokenizer::from_file(&files.tokenizer).map_err(anyhow::Error::msg)?;
+
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&files.weights, dtype, &device)? };
+        let model = BertModel::load(vb.clone(), &config)?;
+        let pooler = candle_nn::linear(hidden_size, hidden_size, vb.pp("bert.pooler.dense"))
+            .or_else(|_| candle_nn::linear(hidden_size, hidden_size, vb.pp("pooler.dense")))?;
+        let classifier = candle_nn::linear(hidden_size, num_labels, vb.pp("classifier"))?;
+
+        Self::from_parts(model, pooler, classifier, tokenizer, device, max_length)
+    }
+
+    /// Assembles a cross-encoder from already loaded parts.
+    pub fn from_parts(
+        model: BertModel,
+        pooler: Linear,
+        classifier: Linear,
+        mut tokenizer: Tokenizer,
+        device: Device,
+        max_length: usize,
+    ) -> anyhow::Result<Self> {
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length,
+                ..Default::default()
+            }))
+            .map_err(anyhow::Error::msg)?;
+
+        Ok(Self {
+            model,
+            pooler,
+            classifier,
+            tokenizer,
+            device,
+            max_length,
+        })
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    /// Returns the relevance score of `document` for `query`; higher is more relevant.
+    pub fn score(&self, query: &str, document: &str) -> anyhow::Result<f32> {
+        let tokens = self
+            .tokenizer
+            .encode((query, document), true)
+            .map_err(anyhow::Error::msg)?;
+        let token_ids = Tensor::new(tokens.get_ids(), &self.device)?.unsqueeze(0)?;
+        let token_type_ids = Tensor::new(tokens.get_type_ids(), &self.device)?.unsqueeze(0)?;
+
+        let hidden_states = self.model.forward(&token_ids, &token_type_ids)?;
+        let cls = hidden_states.get_on_dim(1, 0)?;
+        let pooled = self.pooler.forward(&cls)?.tanh()?;
+        let logits = self.classifier.forward(&pooled)?;
+
+        Ok(logits
+            .get_on_dim(1, 0)?
+            .to_dtype(DType::F32)?
+            .squeeze(0)?
+            .to_scalar::<f32>()?)
+    }
+
+    /// Re-scores each `(id, text)` candidate against `query` and returns the `top_k` best
+    /// `(id, score)` pairs, highest score first.
+    ///
+    /// NOTE: pairs go through the model one at a time, since the underlying `BertModel` takes no
+    /// attention mask and padding a batch would change the scores. Keep the candidate list short.
+    pub fn rerank(
+        &self,
+        query: &str,
+        candidates: &[(usize, String)],
+        top_k: usize,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        let scores = candidates
+            .iter()
+            .map(|(id, text)| Ok((*id, self.score(query, text)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(select_top_k(scores, top_k))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_nn::VarMap;
+    use candle_transformers::models::bert::DTYPE;
+
+    use super::*;
+    use crate::bert::tests::{tiny_config, tiny_tokenizer, HIDDEN_SIZE};
+
+    fn tiny_cross_encoder() -> CrossEncoderModel {
+        let device = Device::Cpu;
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DTYPE, &device);
+        let config: Config = serde_json::from_value(tiny_config()).unwrap();
+        let model = BertModel::load(vb.clone(), &config).unwrap();
+        let pooler = candle_nn::linear(HIDDEN_SIZE, HIDDEN_SIZE, vb.pp("pooler.dense")).unwrap();
+        let classifier = candle_nn::linear(HIDDEN_SIZE, 1, vb.pp("classifier")).unwrap();
+
+        CrossEncoderModel::from_parts(model, pooler, classifier, tiny_tokenizer(), device, 64)
+            .unwrap()
+    }
+
+    #[test]
+    fn rerank_sorts_by_cross_encoder_scores() {
+        let model = tiny_cross_encoder();
+        let query = "the cat sat on the mat";
+        let candidates: Vec<(usize, String)> = [
+            "the dog ran",
+            "a cat sat on a mat",
+            "quick brown fox",
+            "the lazy dog sat",
+            "document embedding",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(index, text)| (index * 10, text.to_string()))
+        .collect();
+
+        let mut expected: Vec<(usize, f32)> = candidates
+            .iter()
+            .map(|(id, text)| (*id, model.score(query, text).unwrap()))
+            .collect();
+        expected.sort_by(|a, b| b.1.total_cmp(&a.1));
+        expected.truncate(3);
+
+        let reranked = model.rerank(query, &candidates, 3).unwrap();
+        assert_eq!(reranked, expected);
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::Context;
+use candle::{DType, Tensor};
+
+use crate::bert::{check_query_hidden_size, cosine_scores, select_top_k, BertInferenceModel};
+
+/// A model turning text into embeddings, so the search helpers of this module work with any
+/// candle encoder (RoBERTa, DistilBERT, ...) and not only [`BertInferenceModel`].
+pub trait Embedder {
+    /// Embeds `text` into a `[1, hidden]` tensor.
+    fn embed(&self, text: &str) -> anyhow::Result<Tensor>;
+
+    /// Embeds `texts` into a `[texts.len(), hidden]` tensor, in order.
+    fn embed_batch(&self, texts: Vec<String>) -> anyhow::Result<Tensor>;
+}
+
+impl Embedder for BertInferenceModel {
+    /// Embeds `text` as a query, with [`BertInferenceModel::infer_sentence_embedding`].
+    fn embed(&self, text: &str) -> anyhow::Result<Tensor> {
+        self.infer_sentence_embedding(text)
+    }
+
+    /// Embeds `texts` as passages, with [`BertInferenceModel::create_embeddings`].
+    fn embed_batch(&self, texts: Vec<String>) -> anyhow::Result<Tensor> {
+        self.create_embeddings(texts)
+    }
+}
+
+/// Embeds `query` with `embedder` and returns the `(row, score)` of the `top_k` rows of
+/// `corpus`, a `[n, hidden]` tensor made by the same embedder, most similar to it by cosine
+/// similarity. Highest score first, ties in row order.
+pub fn search_text<E: Embedder + ?Sized>(
+    embedder: &E,
+    corpus: &Tensor,
+    query: &str,
+    top_k: usize,
+) -> anyhow::Result<Vec<(usize, f32)>> {
+    anyhow::ensure!(
+        corpus.rank() == 2,
+        "The corpus must be a [n, hidden] tensor, got shape {:?}",
+        corpus.dims()
+    );
+    let query = embedder.embed(query)?;
+    check_query_hidden_size(corpus, &query)?;
+    let query = query.to_device(corpus.device())?.to_dtype(corpus.dtype())?;
+    let scores = cosine_scores(corpus, &query)?;
+
+    Ok(select_top_k(scores.into_iter().enumerate(), top_k))
+}
+
+/// Embeds `texts` with `embedder` and returns the `(position, score)` of the `top_k` of them most
+/// similar to `query`, e.g. to rank a handful of candidates without keeping a corpus around.
+pub fn rank_texts<E: Embedder + ?Sized>(
+    embedder: &E,
+    query: &str,
+    texts: Vec<String>,
+    top_k: usize,
+) -> anyhow::Result<Vec<(usize, f32)>> {
+    anyhow::ensure!(!texts.is_empty(), "No texts to rank");
+    let corpus = embedder
+        .embed_batch(texts)
+        .context("Error embedding the texts to rank")?;
+
+    search_text(embedder, &corpus, query, top_k)
+}
+
+/// Cosine similarity between the embeddings of `a` and `b`.
+pub fn text_similarity<E: Embedder + ?Sized>(
+    embedder: &E,
+    a: &str,
+    b: &str,
+) -> anyhow::Result<f32> {
+    let a = embedder.embed(a)?.to_dtype(DType::F32)?;
+    let b = embedder.embed(b)?.to_dtype(DType::F32)?;
+    check_query_hidden_size(&a, &b)?;
+
+    Ok(cosine_scores(&a, &b)?[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use candle::Device;
+
+    use super::*;
+    use crate::bert::tests::tiny_model;
+
+    /// Embeds text as its letter counts, no model involved.
+    struct LetterCounts;
+
+    impl Embedder for LetterCounts {
+        fn embed(&self, text: &str) -> anyhow::Result<Tensor> {
+            let mut counts = [0f32; 26];
+            for letter in text.bytes().filter(u8::is_ascii_lowercase) {
+                counts[(letter - b'a') as usize] += 1.;
+            }
+            Ok(Tensor::new(&[counts], &Device::Cpu)?)
+        }
+
+        fn embed_batch(&self, texts: Vec<String>) -> anyhow::Result<Tensor> {
+            let rows = texts
+                .iter()
+                .map(|text| self.embed(text))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Ok(Tensor::cat(&rows, 0)?)
+        }
+    }
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    #[test]
+    fn search_runs_on_any_embedder() {
+        let corpus = LetterCounts
+            .embed_batch(texts(&["dog", "cat", "bird"]))
+            .unwrap();
+
+        let results = search_text(&LetterCounts, &corpus, "act", 2).unwrap();
+        assert_eq!(results[0].0, 1);
+        assert!((results[0].1 - 1.).abs() < 1e-6);
+        assert_eq!(results.len(), 2);
+
+        let ranked = rank_texts(&LetterCounts, "god", texts(&["bird", "dog"]), 1).unwrap();
+        assert_eq!(ranked[0].0, 1);
+        assert_eq!(text_similarity(&LetterCounts, "abc", "xyz").unwrap(), 0.);
+        // A corpus from another embedder is caught rather than failing inside the matmul
+        let wider = Tensor::zeros((3, 27), DType::F32, &Device::Cpu).unwrap();
+        assert!(search_text(&LetterCounts, &wider, "cat", 1).is_err());
+    }
+
+    #[test]
+    fn bert_model_is_an_embedder() {
+        let model = tiny_model();
+        let corpus = model
+            .embed_batch(texts(&["graph neural network", "autonomous driving"]))
+            .unwrap();
+
+        let embedder: &dyn Embedder = &model;
+        let results = search_text(embedder, &corpus, "autonomous driving", 2).unwrap();
+        assert_eq!(results.len(), 2);
+        let similarity = text_similarity(embedder, "deep learning", "deep learning").unwrap();
+        assert!((similarity - 1.).abs() < 1e-5, "{similarity}");
+    }
+}
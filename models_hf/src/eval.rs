@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+
+use crate::bert::BertInferenceModel;
+
+/// A query of an evaluation set, with the IDs of the documents relevant to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabeledQuery {
+    pub query: String,
+    pub relevant: HashSet<String>,
+}
+
+impl LabeledQuery {
+    pub fn new(
+        query: impl Into<String>,
+        relevant: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        Self {
+            query: query.into(),
+            relevant: relevant.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// Mean, over `queries`, of the fraction of their relevant documents found in the top `k`
+/// results of [`BertInferenceModel::search_text`]. The model needs document IDs.
+pub fn recall_at_k(
+    model: &BertInferenceModel,
+    queries: &[LabeledQuery],
+    k: usize,
+) -> anyhow::Result<f32> {
+    mean_over_queries(model, queries, k, recall)
+}
+
+/// Mean, over `queries`, of the normalized discounted cumulative gain of the top `k` results of
+/// [`BertInferenceModel::search_text`], with binary relevance: 1 when the ranking puts every
+/// relevant document first. The model needs document IDs.
+pub fn ndcg_at_k(
+    model: &BertInferenceModel,
+    queries: &[LabeledQuery],
+    k: usize,
+) -> anyhow::Result<f32> {
+    mean_over_queries(model, queries, k, |retrieved, relevant| {
+        ndcg(retrieved, relevant, k)
+    })
+}
+
+fn mean_over_queries(
+    model: &BertInferenceModel,
+    queries: &[LabeledQuery],
+    k: usize,
+    metric: impl Fn(&[String], &HashSet<String>) -> f32,
+) -> anyhow::Result<f32> {
+    anyhow::ensure!(!queries.is_empty(), "No queries to evaluate");
+    anyhow::ensure!(k > 0, "k must be at least 1");
+
+    let mut total = 0.;
+    for labeled in queries {
+        let retrieved = model
+            .search_text(&labeled.query, k)?
+            .into_iter()
+            .map(|result| {
+                result
+                    .id
+                    .context("No document IDs set, use set_doc_ids first")
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        total += metric(&retrieved, &labeled.relevant);
+    }
+
+    Ok(total / queries.len() as f32)
+}
+
+/// Fraction of `relevant` found in `retrieved`, 0 when nothing is relevant.
+fn recall(retrieved: &[String], relevant: &HashSet<String>) -> f32 {
+    if relevant.is_empty() {
+        return 0.;
+    }
+    let found = retrieved.iter().filter(|id| relevant.contains(*id)).count();
+
+    found as f32 / relevant.len() as f32
+}
+
+/// DCG of the first `k` of `retrieved` over the DCG of the ideal ranking, 0 when nothing is
+/// relevant.
+fn ndcg(retrieved: &[String], relevant: &HashSet<String>, k: usize) -> f32 {
+    // Rank r (from 0) is discounted by log2(r + 2)
+    let discount = |rank: usize| 1. / (rank as f32 + 2.).log2();
+    let dcg: f32 = retrieved
+        .iter()
+        .take(k)
+        .enumerate()
+        .filter(|(_, id)| relevant.contains(*id))
+        .map(|(rank, _)| discount(rank))
+        .sum();
+    let ideal_dcg: f32 = (0..relevant.len().min(k)).map(discount).sum();
+
+    match ideal_dcg > 0. {
+        true => dcg / ideal_dcg,
+        false => 0.,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle::Tensor;
+
+    use super::*;
+    use crate::bert::tests::tiny_model;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn ndcg_matches_the_hand_computed_value() {
+        let relevant: HashSet<_> = ids(&["a", "c"]).into_iter().collect();
+
+        // DCG = 1 + 1/log2(4) = 1.5, ideal DCG = 1 + 1/log2(3)
+        let expected = 1.5 / (1. + 1. / 3f32.log2());
+        let ndcg_3 = ndcg(&ids(&["a", "b", "c"]), &relevant, 3);
+        assert!((ndcg_3 - expected).abs() < 1e-6, "{ndcg_3} vs {expected}");
+        assert_eq!(ndcg(&ids(&["a", "c", "b"]), &relevant, 3), 1.);
+        assert_eq!(ndcg(&ids(&["b", "d"]), &relevant, 2), 0.);
+        assert_eq!(recall(&ids(&["a", "b"]), &relevant), 0.5);
+    }
+
+    #[test]
+    fn queries_identical_to_documents_rank_them_first() {
+        let mut model = tiny_model();
+        let documents = [
+            "deep learning survey",
+            "graph neural network",
+            "autonomous driving",
+        ];
+        // Embedded one by one, so padding doesn't set them apart from the queries
+        let embeddings = documents
+            .iter()
+            .map(|document| model.infer_sentence_embedding(document))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        model
+            .add_embeddings(Tensor::cat(&embeddings, 0).unwrap())
+            .unwrap();
+        model
+            .set_doc_ids(ids(&["survey", "graph", "driving"]))
+            .unwrap();
+        let queries = [
+            LabeledQuery::new("deep learning survey", ["survey"]),
+            LabeledQuery::new("graph neural network", ["graph"]),
+            LabeledQuery::new("autonomous driving", ["driving", "survey"]),
+        ];
+
+        assert_eq!(ndcg_at_k(&model, &queries[..2], 1).unwrap(), 1.);
+        let recall_1 = recall_at_k(&model, &queries, 1).unwrap();
+        assert!((recall_1 - (1. + 1. + 0.5) / 3.).abs() < 1e-6, "{recall_1}");
+        assert_eq!(recall_at_k(&model, &queries, 3).unwrap(), 1.);
+    }
+
+    #[test]
+    fn evaluating_needs_document_ids() {
+        let mut model = tiny_model();
+        let embeddings = model
+            .infer_sentence_embedding("graph neural network")
+            .unwrap();
+        model.add_embeddings(embeddings).unwrap();
+        let queries = [LabeledQuery::new("graph neural network", ["graph"])];
+
+        assert!(recall_at_k(&model, &queries, 1).is_err());
+        assert!(ndcg_at_k(&model, &[], 1).is_err());
+    }
+}
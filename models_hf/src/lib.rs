@@ -1,4 +1,17 @@
+#[cfg(feature = "hnsw")]
+pub mod ann;
 pub mod bert;
+pub mod bm25;
+pub mod cross_encoder;
+pub mod embedder;
+pub mod eval;
+pub mod mmap;
+pub mod quantization;
+#[cfg(feature = "gguf")]
+pub mod quantized_bert;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod standardize;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
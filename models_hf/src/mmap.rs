@@ -0,0 +1,78 @@
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::Context;
+use candle::safetensors::MmapedSafetensors;
+use candle::{DType, Device, Tensor};
+
+/// `[n, hidden]` embeddings left in a memory-mapped safetensors file, read a range of rows at a
+/// time, so searching them never holds the whole matrix in memory.
+pub struct MmapedEmbeddings {
+    tensors: MmapedSafetensors,
+    key: String,
+    len: usize,
+    hidden_size: usize,
+    dtype: DType,
+}
+
+impl MmapedEmbeddings {
+    /// Maps the safetensors file at `path`, whose tensor `key` holds the embeddings. Only the
+    /// header is read.
+    pub fn open(path: &Path, key: &str) -> anyhow::Result<Self> {
+        // SAFETY: like the model weights, the file must not be modified while it is mapped
+        let tensors = unsafe { MmapedSafetensors::new(path) }
+            .with_context(|| format!("Error mapping embeddings file {}", path.display()))?;
+        let view = tensors
+            .get(key)
+            .with_context(|| format!("Embeddings key {key:?} not found in {}", path.display()))?;
+        let (len, hidden_size) = match view.shape() {
+            &[len, hidden_size] => (len, hidden_size),
+            shape => anyhow::bail!("Embeddings must be a [n, hidden] tensor, got shape {shape:?}"),
+        };
+        let dtype = DType::try_from(view.dtype())?;
+
+        Ok(Self {
+            tensors,
+            key: key.to_string(),
+            len,
+            hidden_size,
+            dtype,
+        })
+    }
+
+    /// Number of embeddings.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    /// Copies the embeddings of `rows` from the file into a `[rows.len(), hidden]` tensor.
+    pub fn rows(&self, rows: Range<usize>, device: &Device) -> anyhow::Result<Tensor> {
+        anyhow::ensure!(
+            rows.start <= rows.end && rows.end <= self.len,
+            "Rows {rows:?} out of range for {} embeddings",
+            self.len
+        );
+        let view = self.tensors.get(&self.key)?;
+        let row_bytes = self.hidden_size * self.dtype.size_in_bytes();
+        let data = &view.data()[rows.start * row_bytes..rows.end * row_bytes];
+
+        Ok(Tensor::from_raw_buffer(
+            data,
+            self.dtype,
+            &[rows.len(), self.hidden_size],
+            device,
+        )?)
+    }
+}
@@ -0,0 +1,366 @@
+use candle::{DType, Tensor};
+
+/// Embeddings scalar-quantized to one byte per dimension, with a scale and offset per vector.
+///
+/// Each row `x` is stored as `codes = round((x - min) / scale)` with `scale = (max - min) / 255`,
+/// which takes a quarter of the memory of f32 embeddings.
+#[derive(Debug, Clone)]
+pub struct QuantizedEmbeddings {
+    /// `[n, hidden]` u8 codes.
+    codes: Tensor,
+    /// `[n, 1]` f32 step between consecutive codes.
+    scales: Tensor,
+    /// `[n, 1]` f32 value of code 0 (the row minimum).
+    offsets: Tensor,
+    /// `[n, 1]` f32 L2 norm of the dequantized rows, for cosine scoring.
+    norms: Tensor,
+}
+
+impl QuantizedEmbeddings {
+    pub fn quantize(embeddings: &Tensor) -> anyhow::Result<Self> {
+        let embeddings = embeddings.to_dtype(DType::F32)?;
+        let offsets = embeddings.min_keepdim(1)?;
+        let maxs = embeddings.max_keepdim(1)?;
+        // Keep constant rows from dividing by zero
+        let scales = ((maxs - &offsets)? / 255.)?.maximum(1e-12)?;
+        let codes = embeddings
+            .broadcast_sub(&offsets)?
+            .broadcast_div(&scales)?
+            .round()?
+            .to_dtype(DType::U8)?;
+
+        let mut quantized = Self {
+            codes,
+            scales,
+            offsets,
+            norms: Tensor::zeros(1, DType::F32, embeddings.device())?,
+        };
+        quantized.norms = quantized.dequantize()?.sqr()?.sum_keepdim(1)?.sqrt()?;
+
+        Ok(quantized)
+    }
+
+    /// Reconstructs approximate `[n, hidden]` f32 embeddings.
+    pub fn dequantize(&self) -> anyhow::Result<Tensor> {
+        Ok(self
+            .codes
+            .to_dtype(DType::F32)?
+            .broadcast_mul(&self.scales)?
+            .broadcast_add(&self.offsets)?)
+    }
+
+    /// Number of quantized vectors.
+    pub fn len(&self) -> anyhow::Result<usize> {
+        Ok(self.codes.dim(0)?)
+    }
+
+    pub fn is_empty(&self) -> anyhow::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Cosine similarity of `vector` (`[1, hidden]`) against every quantized row, in index order.
+    ///
+    /// Dequantization is folded into the matmul: `q . (codes * scale + min)` equals
+    /// `scale * (q . codes) + min * sum(q)`, so the f32 rows are never materialized.
+    pub fn cosine_scores(&self, vector: &Tensor) -> anyhow::Result<Vec<f32>> {
+        let vector = vector
+            .to_device(self.codes.device())?
+            .to_dtype(DType::F32)?;
+
+        // [n, hidden] x [hidden, 1] -> [n, 1]
+        let code_products = self.codes.to_dtype(DType::F32)?.matmul(&vector.t()?)?;
+        let dot_products = code_products
+            .mul(&self.scales)?
+            .broadcast_add(&self.offsets.broadcast_mul(&vector.sum_all()?)?)?;
+        let vector_norm = vector.sqr()?.sum_all()?.sqrt()?;
+        let scores = dot_products.broadcast_div(&self.norms.broadcast_mul(&vector_norm)?)?;
+
+        Ok(scores.squeeze(1)?.to_vec1::<f32>()?)
+    }
+}
+
+/// Thresholds every dimension at zero and packs the bits, most significant first, into a
+/// `[n, ceil(hidden / 8)]` u8 tensor.
+pub fn binarize(embeddings: &Tensor) -> anyhow::Result<Tensor> {
+    let (n, hidden) = embeddings.dims2()?;
+    let n_bytes = hidden.div_ceil(8);
+
+    let bits = embeddings
+        .gt(0f64)?
+        .to_dtype(DType::U32)?
+        .pad_with_zeros(1, 0, n_bytes * 8 - hidden)?
+        .reshape((n, n_bytes, 8))?;
+    let bit_values = Tensor::new(&[128u32, 64, 32, 16, 8, 4, 2, 1], embeddings.device())?;
+
+    Ok(bits
+        .broadcast_mul(&bit_values)?
+        .sum(2)?
+        .to_dtype(DType::U8)?)
+}
+
+/// Hamming distance (XOR + popcount) between the packed `query` (`[1, n_bytes]`) and every row
+/// of the packed `codes`, in index order.
+pub fn hamming_distances(codes: &Tensor, query: &Tensor) -> anyhow::Result<Vec<u32>> {
+    let query = query.flatten_all()?.to_vec1::<u8>()?;
+
+    Ok(codes
+        .to_vec2::<u8>()?
+        .iter()
+        .map(|code| {
+            code.iter()
+                .zip(&query)
+                .map(|(a, b)| (a ^ b).count_ones())
+                .sum()
+        })
+        .collect())
+}
+
+/// Compresses vectors to `n_subspaces` bytes each: every vector is cut into `n_subspaces`
+/// contiguous slices, and each slice is replaced by the index of its nearest centroid in a
+/// codebook learned with k-means for that subspace.
+#[derive(Debug, Clone)]
+pub struct ProductQuantizer {
+    /// One `[n_centroids, hidden / n_subspaces]` f32 codebook per subspace.
+    codebooks: Vec<Tensor>,
+}
+
+impl ProductQuantizer {
+    /// Learns the codebooks from the rows of `embeddings` (`[n, hidden]`), running `iterations`
+    /// rounds of k-means with `n_centroids` (at most 256, to fit a byte) centroids per subspace.
+    pub fn train(
+        embeddings: &Tensor,
+        n_subspaces: usize,
+        n_centroids: usize,
+        iterations: usize,
+    ) -> anyhow::Result<Self> {
+        let (n, hidden) = embeddings.dims2()?;
+        anyhow::ensure!(
+            n_subspaces > 0 && hidden % n_subspaces == 0,
+            "Hidden size {hidden} cannot be split into {n_subspaces} subspaces"
+        );
+        anyhow::ensure!(
+            (1..=256).contains(&n_centroids),
+            "n_centroids must be between 1 and 256, got {n_centroids}"
+        );
+        anyhow::ensure!(
+            n >= n_centroids,
+            "Training {n_centroids} centroids needs at least as many embeddings, got {n}"
+        );
+
+        let embeddings = embeddings.to_dtype(DType::F32)?;
+        let subspace_size = hidden / n_subspaces;
+        let codebooks = (0..n_subspaces)
+            .map(|subspace| {
+                let slices = embeddings
+                    .narrow(1, subspace * subspace_size, subspace_size)?
+                    .contiguous()?;
+                kmeans(&slices, n_centroids, iterations)
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { codebooks })
+    }
+
+    pub fn n_subspaces(&self) -> usize {
+        self.codebooks.len()
+    }
+
+    pub fn hidden_size(&self) -> anyhow::Result<usize> {
+        Ok(self.codebooks[0].dim(1)? * self.n_subspaces())
+    }
+
+    /// Cuts `[n, hidden]` embeddings into subspace slices, checking their hidden size.
+    fn slices(&self, embeddings: &Tensor) -> anyhow::Result<Vec<Tensor>> {
+        let (_n, hidden) = embeddings.dims2()?;
+        anyhow::ensure!(
+            hidden == self.hidden_size()?,
+            "Product quantizer trained on hidden size {} got hidden size {hidden}",
+            self.hidden_size()?
+        );
+        let embeddings = embeddings
+            .to_device(self.codebooks[0].device())?
+            .to_dtype(DType::F32)?;
+        let subspace_size = hidden / self.n_subspaces();
+
+        (0..self.n_subspaces())
+            .map(|subspace| {
+                Ok(embeddings
+                    .narrow(1, subspace * subspace_size, subspace_size)?
+                    .contiguous()?)
+            })
+            .collect()
+    }
+
+    /// Encodes `[n, hidden]` embeddings to `[n, n_subspaces]` u8 codes.
+    pub fn encode(&self, embeddings: &Tensor) -> anyhow::Result<Tensor> {
+        let codes = self
+            .slices(embeddings)?
+            .iter()
+            .zip(&self.codebooks)
+            .map(|(slices, codebook)| nearest_centroids(slices, codebook))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Tensor::stack(&codes, 1)?.to_dtype(DType::U8)?)
+    }
+
+    /// Reconstructs approximate `[n, hidden]` f32 embeddings from their codes.
+    pub fn decode(&self, codes: &Tensor) -> anyhow::Result<Tensor> {
+        let codes = codes.to_dtype(DType::U32)?;
+        let slices = self
+            .codebooks
+            .iter()
+            .enumerate()
+            .map(|(subspace, codebook)| {
+                let indices = codes.narrow(1, subspace, 1)?.squeeze(1)?;
+                Ok(codebook.index_select(&indices, 0)?)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Tensor::cat(&slices, 1)?)
+    }
+
+    /// Squared Euclidean distance from `query` (`[1, hidden]`) to the vector encoded by every
+    /// row of `codes`, in index order.
+    ///
+    /// The distances are asymmetric: the query is not quantized. Its distance to every centroid
+    /// is computed once per subspace, then each code only sums `n_subspaces` table lookups.
+    pub fn distances(&self, codes: &Tensor, query: &Tensor) -> anyhow::Result<Vec<f32>> {
+        let tables = self
+            .slices(query)?
+            .iter()
+            .zip(&self.codebooks)
+            .map(|(slice, codebook)| {
+                // [n_centroids, subspace_size] - [1, subspace_size] -> [n_centroids]
+                let distances = codebook.broadcast_sub(slice)?.sqr()?.sum(1)?;
+                Ok(distances.to_vec1::<f32>()?)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(codes
+            .to_vec2::<u8>()?
+            .iter()
+            .map(|code| {
+                code.iter()
+                    .zip(&tables)
+                    .map(|(&centroid, table)| table[centroid as usize])
+                    .sum()
+            })
+            .collect())
+    }
+}
+
+/// Lloyd's k-means over the rows of `data` (`[n, d]` f32), seeded with `k` evenly spaced rows.
+/// Returns the `[k, d]` centroids; a cluster left empty keeps its previous centroid.
+fn kmeans(data: &Tensor, k: usize, iterations: usize) -> anyhow::Result<Tensor> {
+    let (n, _d) = data.dims2()?;
+    let device = data.device();
+    let seeds: Vec<u32> = (0..k).map(|cluster| (cluster * n / k) as u32).collect();
+    let mut centroids = data.index_select(&Tensor::new(seeds.as_slice(), device)?, 0)?;
+    let clusters = Tensor::arange(0u32, k as u32, device)?.unsqueeze(0)?;
+
+    for _ in 0..iterations {
+        let assignments = nearest_centroids(data, &centroids)?;
+        // [n, k], 1 where a row belongs to a cluster
+        let members = assignments
+            .unsqueeze(1)?
+            .broadcast_eq(&clusters)?
+            .to_dtype(DType::F32)?;
+        let counts = members.sum(0)?.unsqueeze(1)?;
+        let means = members
+            .t()?
+            .matmul(data)?
+            .broadcast_div(&counts.maximum(1f32)?)?;
+        let empty = counts.eq(0f32)?.broadcast_as(centroids.shape())?;
+        centroids = empty.where_cond(&centroids, &means)?;
+    }
+
+    Ok(centroids)
+}
+
+/// Index (u32) of the centroid (`[k, d]`) nearest to every row of `data` (`[n, d]`).
+fn nearest_centroids(data: &Tensor, centroids: &Tensor) -> anyhow::Result<Tensor> {
+    // |x - c|^2 = |x|^2 - 2 x.c + |c|^2, where |x|^2 doesn't change the nearest centroid
+    let products = data.matmul(&centroids.t()?)?;
+    let norms = centroids.sqr()?.sum(1)?.unsqueeze(0)?;
+
+    Ok(norms.broadcast_sub(&(products * 2.)?)?.argmin(1)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Device;
+
+    #[test]
+    fn dequantized_values_stay_within_half_a_step() {
+        let embeddings = Tensor::randn(0f32, 1., (8, 16), &Device::Cpu).unwrap();
+
+        let quantized = QuantizedEmbeddings::quantize(&embeddings).unwrap();
+
+        assert_eq!(quantized.codes.dtype(), DType::U8);
+        let error = (quantized.dequantize().unwrap() - &embeddings)
+            .unwrap()
+            .abs()
+            .unwrap();
+        let max_error = error.max_keepdim(1).unwrap();
+        let half_step = (&quantized.scales / 2.).unwrap();
+        let within_bounds = max_error.le(&(half_step + 1e-6).unwrap()).unwrap();
+        assert_eq!(
+            within_bounds
+                .flatten_all()
+                .unwrap()
+                .to_vec1::<u8>()
+                .unwrap(),
+            vec![1; 8]
+        );
+    }
+
+    #[test]
+    fn binarize_packs_sign_bits() {
+        let embeddings = Tensor::new(
+            &[
+                [1f32, -1., 1., -1., 1., -1., 1., -1., 1., 1.],
+                [-1., -1., -1., -1., -1., -1., -1., -1., -1., 1.],
+            ],
+            &Device::Cpu,
+        )
+        .unwrap();
+
+        let codes = binarize(&embeddings).unwrap();
+
+        assert_eq!(
+            codes.to_vec2::<u8>().unwrap(),
+            vec![[0b1010_1010, 0b1100_0000], [0, 0b0100_0000]]
+        );
+        let query = codes.get(0).unwrap();
+        assert_eq!(hamming_distances(&codes, &query).unwrap(), vec![0, 5]);
+    }
+
+    #[test]
+    fn product_quantization_reconstructs_clustered_vectors() {
+        // 4 well-separated points, each repeated with a little noise
+        let centers = Tensor::randn(0f32, 1., (4, 8), &Device::Cpu).unwrap();
+        let noise = Tensor::randn(0f32, 0.01, (4, 16, 8), &Device::Cpu).unwrap();
+        let embeddings = centers
+            .unsqueeze(1)
+            .unwrap()
+            .broadcast_add(&noise)
+            .unwrap()
+            .reshape((64, 8))
+            .unwrap();
+
+        let quantizer = ProductQuantizer::train(&embeddings, 2, 4, 10).unwrap();
+        let codes = quantizer.encode(&embeddings).unwrap();
+
+        assert_eq!(codes.dims(), [64, 2]);
+        assert_eq!(codes.dtype(), DType::U8);
+        let decoded = quantizer.decode(&codes).unwrap();
+        let error = (decoded - &embeddings).unwrap().abs().unwrap();
+        let max_error = error.max_keepdim(0).unwrap().max(1).unwrap();
+        assert!(max_error.to_vec1::<f32>().unwrap()[0] < 0.1);
+        let query = embeddings.get(5).unwrap().unsqueeze(0).unwrap();
+        let distances = quantizer.distances(&codes, &query).unwrap();
+        assert!(distances[..16].iter().all(|&distance| distance < 0.01));
+        assert!(ProductQuantizer::train(&embeddings, 3, 4, 10).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use std::path::Path;
+
+use candle::{Device, Module, Tensor, D};
+use candle_nn::LayerNorm;
+use candle_transformers::quantized_nn::{layer_norm, linear, Embedding, Linear};
+use candle_transformers::quantized_var_builder::VarBuilder;
+
+/// The parts of a BERT `config.json` needed to run the quantized model. Read from the raw
+/// JSON, as candle's `Config` keeps its fields private.
+#[derive(Debug, Clone, PartialEq)]
+struct QuantizedConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    intermediate_size: usize,
+    hidden_act: String,
+    layer_norm_eps: f64,
+    max_position_embeddings: usize,
+    type_vocab_size: usize,
+}
+
+impl QuantizedConfig {
+    fn from_json(config: &serde_json::Value) -> anyhow::Result<Self> {
+        let size = |key: &str| -> anyhow::Result<usize> {
+            config[key]
+                .as_u64()
+                .map(|size| size as usize)
+                .ok_or_else(|| anyhow::anyhow!("config.json has no {key}"))
+        };
+
+        Ok(Self {
+            vocab_size: size("vocab_size")?,
+            hidden_size: size("hidden_size")?,
+            num_hidden_layers: size("num_hidden_layers")?,
+            num_attention_heads: size("num_attention_heads")?,
+            intermediate_size: size("intermediate_size")?,
+            hidden_act: config["hidden_act"].as_str().unwrap_or("gelu").to_string(),
+            layer_norm_eps: config["layer_norm_eps"].as_f64().unwrap_or(1e-12),
+            max_position_embeddings: size("max_position_embeddings")?,
+            type_vocab_size: size("type_vocab_size")?,
+        })
+    }
+}
+
+struct Embeddings {
+    word_embeddings: Embedding,
+    position_embeddings: Embedding,
+    token_type_embeddings: Embedding,
+    layer_norm: LayerNorm,
+}
+
+impl Embeddings {
+    fn load(vb: VarBuilder, config: &QuantizedConfig) -> anyhow::Result<Self> {
+        let hidden = config.hidden_size;
+
+        Ok(Self {
+            word_embeddings: Embedding::new(config.vocab_size, hidden, vb.pp("word_embeddings"))?,
+            position_embeddings: Embedding::new(
+                config.max_position_embeddings,
+                hidden,
+                vb.pp("position_embeddings"),
+            )?,
+            token_type_embeddings: Embedding::new(
+                config.type_vocab_size,
+                hidden,
+                vb.pp("token_type_embeddings"),
+            )?,
+            layer_norm: layer_norm(hidden, config.layer_norm_eps, vb.pp("LayerNorm"))?,
+        })
+    }
+
+    fn forward(&self, token_ids: &Tensor, token_type_ids: &Tensor) -> candle::Result<Tensor> {
+        let (_batch, seq_len) = token_ids.dims2()?;
+        let positions = Tensor::arange(0u32, seq_len as u32, token_ids.device())?;
+
+        let embeddings = self
+            .word_embeddings
+            .forward(token_ids)?
+            .add(&self.token_type_embeddings.forward(token_type_ids)?)?
+            .broadcast_add(&self.position_embeddings.forward(&positions)?)?;
+        self.layer_norm.forward(&embeddings)
+    }
+}
+
+struct Layer {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    attention_output: Linear,
+    attention_layer_norm: LayerNorm,
+    intermediate: Linear,
+    output: Linear,
+    output_layer_norm: LayerNorm,
+    num_attention_heads: usize,
+    hidden_act: String,
+}
+
+impl Layer {
+    fn load(vb: VarBuilder, config: &QuantizedConfig) -> anyhow::Result<Self> {
+        let hidden = config.hidden_size;
+        let eps = config.layer_norm_eps;
+        let attention = vb.pp("attention");
+
+        Ok(Self {
+            query: linear(hidden, hidden, attention.pp("self.query"))?,
+            key: linear(hidden, hidden, attention.pp("self.key"))?,
+            value: linear(hidden, hidden, attention.pp("self.value"))?,
+            attention_output: linear(hidden, hidden, attention.pp("output.dense"))?,
+            attention_layer_norm: layer_norm(hidden, eps, attention.pp("output.LayerNorm"))?,
+            intermediate: linear(
+                hidden,
+                config.intermediate_size,
+                vb.pp("intermediate.dense"),
+            )?,
+            output: linear(config.intermediate_size, hidden, vb.pp("output.dense"))?,
+            output_layer_norm: layer_norm(hidden, eps, vb.pp("output.LayerNorm"))?,
+            num_attention_heads: config.num_attention_heads,
+            hidden_act: config.hidden_act.clone(),
+        })
+    }
+
+    fn forward(&self, hidden_states: &Tensor) -> candle::Result<Tensor> {
+        let (batch, seq_len, hidden) = hidden_states.dims3()?;
+        let head_size = hidden / self.num_attention_heads;
+        // [batch, seq, hidden] -> [batch, heads, seq, head_size]
+        let heads = |xs: Tensor| -> candle::Result<Tensor> {
+            xs.reshape((batch, seq_len, self.num_attention_heads, head_size))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let query = heads(self.query.forward(hidden_states)?)?;
+        let key = heads(self.key.forward(hidden_states)?)?;
+        let value = heads(self.value.forward(hidden_states)?)?;
+
+        let scores = (query.matmul(&key.t()?)? / (head_size as f64).sqrt())?;
+        let probs = candle_nn::ops::softmax(&scores, D::Minus1)?;
+        let context = probs
+            .matmul(&value)?
+            .transpose(1, 2)?
+            .reshape((batch, seq_len, hidden))?;
+        let attention = self.attention_output.forward(&context)?;
+        let attention = self
+            .attention_layer_norm
+            .forward(&(attention + hidden_states)?)?;
+
+        let intermediate = self.intermediate.forward(&attention)?;
+        let intermediate = match self.hidden_act.as_str() {
+            "gelu_approximate" | "gelu_new" => intermediate.gelu()?,
+            "relu" => intermediate.relu()?,
+            _ => intermediate.gelu_erf()?,
+        };
+        let output = self.output.forward(&intermediate)?;
+        self.output_layer_norm.forward(&(output + attention)?)
+    }
+}
+
+/// A BERT encoder whose weights are quantized (e.g. to 8 or 4 bits per value) in a GGUF file,
+/// computing in f32.
+///
+/// The GGUF file holds the tensors of `model.safetensors` under their usual names, as written by
+/// candle's `tensor-tools quantize`. Like candle's `BertModel`, it takes no attention mask.
+pub struct QuantizedBertModel {
+    embeddings: Embeddings,
+    layers: Vec<Layer>,
+    hidden_size: usize,
+}
+
+impl QuantizedBertModel {
+    /// Loads the quantized weights in `gguf_path` onto `device` for the model described by
+    /// `config`, the parsed `config.json` of the original model.
+    pub fn load(
+        gguf_path: &Path,
+        config: &serde_json::Value,
+        device: &Device,
+    ) -> anyhow::Result<Self> {
+        let config = QuantizedConfig::from_json(config)?;
+        let vb = VarBuilder::from_gguf(gguf_path, device)?;
+        // Checkpoints of task heads nest the encoder under `bert.`
+        let vb = match vb.contains_key("embeddings.word_embeddings.weight") {
+            true => vb,
+            false => vb.pp("bert"),
+        };
+
+        let embeddings = Embeddings::load(vb.pp("embeddings"), &config)?;
+        let layers = (0..config.num_hidden_layers)
+            .map(|index| Layer::load(vb.pp(format!("encoder.layer.{index}")), &config))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            embeddings,
+            layers,
+            hidden_size: config.hidden_size,
+        })
+    }
+
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    /// Returns the `[batch, seq, hidden]` f32 hidden states of `[batch, seq]` token ids.
+    pub fn forward(&self, token_ids: &Tensor, token_type_ids: &Tensor) -> candle::Result<Tensor> {
+        let mut hidden_states = self.embeddings.forward(token_ids, token_type_ids)?;
+        for layer in &self.layers {
+            hidden_states = layer.forward(&hidden_states)?;
+        }
+
+        Ok(hidden_states)
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+use candle::DType;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::bert::{BertInferenceModel, SearchResult};
+
+#[derive(Debug, Deserialize)]
+pub struct EmbedRequest {
+    pub text: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmbedResponse {
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchRequest {
+    pub text: String,
+    pub top_k: usize,
+}
+
+type ApiError = (StatusCode, String);
+
+/// Routes `POST /embed` and `POST /search` to `model`, shared by every request.
+pub fn router(model: Arc<BertInferenceModel>) -> Router {
+    Router::new()
+        .route("/embed", post(embed))
+        .route("/search", post(search))
+        .with_state(model)
+}
+
+/// Serves [`router`] on `listener` until the server fails.
+pub async fn serve(listener: TcpListener, model: Arc<BertInferenceModel>) -> anyhow::Result<()> {
+    axum::serve(listener, router(model)).await?;
+    Ok(())
+}
+
+async fn embed(
+    State(model): State<Arc<BertInferenceModel>>,
+    Json(request): Json<EmbedRequest>,
+) -> Result<Json<EmbedResponse>, ApiError> {
+    let embedding = run_blocking(move || {
+        let embedding = model.infer_sentence_embedding(&request.text)?;
+        Ok(embedding
+            .to_dtype(DType::F32)?
+            .squeeze(0)?
+            .to_vec1::<f32>()?)
+    })
+    .await?;
+
+    Ok(Json(EmbedResponse { embedding }))
+}
+
+async fn search(
+    State(model): State<Arc<BertInferenceModel>>,
+    Json(request): Json<SearchRequest>,
+) -> Result<Json<Vec<SearchResult>>, ApiError> {
+    let results = run_blocking(move || model.search_text(&request.text, request.top_k)).await?;
+    Ok(Json(results))
+}
+
+/// Runs model inference off the async workers, which it would otherwise block.
+async fn run_blocking<T, F>(f: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::bert::tests::{random_embeddings, tiny_model};
+
+    #[tokio::test]
+    async fn search_endpoint_returns_results_as_json() {
+        let mut model = tiny_model();
+        model.add_embeddings(random_embeddings(10)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(model)));
+
+        let body = r#"{"text": "the cat sat on the mat", "top_k": 3}"#;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "POST /search HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+        let results: serde_json::Value = serde_json::from_str(body).unwrap();
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        for result in results {
+            assert!(result["index"].as_u64().unwrap() < 10);
+            assert!(result["score"].is_number());
+            assert!(result["id"].is_null());
+        }
+    }
+}
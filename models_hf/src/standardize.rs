@@ -0,0 +1,52 @@
+use candle::{DType, Tensor};
+use serde::{Deserialize, Serialize};
+
+/// Per-dimension mean and standard deviation of a set of embeddings, used to shift and scale
+/// every dimension to zero mean and unit variance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Standardizer {
+    pub mean: Vec<f32>,
+    pub std: Vec<f32>,
+}
+
+impl Standardizer {
+    /// Computes the statistics of the rows of `embeddings` (`[n, hidden]`).
+    pub fn fit(embeddings: &Tensor) -> anyhow::Result<Self> {
+        let embeddings = embeddings.to_dtype(DType::F32)?;
+        let mean = embeddings.mean_keepdim(0)?;
+        let variance = embeddings.broadcast_sub(&mean)?.sqr()?.mean(0)?;
+
+        Ok(Self {
+            mean: mean.squeeze(0)?.to_vec1()?,
+            std: variance.sqrt()?.to_vec1()?,
+        })
+    }
+
+    pub fn hidden_size(&self) -> usize {
+        self.mean.len()
+    }
+
+    /// Returns `(embeddings - mean) / std` for `[n, hidden]` embeddings, in their own dtype.
+    /// Constant dimensions are only shifted.
+    pub fn transform(&self, embeddings: &Tensor) -> anyhow::Result<Tensor> {
+        let (_n, hidden_size) = embeddings.dims2()?;
+        anyhow::ensure!(
+            hidden_size == self.hidden_size(),
+            "Standardizer fitted on hidden size {} got hidden size {hidden_size}",
+            self.hidden_size()
+        );
+
+        let device = embeddings.device();
+        let mean = Tensor::new(self.mean.as_slice(), device)?.unsqueeze(0)?;
+        // Keep constant dimensions from dividing by zero
+        let std = Tensor::new(self.std.as_slice(), device)?
+            .unsqueeze(0)?
+            .maximum(1e-12)?;
+        let standardized = embeddings
+            .to_dtype(DType::F32)?
+            .broadcast_sub(&mean)?
+            .broadcast_div(&std)?;
+
+        Ok(standardized.to_dtype(embeddings.dtype())?)
+    }
+}
@@ -0,0 +1,57 @@
+#![cfg(feature = "cuda")]
+
+use candle::{DType, Device};
+use models_hf::bert::{BertInferenceModel, DEFAULT_MAX_LENGTH};
+
+#[test]
+fn infers_on_cuda_device() -> anyhow::Result<()> {
+    let device = Device::new_cuda(0)?;
+    let model = BertInferenceModel::load(
+        "sentence-transformers/all-MiniLM-L6-v2",
+        "refs/pr/21",
+        "",
+        "",
+        DEFAULT_MAX_LENGTH,
+        device,
+        DType::F32,
+    )?;
+    assert!(model.device().is_cuda());
+
+    let embedding = model.infer_sentence_embedding("deep learning survey")?;
+    assert!(embedding.device().is_cuda());
+    assert_eq!(embedding.dims(), &[1, 384]);
+
+    Ok(())
+}
+
+#[test]
+fn scores_on_cpu_for_a_cuda_model() -> anyhow::Result<()> {
+    let device = Device::new_cuda(0)?;
+    let mut model = BertInferenceModel::load(
+        "sentence-transformers/all-MiniLM-L6-v2",
+        "refs/pr/21",
+        "",
+        "",
+        DEFAULT_MAX_LENGTH,
+        device,
+        DType::F32,
+    )?;
+    let sentences = [
+        "deep learning survey",
+        "graph neural network",
+        "autonomous driving",
+    ];
+    let embeddings = model.create_embeddings(sentences.map(String::from).to_vec())?;
+    model.add_embeddings(embeddings)?;
+
+    let query = model.infer_sentence_embedding("neural networks on graphs")?;
+    let on_cpu = model.score_vector_similarity_on(query.clone(), 3, &Device::Cpu)?;
+    let on_cuda = model.score_vector_similarity(query, 3)?;
+    assert_eq!(on_cpu.len(), on_cuda.len());
+    for (on_cpu, on_cuda) in on_cpu.iter().zip(&on_cuda) {
+        assert_eq!(on_cpu.index, on_cuda.index);
+        assert!((on_cpu.score - on_cuda.score).abs() < 1e-5);
+    }
+
+    Ok(())
+}
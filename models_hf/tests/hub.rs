@@ -0,0 +1,46 @@
+use candle::{DType, Device};
+use models_hf::bert::{BertInferenceModel, DEFAULT_MAX_LENGTH};
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+#[ignore = "downloads a model from the HF Hub"]
+async fn load_async_downloads_a_working_model() -> anyhow::Result<()> {
+    let model = BertInferenceModel::load_async(
+        "sentence-transformers/all-MiniLM-L6-v2",
+        "refs/pr/21",
+        "",
+        "",
+        DEFAULT_MAX_LENGTH,
+        Device::Cpu,
+        DType::F32,
+    )
+    .await?;
+
+    let embedding = model.infer_sentence_embedding("deep learning survey")?;
+    assert_eq!(embedding.dims(), &[1, 384]);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "queries the HF Hub"]
+fn missing_repo_gives_an_actionable_error() {
+    let err = BertInferenceModel::load(
+        "models-hf-tests/no-such-model",
+        "main",
+        "",
+        "",
+        DEFAULT_MAX_LENGTH,
+        Device::Cpu,
+        DType::F32,
+    )
+    .err()
+    .unwrap()
+    .to_string();
+
+    assert!(
+        err.starts_with("Could not fetch ") && err.contains("models-hf-tests/no-such-model"),
+        "{err}"
+    );
+    assert!(err.contains("load_from_path"), "{err}");
+}